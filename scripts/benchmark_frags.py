@@ -0,0 +1,47 @@
+import filecmp
+import os
+import subprocess
+import sys
+import tempfile
+import time
+
+# Compare the run time and peak memory of two flopp binaries on fragments simulated from
+# tests/test_vcfs/pds.vcf with fixed seeds, and check that they give the same phasing.
+#
+# usage : python benchmark_frags.py old_flopp new_flopp [threads]
+
+old_flopp = sys.argv[1]
+new_flopp = sys.argv[2]
+threads = sys.argv[3] if len(sys.argv) > 3 else '10'
+
+script_dir = os.path.dirname(os.path.abspath(__file__))
+vcf_file = os.path.join(script_dir, '..', 'tests', 'test_vcfs', 'pds.vcf')
+simulate = os.path.join(script_dir, 'simulate_frags.py')
+
+ploidy = '3'
+read_length = '10000'
+error_rate = '0.04'
+seed = '1'
+
+def run(flopp, frag_file, out_file):
+    start = time.time()
+    proc = subprocess.Popen([flopp, '-f', frag_file, '-p', ploidy, '-t', threads, '-o', out_file],
+                            stdout=subprocess.DEVNULL)
+    _, status, usage = os.wait4(proc.pid, 0)
+    if status != 0:
+        print("{} failed on {}".format(flopp, frag_file))
+        sys.exit(1)
+    # ru_maxrss is in kilobytes on Linux.
+    return time.time() - start, usage.ru_maxrss / 1024
+
+tmp_dir = tempfile.mkdtemp()
+print("coverage\told_time(s)\told_mem(MB)\tnew_time(s)\tnew_mem(MB)\tsame_output")
+for coverage in ['20', '60']:
+    frag_file = os.path.join(tmp_dir, 'frags_{}x.txt'.format(coverage))
+    subprocess.check_call(['python3', simulate, vcf_file, coverage, read_length, error_rate, seed, frag_file])
+    old_out = os.path.join(tmp_dir, 'old_{}x.txt'.format(coverage))
+    new_out = os.path.join(tmp_dir, 'new_{}x.txt'.format(coverage))
+    old_time, old_mem = run(old_flopp, frag_file, old_out)
+    new_time, new_mem = run(new_flopp, frag_file, new_out)
+    same = filecmp.cmp(old_out, new_out, shallow=False)
+    print("{}x\t{:.1f}\t{:.0f}\t{:.1f}\t{:.0f}\t{}".format(coverage, old_time, old_mem, new_time, new_mem, same))
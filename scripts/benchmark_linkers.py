@@ -0,0 +1,71 @@
+import os
+import subprocess
+import sys
+import tempfile
+import time
+
+# Compare the block linkers of flopp on fragments simulated from tests/test_vcfs/pds.vcf with
+# fixed seeds. For every linker we report the run time and the fraction of overlapping read pairs
+# whose partition disagrees with their true haplotypes (read names are hap{i}_{n}). Unassigned
+# reads are skipped.
+#
+# usage : python benchmark_linkers.py flopp [extra flopp options...]
+
+flopp = sys.argv[1]
+extra_options = sys.argv[2:]
+
+script_dir = os.path.dirname(os.path.abspath(__file__))
+vcf_file = os.path.join(script_dir, '..', 'tests', 'test_vcfs', 'pds.vcf')
+simulate = os.path.join(script_dir, 'simulate_frags.py')
+
+ploidy = '3'
+read_length = '10000'
+error_rate = '0.04'
+linkers = [['greedy'], ['pairwise'], ['beam', '--beam-width', '4'], ['beam', '--beam-width', '16']]
+
+def read_partition(part_file):
+    haps = {}
+    reads = []
+    hap = None
+    for line in open(part_file, 'r'):
+        if line[0] == '#':
+            hap = None if line.startswith('#unassigned') else int(line[1:])
+            continue
+        spl = line.split()
+        haps.setdefault(spl[0], set())
+        if hap is not None:
+            haps[spl[0]].add(hap)
+        reads.append((int(spl[1]), int(spl[2]), spl[0]))
+    return haps, sorted(set(reads))
+
+# Compare each read with the next few reads overlapping it.
+def pair_error(part_file):
+    haps, reads = read_partition(part_file)
+    errors = 0
+    pairs = 0
+    for i in range(len(reads)):
+        for j in range(i + 1, min(i + 6, len(reads))):
+            if reads[j][0] > reads[i][1]:
+                break
+            if not haps[reads[i][2]] or not haps[reads[j][2]]:
+                continue
+            same_true = reads[i][2].split('_')[0] == reads[j][2].split('_')[0]
+            same_part = len(haps[reads[i][2]] & haps[reads[j][2]]) > 0
+            pairs += 1
+            errors += same_true != same_part
+    return errors / max(pairs, 1)
+
+tmp_dir = tempfile.mkdtemp()
+print("coverage\tseed\tlinker\ttime(s)\tpair_error")
+for coverage in ['5', '10', '20']:
+    for seed in ['2', '3']:
+        frag_file = os.path.join(tmp_dir, 'frags_{}x_{}.txt'.format(coverage, seed))
+        subprocess.check_call(['python3', simulate, vcf_file, coverage, read_length, error_rate, seed, frag_file])
+        for linker in linkers:
+            part_dir = os.path.join(tmp_dir, 'part')
+            start = time.time()
+            subprocess.check_call([flopp, '-f', frag_file, '-p', ploidy, '-o', os.path.join(tmp_dir, 'out.txt'),
+                                   '-P', part_dir, '--linker'] + linker + extra_options, stdout=subprocess.DEVNULL)
+            run_time = time.time() - start
+            error = pair_error(os.path.join(part_dir, 'frag_contig_part.txt'))
+            print("{}x\t{}\t{}\t{:.1f}\t{:.4f}".format(coverage, seed, ' '.join(linker), run_time, error))
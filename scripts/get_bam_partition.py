@@ -7,12 +7,17 @@ pref_nam = sys.argv[3]
 bam = pysam.AlignmentFile(bam_file)
 
 read_part = []
+unassigned = set()
 
 count_i = -1 
 for line in open(read_part_file,'r'):
-    if '#' in line:
+    if line.startswith('#unassigned'):
+        count_i = None
+    elif '#' in line:
         read_part.append(set())
         count_i += 1
+    elif count_i is None:
+        unassigned.add(line.split()[0])
     else:
         read_part[count_i].add(line.split()[0])
 
@@ -26,6 +31,9 @@ for i in range(ploidy):
     file_names.append(file_name)
     obam_files.append(pysam.AlignmentFile(file_name, "wb", template=bam))
 
+file_names.append(pref_nam+"-unassigned.bam")
+obam_files.append(pysam.AlignmentFile(pref_nam+"-unassigned.bam", "wb", template=bam))
+
 file_names.append(pref_nam+"-not_mapped.bam")
 obam_files.append(pysam.AlignmentFile(pref_nam+"-not_mapped.bam", "wb", template=bam))
 
@@ -36,6 +44,9 @@ for b in bam.fetch(until_eof=True):
         if b.query_name in qnames:
             obam_files[i].write(b)
             not_frag=False
+    if b.query_name in unassigned:
+        obam_files[-2].write(b)
+        not_frag=False
     if not_frag:
         obam_files[-1].write(b);
 
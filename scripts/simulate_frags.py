@@ -0,0 +1,64 @@
+import bisect
+import random
+import sys
+
+# Simulate long-read fragments from a phased VCF. Writes a fragment file in the
+# same format as frag-dump/H-PoP; read names are of the form hap{i}_{n} so that
+# the true haplotype of every read is known.
+#
+# usage : python simulate_frags.py phased.vcf coverage read_length error_rate seed out.txt [max_snps]
+
+vcf_file = sys.argv[1]
+coverage = float(sys.argv[2])
+read_length = int(sys.argv[3])
+error_rate = float(sys.argv[4])
+random.seed(int(sys.argv[5]))
+out_file = sys.argv[6]
+max_snps = int(sys.argv[7]) if len(sys.argv) > 7 else -1
+
+positions = []
+haps = []
+for line in open(vcf_file, 'r'):
+    if line[0] == '#':
+        continue
+    spl = line.split()
+    gt = spl[9].split(':')[0].replace('/', '|').split('|')
+    if len(positions) > 0 and int(spl[1]) <= positions[-1]:
+        break
+    positions.append(int(spl[1]))
+    haps.append([int(x) for x in gt])
+    if max_snps > 0 and len(positions) >= max_snps:
+        break
+
+ploidy = len(haps[0])
+genome_length = positions[-1]
+num_reads = int(coverage * genome_length / read_length)
+
+reads = []
+for n in range(num_reads):
+    length = int(random.expovariate(1.0 / read_length)) + read_length // 4
+    start = random.randint(-length // 2, genome_length)
+    end = start + length
+    hap = random.randint(0, ploidy - 1)
+    reads.append((start, end, hap, n))
+
+out = open(out_file, 'w')
+lines = []
+for (start, end, hap, n) in reads:
+    lo = bisect.bisect_left(positions, start)
+    hi = bisect.bisect_right(positions, end)
+    if hi - lo < 2:
+        continue
+    seq = []
+    for i in range(lo, hi):
+        allele = haps[i][hap]
+        if random.random() < error_rate:
+            allele = 1 - allele
+        seq.append(str(allele))
+    quals = ''.join(['?' for _ in seq])
+    lines.append((lo + 1, "1\thap{}_{}\t{}\t{}\t{}\n".format(hap, n, lo + 1, ''.join(seq), quals)))
+
+lines.sort()
+for l in lines:
+    out.write(l[1])
+out.close()
@@ -2,12 +2,17 @@ extern crate time;
 use clap::{App, AppSettings, Arg};
 use flopp::file_reader;
 use flopp::local_clustering;
-use flopp::types_structs::Frag;
+use flopp::local_clustering::{CoverageObjective, HapCoverage, PartitionObjective, PemObjective, UpemObjective};
+use flopp::types_structs::{Frag, FragIndex};
 use flopp::types_structs::HapBlock;
+use flopp::types_structs::MaxFragSpan;
+use flopp::types_structs::ParentGenotypes;
 use flopp::utils_frags;
 use flopp::vcf_polishing;
+use flopp::vcf_polishing::{BlockLinker, SwitchCorrection};
 use fxhash::{FxHashMap, FxHashSet};
 use rayon::prelude::*;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 use std::time::Instant;
 
@@ -29,8 +34,29 @@ fn main() {
                           .arg(Arg::with_name("bam")
                               .short("b")
                               .value_name("BAMFILE")
-                               .help("Input a bam file.")
-                                .takes_value(true))
+                               .help("Input a bam file. Can be given multiple times, e.g. for reads from different sequencing technologies; fragments are merged per contig.")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1))
+                          .arg(Arg::with_name("bam_epsilon")
+                              .long("bam-epsilon")
+                              .value_name("EPSILON")
+                              .help("Error rate of the reads in each bam file, given once per -b in the same order. Use this when combining technologies with different error rates. (default : all reads use the global error rate -e)")
+                              .takes_value(true)
+                              .multiple(true)
+                              .number_of_values(1))
+                          .arg(Arg::with_name("sample")
+                              .long("sample")
+                              .value_name("NAME")
+                              .help("Sample of the reads in each bam file, given once per -b in the same order. With two or more samples, e.g. an F1 population or clonal lines, the samples are phased jointly : reads of all samples are clustered into a shared pool of haplotypes, each sample is assigned its -p haplotypes from the pool in every local block, and the haplotypes of each sample are written to -o with the sample name added, e.g. results.S1.txt.")
+                              .takes_value(true)
+                              .multiple(true)
+                              .number_of_values(1))
+                          .arg(Arg::with_name("pool_size")
+                              .long("pool-size")
+                              .value_name("NUMBER")
+                              .help("Number of distinct haplotypes over all samples given with --sample. (default : twice the ploidy, e.g. the haplotypes of the two parents of an F1 population, but at most the ploidy times the number of samples)")
+                              .takes_value(true))
                           .arg(Arg::with_name("vcf")
                                .short("v")
                                .help("Input a VCF: Mandatory if using BAM file; Enables genotype polishing if using frag file.")
@@ -40,7 +66,7 @@ fn main() {
                               .short("p")
                               .help("Ploidy of organism.")
                               .value_name("PLOIDY")
-                              .required(true)
+                              .required_unless("strains")
                               .takes_value(true))
                           .arg(Arg::with_name("threads")
                               .short("t")
@@ -78,13 +104,150 @@ fn main() {
                               .takes_value(true)
                               .value_name("BLOCK LENGTH QUANTILE (0.00-1.00)")
                               .help("The quantile we use for the haplotype block lengths. Make higher for larger initial blocks during haplotyping. (default : 0.33 a.k.a 33% quantile)"))
+                          .arg(Arg::with_name("adaptive_blocks")
+                              .long("adaptive-blocks")
+                              .help("Choose local block boundaries from the reads : block lengths follow the -B quantile of the lengths of nearby reads, and blocks end where few reads cover a SNP. (default : blocks of equal length)"))
+
+                          .arg(Arg::with_name("block_overlap")
+                              .long("block-overlap")
+                              .takes_value(true)
+                              .value_name("SNPS")
+                              .help("Number of SNPs by which consecutive local blocks overlap. Blocks are then also linked by how well their haplotypes agree on the shared SNPs, which helps at low coverage. Only with the greedy linker. (default : 0)"))
+
+                          .arg(Arg::with_name("init")
+                              .long("init")
+                              .takes_value(true)
+                              .value_name("clique|kmedoids|spectral")
+                              .help("How the reads of a local block are split into the initial clusters which are then optimized : clique seeds the clusters with the most distant reads, kmedoids uses k-medoids on the distances between reads, spectral uses spectral clustering of the read graph. (default : clique)"))
+                          .arg(Arg::with_name("restarts")
+                              .long("restarts")
+                              .takes_value(true)
+                              .value_name("N")
+                              .help("Also optimize each local block from N random perturbations of its initial clustering, in parallel, and keep the partition with the best score. --seed makes the perturbations reproducible. (default : 0)"))
+                          .arg(Arg::with_name("linker")
+                              .long("linker")
+                              .takes_value(true)
+                              .value_name("greedy|pairwise|beam")
+                              .help("How local blocks are linked : greedy joins each block to the haplotypes so far by shared reads, pairwise links consecutive blocks by shared reads in parallel, beam keeps the --beam-width linkings with the lowest MEC score. (default : greedy)"))
+                          .arg(Arg::with_name("beam_width")
+                              .long("beam-width")
+                              .takes_value(true)
+                              .value_name("WIDTH")
+                              .help("Number of linkings kept by the beam linker. (default : 4)"))
+                          .arg(Arg::with_name("refine_iters")
+                              .long("refine-iters")
+                              .takes_value(true)
+                              .value_name("ITERS")
+                              .help("Refine the linked haplotypes on windows sliding across each contig, with at most this many UPEM (or MEC with -m) iterations per window. Fixes reads misassigned near block boundaries. (default : 0, no refinement)"))
+                          .arg(Arg::with_name("switch_correction")
+                              .long("switch-correction")
+                              .takes_value(true)
+                              .value_name("flip|split")
+                              .help("Find switch errors from reads whose alleles agree with another haplotype on one side, and either swap the haplotypes back (flip) or start a new phased block (split) at each switch. (default : off)"))
+                          .arg(Arg::with_name("switch_report")
+                              .long("switch-report")
+                              .takes_value(true)
+                              .value_name("FILE")
+                              .help("Write the switch errors found to this file, with or without --switch-correction."))
+                          .arg(Arg::with_name("epsilon_report")
+                              .long("epsilon-report")
+                              .takes_value(true)
+                              .value_name("FILE")
+                              .help("Write the estimated epsilon and the error rates of the blocks it was estimated from to this file. Not used with -e."))
+                          .arg(Arg::with_name("seed")
+                              .long("seed")
+                              .takes_value(true)
+                              .value_name("SEED")
+                              .help("Seed for the random sampling of blocks when estimating epsilon and for --restarts. (default : 1)"))
 
+                          .arg(Arg::with_name("max_frag_span")
+                              .long("max-frag-span")
+                              .takes_value(true)
+                              .value_name("SNPS")
+                              .help("Ignore fragments spanning more than this many SNPs, e.g. chimeric or mismapped reads. (default : ignore fragments whose genome span is an extreme outlier of the fragment span distribution)"))
+                          .arg(Arg::with_name("max_read_error")
+                              .long("max-read-error")
+                              .takes_value(true)
+                              .value_name("RATE|auto")
+                              .help("Leave reads with a higher error rate against their haplotype after local clustering out of linking, refinement and switch correction, and put them on the haplotype they agree with best afterwards. auto uses three times the median read error rate, at least 0.1. (default : keep all reads)"))
+                          .arg(Arg::with_name("min_assignment_margin")
+                              .long("min-assignment-margin")
+                              .takes_value(true)
+                              .value_name("MARGIN")
+                              .help("Leave reads unassigned in the -P output if the log likelihood of their best haplotype is less than this much higher than that of the second best. Use 0 to assign all reads. (default : 5)"))
+                          .arg(Arg::with_name("read_probabilities")
+                              .long("read-probabilities")
+                              .takes_value(true)
+                              .value_name("FILE")
+                              .help("Write the posterior probability of every haplotype for each read to this file, one read per line as (read) (contig) (p_0) ... (p_ploidy-1)."))
                           .arg(Arg::with_name("use_mec")
                               .short("m")
                               .help("Use MEC score instead of UPEM for cluster refinement. Use this when your haplotypes have unbalanced coverage. (default : use UPEM)"))
+                          .arg(Arg::with_name("hap_coverage")
+                              .long("hap-coverage")
+                              .takes_value(true)
+                              .value_name("PROPORTIONS|estimate")
+                              .help("Expected proportions of the reads on the haplotypes, e.g. 2,1,1 for triploid, or estimate to estimate them from sampled blocks. Replaces the equal coverage term of UPEM by a fit to these proportions; overrides -m. (default : equal coverage)"))
+                          .arg(Arg::with_name("subgenomes")
+                              .long("subgenomes")
+                              .takes_value(true)
+                              .value_name("NAME:PLOIDY,...")
+                              .help("Subgenomes of an allopolyploid and their ploidies, e.g. A:2,B:2,D:2 for wheat. Used with --subgenome-contigs and/or --subgenome-reads; reads from different subgenomes are phased separately with the ploidy of their subgenome."))
+                          .arg(Arg::with_name("subgenome_contigs")
+                              .long("subgenome-contigs")
+                              .takes_value(true)
+                              .value_name("FILE")
+                              .help("File of (contig) (subgenome) lines. The reads of these contigs are phased with the ploidy of the contig's subgenome; other contigs use -p."))
+                          .arg(Arg::with_name("subgenome_reads")
+                              .long("subgenome-reads")
+                              .takes_value(true)
+                              .value_name("FILE")
+                              .help("File of (read) (subgenome) lines, e.g. from a subgenome classifier. The reads of each contig not in --subgenome-contigs are split by subgenome and each subgenome is phased separately with its ploidy, written as contig (contig)_(subgenome). Reads not in the file are left out."))
+                          .arg(Arg::with_name("strains")
+                              .long("strains")
+                              .takes_value(true)
+                              .value_name("MAX")
+                              .help("Metagenomic mode : separate co-occurring strains instead of haplotypes of a polyploid. The number of strains of each local block is chosen between 1 and MAX by BIC, clusters are refined with PEM (no equal coverage term) and empty strains are left out of the output. Replaces -p; VCF genotypes are not used for polishing."))
+                          .arg(Arg::with_name("strain_report")
+                              .long("strain-report")
+                              .takes_value(true)
+                              .value_name("FILE")
+                              .help("Write the number of reads and relative abundance of each strain found with --strains to this file."))
+                          .arg(Arg::with_name("parent_vcf")
+                              .long("parent-vcf")
+                              .takes_value(true)
+                              .value_name("VCFFILE")
+                              .multiple(true)
+                              .number_of_values(1)
+                              .help("VCF with the genotypes of a parent, given once or twice. Each parent passes on half its ploidy of homologs, so the haplotypes are assigned to the parents, haplotypes of different parents swapped between local blocks are fixed using the alleles each parent can pass on and, with -v, haplotypes are only polished to alleles their parent can pass on. SNPs are matched to -v/-c by position; requires BAM input or a VCF with -f."))
+                          .arg(Arg::with_name("panel_vcf")
+                              .long("panel-vcf")
+                              .takes_value(true)
+                              .value_name("VCFFILE")
+                              .help("Phased VCF of a panel of related samples, e.g. other cultivars of the same family. Where few reads span two local blocks, haplotypes are swapped between the blocks if that makes them continue along haplotypes of the panel. SNPs are matched to -v/-c by position; requires BAM input or a VCF with -f."))
+                          .arg(Arg::with_name("panel_weight")
+                              .long("panel-weight")
+                              .takes_value(true)
+                              .value_name("FLOAT")
+                              .help("Number of read errors a SNP agreeing with a panel haplotype counts as when linking blocks with --panel-vcf. (default : 1.0)"))
                           .arg(Arg::with_name("fill_in")
                               .short("i")
                               .help("Fill in blocks that have a lot of errors (off by default)."))
+                          .arg(Arg::with_name("max_split_gap")
+                              .long("max-split-gap")
+                              .takes_value(true)
+                              .value_name("BASES")
+                              .help("Split (supplementary) alignments of a read are stitched into one fragment if they are on the same strand, agree on shared SNPs and are at most this many bases apart on the reference. Otherwise they become separate fragments. (default : 10000)"))
+                          .arg(Arg::with_name("group_by")
+                              .long("group-by")
+                              .takes_value(true)
+                              .value_name("qname|BX|TAG")
+                              .help("How alignments are grouped into fragments when using a BAM file : by read name (qname, also joins Hi-C read pairs), or by a SAM tag such as the BX barcode for linked reads. (default : qname)"))
+                          .arg(Arg::with_name("max_group_gap")
+                              .long("max-group-gap")
+                              .takes_value(true)
+                              .value_name("BASES")
+                              .help("With --group-by TAG, split a group into separate molecules where consecutive alignments are more than this many bases apart. Alignments on different contigs are always separate. (default : 20000)"))
                           .arg(Arg::with_name("keep_duplicates")
                               .short("d")
                               .help("Allow duplicate reads in partition. This doesn't change the phasing algorithm but allows reads to be in multiple partitions, perhaps simplifying downstream analysis. (off by default)"))
@@ -103,13 +266,169 @@ fn main() {
         Ok(num_t) => num_t,
         Err(_) => panic!("Number of threads must be positive integer"),
     };
-    let ploidy = matches.value_of("ploidy").unwrap();
-    let ploidy = match ploidy.parse::<usize>() {
-        Ok(ploidy) => ploidy,
-        Err(_) => panic!("Must input valid ploidy"),
+    //In metagenomic mode, blocks are clustered into up to max_strains strains and linked as if
+    //the ploidy was max_strains.
+    let max_strains = match matches.value_of("strains") {
+        None => None,
+        Some(value) => match value.parse::<usize>() {
+            Ok(max_strains) if max_strains > 0 => Some(max_strains),
+            _ => panic!("Maximum number of strains must be a positive integer."),
+        },
+    };
+    let strain_report = matches.value_of("strain_report");
+    let parent_vcf_files: Vec<&str> = match matches.values_of("parent_vcf") {
+        None => vec![],
+        Some(values) => values.collect(),
+    };
+    if parent_vcf_files.len() > 2 {
+        panic!("At most two parent VCFs can be given.");
+    }
+    if !parent_vcf_files.is_empty() && max_strains.is_some() {
+        panic!("Parent VCFs can't be used with --strains.");
+    }
+    let panel_vcf_file = matches.value_of("panel_vcf");
+    let panel_weight = match matches.value_of("panel_weight").unwrap_or("1.0").parse::<f64>() {
+        Ok(panel_weight) if panel_weight >= 0.0 => panel_weight,
+        _ => panic!("Panel weight must be a non-negative float."),
+    };
+    let ploidy = match max_strains {
+        Some(max_strains) => max_strains,
+        None => match matches.value_of("ploidy").unwrap().parse::<usize>() {
+            Ok(ploidy) => ploidy,
+            Err(_) => panic!("Must input valid ploidy"),
+        },
+    };
+
+    let max_split_gap_str = matches.value_of("max_split_gap").unwrap_or("10000");
+    let max_split_gap = match max_split_gap_str.parse::<i64>() {
+        Ok(max_split_gap) if max_split_gap >= 0 => max_split_gap,
+        _ => panic!("Maximum split alignment gap must be a non-negative integer."),
+    };
+    let group_key_str = matches.value_of("group_by").unwrap_or("qname");
+    let group_key = match file_reader::parse_group_key(group_key_str) {
+        Some(group_key) => group_key,
+        None => panic!("Fragment grouping key must be qname or a two character SAM tag (e.g. BX)."),
+    };
+    let max_group_gap = match matches.value_of("max_group_gap") {
+        Some(value) => match value.parse::<i64>() {
+            Ok(max_group_gap) if max_group_gap >= 0 => max_group_gap,
+            _ => panic!("Maximum gap within a group must be a non-negative integer."),
+        },
+        None => file_reader::DEFAULT_MAX_GROUP_GAP,
+    };
+
+    let block_overlap_str = matches.value_of("block_overlap").unwrap_or("0");
+    let block_overlap = match block_overlap_str.parse::<usize>() {
+        Ok(block_overlap) => block_overlap,
+        Err(_) => panic!("Block overlap must be a non-negative integer."),
+    };
+
+    let init_str = matches.value_of("init").unwrap_or("clique");
+    let init = match local_clustering::parse_cluster_init(init_str) {
+        Some(init) => init,
+        None => panic!("Initial clustering must be one of clique, kmedoids or spectral."),
+    };
+
+    let linker_str = matches.value_of("linker").unwrap_or("greedy");
+    let linker = match vcf_polishing::parse_linker(linker_str) {
+        Some(linker) => linker,
+        None => panic!("Linker must be one of greedy, pairwise or beam."),
+    };
+    let beam_width_str = matches.value_of("beam_width").unwrap_or("4");
+    let beam_width = match beam_width_str.parse::<usize>() {
+        Ok(beam_width) if beam_width > 0 => beam_width,
+        _ => panic!("Beam width must be a positive integer."),
+    };
+    //Only the greedy linker uses the shared SNPs of overlapping blocks and resolves reads they
+    //put on different haplotypes.
+    if block_overlap > 0 && linker != BlockLinker::Greedy {
+        panic!("--block-overlap can only be used with the greedy linker.");
+    }
+
+    let refine_iters_str = matches.value_of("refine_iters").unwrap_or("0");
+    let refine_iters = match refine_iters_str.parse::<usize>() {
+        Ok(refine_iters) => refine_iters,
+        Err(_) => panic!("Number of refinement iterations must be a non-negative integer."),
+    };
+
+    let switch_correction = match matches.value_of("switch_correction") {
+        None => None,
+        Some(value) => match vcf_polishing::parse_switch_correction(value) {
+            Some(switch_correction) => Some(switch_correction),
+            None => panic!("Switch correction must be flip or split."),
+        },
+    };
+    let switch_report = matches.value_of("switch_report");
+    let epsilon_report = matches.value_of("epsilon_report");
+    let read_probabilities = matches.value_of("read_probabilities");
+
+    let num_restarts_str = matches.value_of("restarts").unwrap_or("0");
+    let num_restarts = match num_restarts_str.parse::<usize>() {
+        Ok(num_restarts) => num_restarts,
+        Err(_) => panic!("Number of restarts must be a non-negative integer."),
+    };
+    let restart_fraction = 0.25;
+
+    let seed_str = matches.value_of("seed").unwrap_or("1");
+    let seed = match seed_str.parse::<u64>() {
+        Ok(seed) => seed,
+        Err(_) => panic!("Seed must be a non-negative integer."),
+    };
+
+    let max_frag_span_opt = match matches.value_of("max_frag_span") {
+        None => None,
+        Some(value) => match value.parse::<usize>() {
+            Ok(max_frag_span) => Some(max_frag_span),
+            Err(_) => panic!("Maximum fragment span must be a positive integer."),
+        },
+    };
+
+    //Reads with a high error rate are only left out with --max-read-error; auto (None) derives
+    //the cutoff from the read error rates.
+    let max_read_error = match matches.value_of("max_read_error") {
+        None => None,
+        Some("auto") => Some(None),
+        Some(value) => match value.parse::<f64>() {
+            Ok(max_read_error) => Some(Some(max_read_error)),
+            Err(_) => panic!("Maximum read error rate must be a number or auto."),
+        },
+    };
+
+    let min_assignment_margin_str = matches.value_of("min_assignment_margin").unwrap_or("5");
+    let min_assignment_margin = match min_assignment_margin_str.parse::<f64>() {
+        Ok(min_assignment_margin) => min_assignment_margin,
+        Err(_) => panic!("Minimum assignment margin must be a number."),
     };
 
+    let subgenomes = match matches.value_of("subgenomes") {
+        None => None,
+        Some(value) => match file_reader::parse_subgenomes(value) {
+            Some(subgenomes) => Some(subgenomes),
+            None => panic!("Subgenomes must be comma separated name:ploidy pairs with distinct names, e.g. A:2,B:2,D:2."),
+        },
+    };
+    let subgenome_contigs_file = matches.value_of("subgenome_contigs");
+    let subgenome_reads_file = matches.value_of("subgenome_reads");
+    if (subgenome_contigs_file.is_some() || subgenome_reads_file.is_some()) != subgenomes.is_some() {
+        panic!("--subgenomes must be given together with --subgenome-contigs or --subgenome-reads.");
+    }
+
     let use_mec = matches.is_present("use_mec");
+    let hap_coverage = match matches.value_of("hap_coverage") {
+        None => None,
+        Some(value) => match local_clustering::parse_hap_coverage(value) {
+            Some(HapCoverage::Given(proportions)) if proportions.len() != ploidy && subgenomes.is_none() => {
+                panic!("Haplotype coverage must have one proportion for each of the {} haplotypes.", ploidy)
+            }
+            Some(hap_coverage) => Some(hap_coverage),
+            None => panic!("Haplotype coverage must be comma separated positive proportions or estimate."),
+        },
+    };
+    if hap_coverage.is_some() && max_strains.is_some() {
+        panic!("--hap-coverage can't be used with --strains, whose abundances are unknown.");
+    }
+    let num_coverage_tries = 50;
+    let adaptive_blocks = matches.is_present("adaptive_blocks");
     let fill = matches.is_present("fill_in");
     let keep_duplicates = matches.is_present("keep_duplicates");
 
@@ -134,15 +453,64 @@ fn main() {
 
     //If the user is getting frag files from BAM and VCF.
     let bam;
-    let bam_file = match matches.value_of("bam") {
+    let bam_files: Vec<&str> = match matches.values_of("bam") {
         None => {
             bam = false;
-            "_"
+            vec![]
         }
-        Some(bam_file) => {
+        Some(bam_files) => {
             bam = true;
-            bam_file
+            bam_files.collect()
+        }
+    };
+
+    //Per technology error rates for each BAM file.
+    let mut bam_epsilons: Vec<Option<f64>> = vec![None; bam_files.len()];
+    if let Some(values) = matches.values_of("bam_epsilon") {
+        let values: Vec<&str> = values.collect();
+        if values.len() != bam_files.len() {
+            panic!("--bam-epsilon must be given once for every BAM file.");
+        }
+        for (i, value) in values.iter().enumerate() {
+            bam_epsilons[i] = match value.parse::<f64>() {
+                Ok(bam_epsilon) if bam_epsilon > 0.0 && bam_epsilon < 1.0 => Some(bam_epsilon),
+                _ => panic!("BAM error rates must be floats between 0.00 and 1.00."),
+            };
+        }
+    }
+
+    //Samples of the reads of each BAM file. Several samples are phased jointly with a shared pool
+    //of pool_size haplotypes.
+    let mut sample_names: Vec<String> = Vec::new();
+    let mut source_samples: Vec<usize> = vec![0; bam_files.len()];
+    if let Some(values) = matches.values_of("sample") {
+        let values: Vec<&str> = values.collect();
+        if values.len() != bam_files.len() {
+            panic!("--sample must be given once for every BAM file.");
+        }
+        for (i, value) in values.iter().enumerate() {
+            source_samples[i] = match sample_names.iter().position(|name| name == value) {
+                Some(sample) => sample,
+                None => {
+                    sample_names.push(value.to_string());
+                    sample_names.len() - 1
+                }
+            };
         }
+    }
+    let pool_size = if sample_names.len() > 1 {
+        if max_strains.is_some() || subgenomes.is_some() || hap_coverage.is_some() {
+            panic!("Several samples can't be phased jointly with --strains, --subgenomes or --hap-coverage.");
+        }
+        match matches.value_of("pool_size") {
+            None => Some((2 * ploidy).min(ploidy * sample_names.len())),
+            Some(value) => match value.parse::<usize>() {
+                Ok(pool_size) if pool_size >= ploidy => Some(pool_size),
+                _ => panic!("Pool size must be an integer of at least the ploidy."),
+            },
+        }
+    } else {
+        None
     };
 
     //If user is using a frag file.
@@ -226,42 +594,163 @@ fn main() {
     //The inter quantile range outlier factor. 3.0 is standard for detecting extreme outliers
     //Blocks outside of the range will get filled in.
     let iqr_factor = 3.0;
-    let polish = vcf;
+    let polish = vcf && max_strains.is_none() && pool_size.is_none();
     //If we estimate the frag error rate by clustering a few random test blocks.
     let estimate_epsilon = true;
     //Number of iterations for the iterative UPEM optimization
     let num_iters_optimizing = 10;
+    //Reads need this many alleles compared to their haplotypes to be left out for a high error rate.
+    let min_read_error_bases = 10;
+    //An allele of a haplotype missing from its parent's genotype counts as this many read errors
+    //when fixing phase errors between haplotypes of different parents.
+    let parent_site_weight = 10;
+    //Log likelihood cost of a sample changing pool haplotypes between local blocks when several
+    //samples are phased jointly.
+    let recombination_cost = 20.0;
 
     println!("Reading inputs (BAM/VCF/frags).");
     let start_t = Instant::now();
-    let mut all_frags_map;
-    if bam {
-        all_frags_map = file_reader::get_frags_from_bamvcf(vcf_file, bam_file);
+    let all_frags_map = if bam {
+        file_reader::get_frags_from_multiple_bams(
+            vcf_file,
+            &bam_files,
+            &bam_epsilons,
+            &group_key,
+            max_group_gap,
+            max_split_gap,
+        )
     } else {
-        all_frags_map = file_reader::get_frags_container(frag_file);
-    }
+        file_reader::get_frags_container(frag_file)
+    };
     println!("Time taken reading inputs {:?}", Instant::now() - start_t);
 
     let mut genotype_dict_map: FxHashMap<String, FxHashMap<usize, FxHashMap<usize, usize>>> =
         FxHashMap::default();
     let mut snp_to_genome_pos_map: FxHashMap<String, Vec<usize>> = FxHashMap::default();
+    let mut vcf_ploidy = ploidy;
     if vcf || vcf_nopolish {
-        let (snp_to_genome_pos_t, genotype_dict_t, vcf_ploidy) =
+        let (snp_to_genome_pos_t, genotype_dict_t, vcf_ploidy_t) =
             file_reader::get_genotypes_from_vcf_hts(vcf_file);
         snp_to_genome_pos_map = snp_to_genome_pos_t;
         genotype_dict_map = genotype_dict_t;
+        vcf_ploidy = vcf_ploidy_t;
+    }
+
+    //Genotypes of the parents keyed by the SNPs of the offspring, and the number of homologs each
+    //parent passes on.
+    let mut parent_genotype_maps = Vec::new();
+    let mut parent_contributions = Vec::new();
+    for parent_vcf_file in parent_vcf_files.iter() {
+        if snp_to_genome_pos_map.is_empty() {
+            panic!("Parent VCFs need the genome positions of the SNPs from -v or -c.");
+        }
+        let (parent_genotype_map, parent_ploidy) =
+            file_reader::get_parent_genotypes(parent_vcf_file, &snp_to_genome_pos_map);
+        if parent_ploidy % 2 != 0 {
+            panic!("Parent ploidy must be even, but {} has ploidy {}.", parent_vcf_file, parent_ploidy);
+        }
+        parent_genotype_maps.push(parent_genotype_map);
+        parent_contributions.push(parent_ploidy / 2);
+    }
+
+    //Haplotypes of the reference panel keyed by the SNPs of the sample.
+    let panel_haplotype_map = match panel_vcf_file {
+        None => FxHashMap::default(),
+        Some(panel_vcf_file) => {
+            if snp_to_genome_pos_map.is_empty() {
+                panic!("A panel VCF needs the genome positions of the SNPs from -v or -c.");
+            }
+            file_reader::get_panel_haplotypes(panel_vcf_file, &snp_to_genome_pos_map)
+        }
+    };
+
+    //Fragments are phased in units : a whole contig, or with --subgenome-reads the reads of one
+    //subgenome on a contig. Reads of different subgenomes never share a unit, so they are never
+    //clustered or linked together.
+    let (contig_subgenomes, read_subgenomes) = match &subgenomes {
+        Some(subgenomes) => (
+            subgenome_contigs_file.map(|file| file_reader::get_subgenome_assignments(file, subgenomes)),
+            subgenome_reads_file.map(|file| file_reader::get_subgenome_assignments(file, subgenomes)),
+        ),
+        None => (None, None),
+    };
+    let contig_subgenomes = contig_subgenomes.unwrap_or_default();
+    let mut phasing_units = Vec::new();
+    for (contig, frags) in all_frags_map.into_iter() {
+        match (&subgenomes, contig_subgenomes.get(&contig), &read_subgenomes) {
+            (Some(subgenomes), Some(subgenome), _) => {
+                println!("Contig {} is phased as subgenome {}.", contig, subgenomes[*subgenome].name);
+                phasing_units.push(PhasingUnit {
+                    name: contig.clone(),
+                    contig,
+                    ploidy: subgenomes[*subgenome].ploidy,
+                    polish,
+                    frags,
+                });
+            }
+            (Some(subgenomes), None, Some(read_subgenomes)) => {
+                let (subgenome_frags, unknown_frags) =
+                    utils_frags::split_frags_by_subgenome(frags, read_subgenomes, subgenomes.len());
+                println!(
+                    "{} fragments of contig {} have no subgenome and are left out.",
+                    unknown_frags.len(),
+                    contig
+                );
+                //The genotypes of the VCF are over all subgenomes, so they can't be used to
+                //polish the haplotypes of a single subgenome.
+                if polish {
+                    println!("Haplotypes of contig {} are not polished since its reads are split by subgenome.", contig);
+                }
+                for (subgenome, frags) in subgenomes.iter().zip(subgenome_frags) {
+                    phasing_units.push(PhasingUnit {
+                        contig: contig.clone(),
+                        name: format!("{}_{}", contig, subgenome.name),
+                        ploidy: subgenome.ploidy,
+                        polish: false,
+                        frags,
+                    });
+                }
+            }
+            _ => phasing_units.push(PhasingUnit {
+                name: contig.clone(),
+                contig,
+                ploidy: pool_size.unwrap_or(ploidy),
+                polish,
+                frags,
+            }),
+        }
+    }
 
-        //If the VCF file is misformatted or has weird genotyping call we can catch that here.
-        if vcf_ploidy != ploidy {
-            if polish{
+    //If the VCF file is misformatted or has weird genotyping call we can catch that here. Each
+    //contig's genotypes must have the ploidy it is phased with; contigs of a subgenome whose
+    //genotypes don't are phased without polishing.
+    for unit in phasing_units.iter_mut().filter(|unit| unit.polish) {
+        let contig_vcf_ploidy = match genotype_dict_map.get(&unit.contig) {
+            Some(genotype_dict) => utils_frags::get_genotype_ploidy(genotype_dict).unwrap_or(vcf_ploidy),
+            None => vcf_ploidy,
+        };
+        if contig_vcf_ploidy != unit.ploidy {
+            if subgenomes.is_none() {
                 panic!("VCF File ploidy doesn't match input ploidy");
             }
+            println!(
+                "VCF ploidy {} of contig {} doesn't match its ploidy {}; its haplotypes are not polished.",
+                contig_vcf_ploidy, unit.name, unit.ploidy
+            );
+            unit.polish = false;
         }
     }
 
     let mut first_iter = true;
+    //With several samples, units are phased with the pool size and each sample has -p haplotypes.
+    let sample_ploidy = ploidy;
 
-    for (contig, all_frags) in all_frags_map.iter_mut() {
+    for PhasingUnit { contig, name, ploidy, polish, frags: all_frags } in phasing_units.iter_mut() {
+        let (ploidy, polish) = (*ploidy, *polish);
+        if all_frags.is_empty() {
+            println!("No fragments to phase for {}.", name);
+            continue;
+        }
         if snp_to_genome_pos_map.contains_key(contig) || bam == false {
             let mut genotype_dict: &FxHashMap<usize, FxHashMap<usize, usize>> =
                 &FxHashMap::default();
@@ -285,9 +774,60 @@ fn main() {
                 }
             }
 
+            //Genotypes of the parents, which constrain polishing and fix phase between haplotypes
+            //of different parents. With a single parent, the other parent passes on the remaining
+            //homologs and any allele.
+            let no_genotypes = FxHashMap::default();
+            let mut parents = None;
+            if !parent_genotype_maps.is_empty() {
+                let mut parent_genotypes: Vec<&FxHashMap<usize, FxHashMap<usize, usize>>> = parent_genotype_maps
+                    .iter()
+                    .map(|parent_genotype_map| {
+                        if bam {
+                            parent_genotype_map.get(contig).unwrap_or(&no_genotypes)
+                        } else {
+                            parent_genotype_map.values().next().unwrap_or(&no_genotypes)
+                        }
+                    })
+                    .collect();
+                let mut contributions = parent_contributions.clone();
+                if contributions.len() == 1 && contributions[0] <= ploidy {
+                    contributions.push(ploidy - contributions[0]);
+                    parent_genotypes.push(&no_genotypes);
+                }
+                if contributions.iter().sum::<usize>() != ploidy {
+                    println!(
+                        "The parents pass on {:?} homologs but {} has ploidy {}; parents are not used.",
+                        contributions, name, ploidy
+                    );
+                } else {
+                    parents = Some(ParentGenotypes { genotypes: parent_genotypes, contributions });
+                }
+            }
+
             //We need frags sorted by first position to make indexing easier.
             all_frags.sort_by(|a, b| a.first_position.cmp(&b.first_position));
 
+            //Fragments spanning too many SNPs are outliers which we ignore during clustering.
+            let max_frag_span = match max_frag_span_opt {
+                Some(max_frag_span) => MaxFragSpan::Snps(max_frag_span),
+                None => utils_frags::get_max_frag_span(all_frags, snp_to_genome_pos, iqr_factor),
+            };
+            let (max_span, span_unit) = match max_frag_span {
+                MaxFragSpan::Snps(max_snps) => (max_snps, "SNPs"),
+                MaxFragSpan::Bases(max_bases, _) => (max_bases, "bases"),
+            };
+            println!(
+                "{} fragments span more than {} {} and are ignored as outliers.",
+                utils_frags::count_long_frags(all_frags, max_frag_span),
+                max_span,
+                span_unit
+            );
+
+            //Index the fragments once so that finding the reads in a block doesn't need a scan
+            //over all fragments.
+            let frag_index = FragIndex::new(all_frags, max_frag_span);
+
             //We use the median # bases spanned by fragments as the length of blocks.
             let avg_read_length = utils_frags::get_avg_length(&all_frags, 0.5);
             println!("Median read length is {}", avg_read_length);
@@ -300,87 +840,195 @@ fn main() {
             //Final partitions
             let parts: Mutex<Vec<(Vec<FxHashSet<&Frag>>, usize)>> = Mutex::new(vec![]);
 
-            //The length of each local haplotype block. This is currently useless because all haplotype
-            //blocks have the same fixed length, but we may change this in the future.
-            let lengths: Mutex<Vec<usize>> = Mutex::new(vec![]);
-
             //UPEM scores for each block.
             let scores: Mutex<Vec<(f64, usize)>> = Mutex::new(vec![]);
             let length_block = utils_frags::get_avg_length(&all_frags, block_len_quant);
 
-            //If we want blocks to overlap -- I don't think we actually want blocks to overlap but this may
-            //be an optional parameter for testing purposes.
-            let overlap = 0;
-
             //Get last SNP on the genome covered over all fragments.
             let length_gn = utils_frags::get_length_gn(&all_frags);
             println!("Length of genome is {}", length_gn);
             println!("Length of each block is {}", length_block);
+
+            //The SNPs covered by each local haplotype block.
+            let mut blocks = if adaptive_blocks {
+                utils_frags::get_adaptive_blocks(&frag_index, length_gn, length_block, block_len_quant)
+            } else {
+                utils_frags::get_fixed_blocks(length_gn, length_block)
+            };
+            utils_frags::extend_blocks(&mut blocks, block_overlap, length_gn);
+            println!("Number of local blocks is {}", blocks.len());
+
             //How many blocks we iterate through to estimate epsilon.
             let num_epsilon_attempts = 20;
             let mut epsilon = 0.03;
-            let num_iters = length_gn / length_block;
-            if estimate_epsilon {
-                epsilon = local_clustering::estimate_epsilon(
-                    num_iters,
-                    num_epsilon_attempts,
-                    ploidy,
-                    &all_frags,
-                    length_block,
-                    epsilon,
-                );
-            }
+            match matches.value_of("epsilon") {
+                Some(value) => {
+                    epsilon = value.parse::<f64>().unwrap();
+                }
+                None if estimate_epsilon => {
+                    match local_clustering::estimate_epsilon(
+                        &blocks,
+                        num_epsilon_attempts,
+                        ploidy,
+                        max_strains.is_some(),
+                        &frag_index,
+                        epsilon,
+                        init,
+                        seed,
+                    ) {
+                        Some(estimate) => {
+                            println!(
+                                "Estimated epsilon is {} (95% confidence interval {} - {}), read error rate is {}",
+                                estimate.epsilon, estimate.lower, estimate.upper, estimate.read_epsilon
+                            );
+                            let noisy_blocks = estimate
+                                .block_estimates
+                                .iter()
+                                .filter(|block| block.epsilon > 2.0 * estimate.read_epsilon)
+                                .count();
+                            if estimate.read_epsilon > 0.1 || noisy_blocks > estimate.block_estimates.len() / 2 {
+                                println!(
+                                    "Warning : the reads are unusually noisy ({} of {} sampled blocks have more than twice the estimated error rate).",
+                                    noisy_blocks,
+                                    estimate.block_estimates.len()
+                                );
+                            }
+                            if let Some(epsilon_report) = epsilon_report {
+                                file_reader::write_epsilon_estimate_to_file(
+                                    epsilon_report,
+                                    &estimate,
+                                    snp_to_genome_pos,
+                                    first_iter,
+                                    name,
+                                );
+                            }
+                            epsilon = estimate.epsilon;
+                        }
+                        None => {
+                            println!("No reads to estimate epsilon from, using {}.", epsilon);
+                        }
+                    }
+                }
+                None => {}
+            };
 
             //TEST TODO
             //epsilon = epsilon * ploidy as f64 / 4.0;
 
-            if epsilon == 0.0 {
-                epsilon = 0.010;
-            }
+            println!("Epsilon is {}", epsilon);
 
-            match matches.value_of("epsilon") {
-                None => {}
-                Some(value) => {
-                    epsilon = value.parse::<f64>().unwrap();
+            let objective: Box<dyn PartitionObjective> = match &hap_coverage {
+                Some(HapCoverage::Given(proportions)) if proportions.len() == ploidy => {
+                    Box::new(CoverageObjective::new(binomial_factor, proportions))
+                }
+                Some(HapCoverage::Given(_)) => {
+                    panic!("Haplotype coverage must have one proportion for each of the {} haplotypes of {}.", ploidy, name)
+                }
+                Some(HapCoverage::Estimate) => {
+                    match local_clustering::estimate_hap_proportions(
+                        &blocks,
+                        num_coverage_tries,
+                        ploidy,
+                        &frag_index,
+                        epsilon,
+                        init,
+                        &PemObjective { div_factor: binomial_factor },
+                        seed,
+                    ) {
+                        Some(proportions) => {
+                            println!("Estimated haplotype coverage proportions are {:?}", proportions);
+                            Box::new(CoverageObjective::new(binomial_factor, &proportions))
+                        }
+                        None => {
+                            println!("No reads to estimate haplotype coverage from, using equal coverage.");
+                            Box::new(UpemObjective { div_factor: binomial_factor })
+                        }
+                    }
                 }
+                None if use_mec || max_strains.is_some() || pool_size.is_some() => Box::new(PemObjective { div_factor: binomial_factor }),
+                None => Box::new(UpemObjective { div_factor: binomial_factor }),
             };
 
-            println!("Estimated epsilon is {}", epsilon);
-
             println!("Generating haplotype blocks");
             let start_t = Instant::now();
 
             //Embarassing parallel building of local haplotype blocks using rayon crate.
-            (0..num_iters)
+            (0..blocks.len())
                 .collect::<Vec<usize>>()
                 .into_par_iter()
                 .for_each(|x| {
                     //            println!("{} iteration number", x);
-                    let block_start = x * length_block + 1;
-                    let part = local_clustering::generate_hap_block(
-                        block_start,
-                        block_start + length_block + overlap,
-                        ploidy,
-                        &all_frags,
-                        epsilon,
-                    );
-
-                    let (best_score, best_part, _best_block) =
-                        local_clustering::optimize_clustering(
-                            part,
+                    let cluster_block = |block_ploidy: usize| {
+                        let part = local_clustering::generate_hap_block(
+                            blocks[x].start,
+                            blocks[x].end,
+                            block_ploidy,
+                            &frag_index,
                             epsilon,
-                            &genotype_dict,
-                            polish,
-                            num_iters_optimizing,
-                            binomial_factor,
-                            use_mec,
+                            init,
                         );
 
+                        //Restarts optimize perturbed copies of the initial clustering in parallel,
+                        //and the partition with the best score is kept.
+                        let results: Vec<_> = (0..num_restarts + 1)
+                            .into_par_iter()
+                            .map(|restart| {
+                                let start_part = if restart == 0 {
+                                    part.clone()
+                                } else {
+                                    local_clustering::perturb_partition(
+                                        &part,
+                                        restart_fraction,
+                                        seed,
+                                        (x * num_restarts + restart - 1) as u64,
+                                    )
+                                };
+                                local_clustering::optimize_clustering(
+                                    start_part,
+                                    epsilon,
+                                    genotype_dict,
+                                    parents.as_ref(),
+                                    polish,
+                                    num_iters_optimizing,
+                                    objective.as_ref(),
+                                )
+                            })
+                            .collect();
+                        let mut best_result = None;
+                        for result in results {
+                            match &best_result {
+                                Some((score, _, _)) if result.0 <= *score => {}
+                                _ => best_result = Some(result),
+                            }
+                        }
+                        best_result.unwrap()
+                    };
+
+                    //In metagenomic mode the block is clustered into every number of strains up
+                    //to max_strains and the clustering with the lowest BIC is kept. It is padded
+                    //with empty strains so that all blocks can be linked.
+                    let (best_score, best_part, _best_block) = match max_strains {
+                        None => cluster_block(ploidy),
+                        Some(max_strains) => {
+                            let mut best_result = None;
+                            let mut best_bic = f64::MAX;
+                            for num_strains in 1..max_strains + 1 {
+                                let result = cluster_block(num_strains);
+                                let bic = local_clustering::get_strain_bic(&result.1, epsilon);
+                                if bic < best_bic {
+                                    best_bic = bic;
+                                    best_result = Some(result);
+                                }
+                            }
+                            let (score, mut part, block) = best_result.unwrap();
+                            part.resize(max_strains, FxHashSet::default());
+                            (score, part, block)
+                        }
+                    };
+
                     let mut locked_parts = parts.lock().unwrap();
-                    let mut locked_lengths = lengths.lock().unwrap();
                     let mut locked_scores = scores.lock().unwrap();
                     locked_parts.push((best_part, x));
-                    locked_lengths.push(length_block);
                     locked_scores.push((best_score, x));
 
                     //        println!("UPEM Score : {}", best_score);
@@ -391,24 +1039,51 @@ fn main() {
             //Sort the vectors which may be out of order due to multi-threading.
             let mut scores = scores.lock().unwrap().to_vec();
             scores.sort_by(|a, b| a.1.cmp(&b.1));
-            let scores = scores.into_iter().map(|x| x.0).collect();
+            let mut scores = scores.into_iter().map(|x| x.0).collect();
             let mut parts = parts.lock().unwrap().to_vec();
             parts.sort_by(|a, b| a.1.cmp(&b.1));
-            let parts = parts.into_iter().map(|x| x.0).collect();
+            let mut parts: Vec<Vec<FxHashSet<&Frag>>> = parts.into_iter().map(|x| x.0).collect();
+
+            //Reads with many errors against their haplotype, e.g. chimeras or reads from paralogs,
+            //are left out of linking and are put back on the haplotype they agree with best at the end.
+            let read_stats = local_clustering::get_read_error_stats(&parts);
+            let high_error_reads = match max_read_error {
+                Some(max_read_error) => {
+                    local_clustering::get_high_error_reads(&read_stats, max_read_error, min_read_error_bases)
+                }
+                None => FxHashSet::default(),
+            };
+            if max_read_error.is_some() {
+                println!(
+                    "{} reads have a high error rate and are left out of linking.",
+                    high_error_reads.len()
+                );
+            }
+            for part in parts.iter_mut() {
+                for reads in part.iter_mut() {
+                    reads.retain(|read| !high_error_reads.contains(read));
+                }
+            }
 
             let start_t = Instant::now();
-            let part_filled;
+            let mut part_filled;
 
             //Fill blocks
             if fill {
                 part_filled = vcf_polishing::replace_with_filled_blocks(
-                    &scores,
+                    &mut scores,
                     parts,
                     iqr_factor,
-                    length_block,
-                    &all_frags,
+                    &mut blocks,
+                    &frag_index,
                     epsilon,
                 );
+                //Filled in blocks are clustered from all reads in them.
+                for part in part_filled.iter_mut() {
+                    for reads in part.iter_mut() {
+                        reads.retain(|read| !high_error_reads.contains(read));
+                    }
+                }
             } else {
                 part_filled = parts;
             }
@@ -417,9 +1092,115 @@ fn main() {
             let start_t = Instant::now();
 
             //Link and polish all blocks.
-            //    let mut final_part = vcf_polishing::link_blocks(&part_filled);
-            let mut final_part = vcf_polishing::link_blocks_greedy(&part_filled, &all_frags);
-            //    let mut final_part = vcf_polishing::link_blocks_heur(&part_filled,4);
+            let mut final_part = match linker {
+                BlockLinker::Greedy => {
+                    if block_overlap > 0 {
+                        vcf_polishing::link_blocks_overlap(&part_filled, &blocks, all_frags)
+                    } else {
+                        vcf_polishing::link_blocks_greedy(&part_filled, all_frags)
+                    }
+                }
+                BlockLinker::Pairwise => vcf_polishing::link_blocks(&part_filled),
+                BlockLinker::Beam => vcf_polishing::link_blocks_heur(&part_filled, beam_width),
+            };
+
+            if refine_iters > 0 {
+                let start_t = Instant::now();
+                let linked_block = utils_frags::hap_block_from_partition(&final_part);
+                vcf_polishing::remove_duplicate_reads(&mut final_part, all_frags, &linked_block);
+                final_part = local_clustering::refine_partition(
+                    final_part,
+                    &frag_index,
+                    length_gn,
+                    length_block,
+                    epsilon,
+                    genotype_dict,
+                    parents.as_ref(),
+                    polish,
+                    refine_iters,
+                    objective.as_ref(),
+                );
+                println!("Time taken global refinement {:?}", Instant::now() - start_t);
+            }
+
+            //Haplotypes of different parents swapped between local blocks are found from the alleles
+            //each parent can pass on.
+            if let Some(parents) = &parents {
+                let linked_block = utils_frags::hap_block_from_partition(&final_part);
+                let costs = vcf_polishing::get_parent_costs(&linked_block, parents, 1, length_gn);
+                let (hap_parents, cost) = vcf_polishing::assign_haplotypes_to_parents(&costs, &parents.contributions);
+                println!(
+                    "Parent of each haplotype is {:?}, with {} alleles missing from the parents' genotypes.",
+                    hap_parents, cost
+                );
+                let parental_switches = vcf_polishing::find_parental_switches(
+                    &final_part,
+                    &linked_block,
+                    &blocks,
+                    parents,
+                    &hap_parents,
+                    parent_site_weight,
+                );
+                println!(
+                    "Number of phase errors between haplotypes of different parents fixed is {}",
+                    parental_switches.len()
+                );
+                vcf_polishing::fix_switch_errors(&mut final_part, &parental_switches);
+            }
+
+            //Haplotypes are swapped between local blocks to continue along panel haplotypes where
+            //the reads spanning the blocks don't say otherwise.
+            if panel_vcf_file.is_some() {
+                let no_haplotypes = Vec::new();
+                let panel = if bam {
+                    panel_haplotype_map.get(contig).unwrap_or(&no_haplotypes)
+                } else {
+                    panel_haplotype_map.values().next().unwrap_or(&no_haplotypes)
+                };
+                let linked_block = utils_frags::hap_block_from_partition(&final_part);
+                let panel_switches =
+                    vcf_polishing::find_panel_switches(&final_part, &linked_block, &blocks, panel, panel_weight);
+                println!(
+                    "Number of phase errors fixed using {} panel haplotypes is {}",
+                    panel.len(),
+                    panel_switches.len()
+                );
+                vcf_polishing::fix_switch_errors(&mut final_part, &panel_switches);
+            }
+
+            //Phased blocks are ended at switch errors with --switch-correction split.
+            let mut phased_lengths = vec![length_gn];
+            if switch_correction.is_some() || switch_report.is_some() {
+                let linked_block = utils_frags::hap_block_from_partition(&final_part);
+                let switch_errors = vcf_polishing::find_switch_errors(&final_part, &linked_block, epsilon, 1);
+                println!("Number of switch errors found is {}", switch_errors.len());
+                match switch_correction {
+                    Some(SwitchCorrection::Flip) => {
+                        vcf_polishing::fix_switch_errors(&mut final_part, &switch_errors)
+                    }
+                    Some(SwitchCorrection::Split) => {
+                        let mut breaks: Vec<usize> = switch_errors.iter().map(|x| x.position).collect();
+                        breaks.dedup();
+                        breaks.push(length_gn + 1);
+                        phased_lengths = Vec::new();
+                        let mut prev_break = 1;
+                        for position in breaks {
+                            phased_lengths.push(position - prev_break);
+                            prev_break = position;
+                        }
+                    }
+                    None => {}
+                }
+                if let Some(switch_report) = switch_report {
+                    file_reader::write_switch_errors_to_file(
+                        switch_report,
+                        &switch_errors,
+                        snp_to_genome_pos,
+                        first_iter,
+                        name,
+                    );
+                }
+            }
 
             //    for i in 0..ploidy{
             //        let inter =  &final_part[i].intersection(&final_part2[i]).collect::<Vec<_>>();
@@ -429,8 +1210,17 @@ fn main() {
             //        dbg!(final_part[i].len());
             //        dbg!(final_part2[i].len());
             //    }
+            //Put back the reads with a high error rate.
+            let high_error_reads: Vec<&Frag> = high_error_reads.into_iter().collect();
+            vcf_polishing::assign_reads_to_haplotypes(&mut final_part, &high_error_reads);
+
+            if max_strains.is_some() {
+                final_part = local_clustering::sort_strains(final_part);
+                println!("Number of strains found is {}", final_part.len());
+            }
+
             let final_block_unpolish = utils_frags::hap_block_from_partition(&final_part);
-            let mut final_block_polish = HapBlock { blocks: Vec::new() };
+            let mut final_block_polish = HapBlock { blocks: Vec::new(), sample_blocks: Vec::new() };
             if polish {
                 final_block_polish = vcf_polishing::polish_using_vcf(
                     &genotype_dict,
@@ -449,6 +1239,36 @@ fn main() {
                 );
             }
 
+            if max_strains.is_some() {
+                let num_reads: usize = final_part.iter().map(|reads| reads.len()).sum();
+                let abundances: Vec<f64> = final_part
+                    .iter()
+                    .map(|reads| reads.len() as f64 / num_reads as f64)
+                    .collect();
+                println!("Strain abundances are {:?}", abundances);
+                if let Some(strain_report) = strain_report {
+                    file_reader::write_strain_abundances_to_file(strain_report, &final_part, first_iter, name);
+                }
+            }
+
+            //Reads which fit two haplotypes about equally well are left unassigned in the -P
+            //partition. The haplotypes written to -o are still built from all reads.
+            let linked_block = utils_frags::hap_block_from_partition(&final_part);
+            let margins = vcf_polishing::get_assignment_margins(&final_part, &linked_block, epsilon);
+            if let Some(read_probabilities) = read_probabilities {
+                file_reader::write_read_probabilities_to_file(
+                    read_probabilities,
+                    &vcf_polishing::get_assignment_probabilities(&final_part, &linked_block, epsilon),
+                    final_part.len(),
+                    first_iter,
+                    name,
+                );
+            }
+            let mut assigned_part = final_part.clone();
+            let unassigned_reads =
+                vcf_polishing::remove_ambiguous_reads(&mut assigned_part, &margins, min_assignment_margin);
+            println!("{} reads are ambiguous and are left unassigned.", unassigned_reads.len());
+
             let (f_binom_vec, f_freq_vec) =
                 local_clustering::get_partition_stats(&final_part, &final_block_unpolish);
             let final_score = local_clustering::get_mec_score(&f_binom_vec, &f_freq_vec, 0.0, 0.0);
@@ -479,37 +1299,139 @@ fn main() {
             if polish {
                 file_reader::write_blocks_to_file(
                     output_blocks_str,
-                    &vec![final_block_polish],
-                    &vec![length_gn],
+                    &split_block(&final_block_polish, &phased_lengths),
+                    &phased_lengths,
                     &snp_to_genome_pos,
                     &final_part,
                     first_iter,
-                    contig,
+                    name,
                 );
             } else {
                 file_reader::write_blocks_to_file(
                     output_blocks_str,
-                    &vec![final_block_unpolish],
-                    &vec![length_gn],
+                    &split_block(&final_block_unpolish, &phased_lengths),
+                    &phased_lengths,
                     &snp_to_genome_pos,
                     &final_part,
                     first_iter,
-                    contig,
+                    name,
                 );
             }
 
             if bam_part_out {
-                file_reader::write_output_partition_to_file(&final_part, bam_part_out_dir, contig);
+                file_reader::write_output_partition_to_file(
+                    &assigned_part,
+                    &unassigned_reads,
+                    &read_stats,
+                    &margins,
+                    bam_part_out_dir,
+                    name,
+                );
+                file_reader::write_local_blocks_to_file(&blocks, snp_to_genome_pos, bam_part_out_dir, name);
+            }
+
+            //The haplotypes of each sample are taken from the pool and written to their own output
+            //and partition files.
+            if pool_size.is_some() {
+                let sample_haps = vcf_polishing::assign_samples_to_haplotypes(
+                    &final_part,
+                    &source_samples,
+                    sample_names.len(),
+                    &blocks,
+                    sample_ploidy,
+                    recombination_cost,
+                );
+                for (sample, sample_name) in sample_names.iter().enumerate() {
+                    let haps = &sample_haps[sample];
+                    let num_changes: usize = haps
+                        .windows(2)
+                        .map(|pair| pair[0].iter().zip(pair[1].iter()).filter(|(a, b)| a != b).count())
+                        .sum();
+                    println!(
+                        "Sample {} starts on pool haplotypes {:?} and changes pool haplotype {} times.",
+                        sample_name,
+                        haps.first().cloned().unwrap_or_default(),
+                        num_changes
+                    );
+                    let sample_part = vcf_polishing::get_sample_partition(
+                        &final_part,
+                        &blocks,
+                        haps,
+                        &source_samples,
+                        sample,
+                        sample_ploidy,
+                    );
+                    file_reader::write_blocks_to_file(
+                        sample_output_path(output_blocks_str, sample_name),
+                        &split_block(&utils_frags::hap_block_from_partition(&sample_part), &phased_lengths),
+                        &phased_lengths,
+                        snp_to_genome_pos,
+                        &sample_part,
+                        first_iter,
+                        name,
+                    );
+                    if bam_part_out {
+                        //Margins between pool haplotypes the sample doesn't carry don't matter for
+                        //its partition, so they are taken against the sample's own copies.
+                        let sample_margins = vcf_polishing::get_sample_assignment_margins(&sample_part, epsilon);
+                        let mut sample_assigned_part = sample_part.clone();
+                        let sample_unassigned = vcf_polishing::remove_ambiguous_reads(
+                            &mut sample_assigned_part,
+                            &sample_margins,
+                            min_assignment_margin,
+                        );
+                        file_reader::write_output_partition_to_file(
+                            &sample_assigned_part,
+                            &sample_unassigned,
+                            &read_stats,
+                            &sample_margins,
+                            bam_part_out_dir,
+                            &format!("{}_{}", name, sample_name),
+                        );
+                    }
+                }
             }
 
             first_iter = false;
 
             println!(
                 "Time taken writing blocks for contig {} to {} : {:?}",
-                contig,
+                name,
                 output_blocks_str,
                 Instant::now() - start_t
             );
         }
     }
 }
+
+//Split a haplotype block into consecutive blocks with the given numbers of SNPs.
+fn split_block(hap_block: &HapBlock, lengths: &[usize]) -> Vec<HapBlock> {
+    let mut start = 1;
+    let mut blocks = Vec::new();
+    for length in lengths.iter() {
+        blocks.push(vcf_polishing::clone_block_range(hap_block, start, start + length - 1));
+        start += length;
+    }
+    blocks
+}
+
+//Output file of a sample phased jointly with others : the sample name is added before the
+//extension, e.g. results.txt becomes results.S1.txt.
+fn sample_output_path(output: &str, sample: &str) -> PathBuf {
+    let path = Path::new(output);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    match path.extension() {
+        Some(extension) => path.with_file_name(format!("{}.{}.{}", stem, sample, extension.to_string_lossy())),
+        None => path.with_file_name(format!("{}.{}", stem, sample)),
+    }
+}
+
+//Fragments phased together with the given ploidy : the reads of a contig, or of one subgenome
+//on a contig. name is the contig name used in the outputs.
+struct PhasingUnit {
+    contig: String,
+    name: String,
+    ploidy: usize,
+    polish: bool,
+    frags: Vec<Frag>,
+}
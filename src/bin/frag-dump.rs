@@ -26,6 +26,21 @@ fn main() {
                               .value_name("OUTPUT")
                               .takes_value(true)
                               .required(true))
+                          .arg(Arg::with_name("max_split_gap")
+                              .long("max-split-gap")
+                              .takes_value(true)
+                              .value_name("BASES")
+                              .help("Split (supplementary) alignments of a read are stitched into one fragment if they are on the same strand, agree on shared SNPs and are at most this many bases apart on the reference. Otherwise they become separate fragments. (default : 10000)"))
+                          .arg(Arg::with_name("group_by")
+                              .long("group-by")
+                              .takes_value(true)
+                              .value_name("qname|BX|TAG")
+                              .help("How alignments are grouped into fragments : by read name (qname) or by a SAM tag such as the BX barcode for linked reads. (default : qname)"))
+                          .arg(Arg::with_name("max_group_gap")
+                              .long("max-group-gap")
+                              .takes_value(true)
+                              .value_name("BASES")
+                              .help("With --group-by TAG, split a group into separate molecules where consecutive alignments are more than this many bases apart. Alignments on different contigs are always separate. (default : 20000)"))
                           .get_matches();
 
     let bam_file = matches.value_of("bam").unwrap();
@@ -34,11 +49,28 @@ fn main() {
     let vcf_file = matches.value_of("vcf").unwrap();
     let start_t = Instant::now();
     let output_frag_str = matches.value_of("output").unwrap_or("flopp_frags.txt");
+    let max_split_gap_str = matches.value_of("max_split_gap").unwrap_or("10000");
+    let max_split_gap = match max_split_gap_str.parse::<i64>() {
+        Ok(max_split_gap) if max_split_gap >= 0 => max_split_gap,
+        _ => panic!("Maximum split alignment gap must be a non-negative integer."),
+    };
+    let group_key_str = matches.value_of("group_by").unwrap_or("qname");
+    let group_key = match file_reader::parse_group_key(group_key_str) {
+        Some(group_key) => group_key,
+        None => panic!("Fragment grouping key must be qname or a two character SAM tag (e.g. BX)."),
+    };
+    let max_group_gap = match matches.value_of("max_group_gap") {
+        Some(value) => match value.parse::<i64>() {
+            Ok(max_group_gap) if max_group_gap >= 0 => max_group_gap,
+            _ => panic!("Maximum gap within a group must be a non-negative integer."),
+        },
+        None => file_reader::DEFAULT_MAX_GROUP_GAP,
+    };
 
     //CONSTANTS - Constants which users probably should not change.
 
     println!("Reading frags.");
-    let all_frags_map = file_reader::get_frags_from_bamvcf(vcf_file, bam_file);
+    let all_frags_map = file_reader::get_frags_from_bamvcf(vcf_file, bam_file, &group_key, max_group_gap, max_split_gap);
     let mut all_frags = Vec::new();
     for (_id,vect) in all_frags_map.into_iter(){
         all_frags = vect;
@@ -1,4 +1,4 @@
-use crate::types_structs::{build_frag, update_frag, Frag, HapBlock};
+use crate::types_structs::{build_frag, update_frag, EpsilonEstimate, Frag, HapBlock, LocalBlock, Subgenome, SwitchError};
 use crate::utils_frags;
 use fxhash::{FxHashMap, FxHashSet};
 use rust_htslib::bam::header::Header;
@@ -6,14 +6,12 @@ use rust_htslib::bam::HeaderView as HeaderViewBam;
 use rust_htslib::bcf::record::GenotypeAllele;
 use rust_htslib::{bam, bam::Read as DUMMY_NAME1};
 use rust_htslib::{bcf, bcf::Read as DUMMY_NAME2};
-use std::collections::BTreeMap;
 use std::fs;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::LineWriter;
 use std::io::Write;
 use std::io::{self, BufRead};
-use std::mem;
 use std::path::Path;
 use std::str;
 
@@ -47,45 +45,25 @@ where
                 //First column is the # of blocks
                 if let Ok(num_blocks) = v[0].parse::<i32>() {
                     //                    println!("{}",num_blocks);
-                    let mut seqs = FxHashMap::default();
-                    let mut quals = FxHashMap::default();
-                    let mut positions = FxHashSet::default();
-                    let mut list_of_positions = Vec::new();
-                    let mut first_position = 1;
-                    let mut last_position = 1;
-
-                    // For each block, read it into a dictionary with corresp. base
+                    let mut new_frag = build_frag(v[1].to_string(), counter);
+                    let mut list_of_alleles = Vec::new();
+
+                    // For each block, read the alleles with their corresp. positions
                     for i in 0..num_blocks {
                         let index = i as usize;
                         let start_pos = v[2 * index + 2].parse::<usize>().unwrap();
-                        if i == 0 {
-                            first_position = start_pos;
-                        }
                         for (j, c) in v[2 * index + 3].chars().enumerate() {
-                            seqs.insert(start_pos + j, c.to_digit(10).unwrap() as usize);
-                            list_of_positions.push(start_pos + j);
-                            positions.insert(start_pos + j);
-                            last_position = start_pos + j
+                            list_of_alleles.push((start_pos + j, c.to_digit(10).unwrap() as usize));
                         }
                     }
 
                     let qual_string = v.last().unwrap().as_bytes();
-                    for (i, key) in list_of_positions.iter().enumerate() {
+                    for (i, (pos, geno)) in list_of_alleles.into_iter().enumerate() {
                         //We usually have a 33 offset for phred qualities. Rust should throw an
                         //error here if this result is negative.
-                        quals.insert(*key, qual_string[i] - 33);
+                        update_frag(&mut new_frag, geno, qual_string[i] - 33, pos);
                     }
 
-                    let new_frag = Frag {
-                        id: v[1].to_string(),
-                        counter_id: counter,
-                        seq_dict: seqs,
-                        qual_dict: quals,
-                        positions: positions,
-                        first_position: first_position,
-                        last_position: last_position,
-                    };
-
                     all_frags.push(new_frag);
                     counter += 1
                 } else {
@@ -180,8 +158,328 @@ pub fn write_blocks_to_file<P>(
     }
 }
 
-//Given a vcf file and a bam file, we get a vector of frags.
-pub fn get_frags_from_bamvcf<P>(vcf_file: P, bam_file: P) -> FxHashMap<String, Vec<Frag>>
+//How alignments are grouped into fragments. By default every read name gives one fragment (this
+//also joins the two mates of a Hi-C/paired-end read pair). Linked reads (10x, TELL-seq) can instead
+//be grouped by their barcode tag, e.g. BX, to get long-range fragments.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FragGroupKey {
+    ReadName,
+    Tag(Vec<u8>),
+}
+
+//Parse the grouping key given on the command line : "qname" or a two character SAM tag.
+pub fn parse_group_key(key: &str) -> Option<FragGroupKey> {
+    if key == "qname" {
+        Some(FragGroupKey::ReadName)
+    } else if key.len() == 2 && key.is_ascii() {
+        Some(FragGroupKey::Tag(key.as_bytes().to_vec()))
+    } else {
+        None
+    }
+}
+
+//Get the name of the group an alignment belongs to. Alignments without the grouping tag
+//fall back to being grouped by read name.
+fn get_group_id(record: &bam::Record, group_key: &FragGroupKey) -> String {
+    let qname = || String::from_utf8(record.qname().to_vec()).unwrap();
+    match group_key {
+        FragGroupKey::ReadName => qname(),
+        FragGroupKey::Tag(tag) => match record.aux(tag) {
+            Some(bam::record::Aux::String(value)) => String::from_utf8_lossy(value).to_string(),
+            Some(bam::record::Aux::Integer(value)) => value.to_string(),
+            Some(bam::record::Aux::Char(value)) => (value as char).to_string(),
+            _ => qname(),
+        },
+    }
+}
+
+//Parse the subgenomes given on the command line as comma separated name:ploidy pairs, e.g.
+//A:2,B:2,D:2 for hexaploid wheat. Names must be distinct and ploidies positive.
+pub fn parse_subgenomes(value: &str) -> Option<Vec<Subgenome>> {
+    let mut subgenomes: Vec<Subgenome> = Vec::new();
+    for field in value.split(',') {
+        let mut name_ploidy = field.trim().splitn(2, ':');
+        let name = name_ploidy.next()?;
+        let ploidy = name_ploidy.next()?.parse::<usize>().ok()?;
+        if name.is_empty() || ploidy == 0 || subgenomes.iter().any(|x| x.name == name) {
+            return None;
+        }
+        subgenomes.push(Subgenome {
+            name: name.to_string(),
+            ploidy,
+        });
+    }
+    Some(subgenomes)
+}
+
+//Read a tab or space separated file of (name) (subgenome) lines, e.g. the subgenome of each
+//contig, or the subgenome of each read from a subgenome classifier. Lines with a subgenome not
+//in subgenomes are an error. Returns the index of the subgenome of each name.
+pub fn get_subgenome_assignments<P>(filename: P, subgenomes: &[Subgenome]) -> FxHashMap<String, usize>
+where
+    P: AsRef<Path>,
+{
+    let lines = match read_lines(filename) {
+        Ok(lines) => lines,
+        Err(_) => panic!("Could not read the subgenome assignment file."),
+    };
+    let mut assignments = FxHashMap::default();
+    for line in lines {
+        let line = line.unwrap();
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() || fields[0].starts_with('#') {
+            continue;
+        }
+        if fields.len() < 2 {
+            panic!("Subgenome assignment line {} must be (name) (subgenome).", line);
+        }
+        match subgenomes.iter().position(|x| x.name == fields[1]) {
+            Some(index) => {
+                assignments.insert(fields[0].to_string(), index);
+            }
+            None => panic!("Subgenome {} is not one of the subgenomes given by --subgenomes.", fields[1]),
+        }
+    }
+    assignments
+}
+
+//Default largest distance in bases between consecutive alignments of a barcode that still come
+//from the same molecule. Linked-read molecules are rarely longer than 100kb and reads along
+//them are dense, so larger gaps usually mean the barcode was reused for another molecule.
+pub const DEFAULT_MAX_GROUP_GAP: i64 = 20000;
+
+//One alignment of a group, as [ref_start, ref_end) on the reference.
+pub struct GroupedAlignment {
+    pub ref_start: i64,
+    pub ref_end: i64,
+    pub frag: Frag,
+}
+
+//Merge the alignments of a contig into one fragment per group, the id of each alignment's
+//fragment being its group. When max_group_gap is given, alignments of a group are sorted along
+//the contig and the group is split into molecules wherever the next alignment starts more than
+//max_group_gap bases after the end of the previous ones. Split molecules get the ids
+//group_mol0, group_mol1, ...
+pub fn merge_grouped_alignments(
+    alignments: Vec<GroupedAlignment>,
+    max_group_gap: Option<i64>,
+) -> Vec<Frag> {
+    let mut group_to_alignments: FxHashMap<String, Vec<GroupedAlignment>> = FxHashMap::default();
+    for alignment in alignments.into_iter() {
+        group_to_alignments
+            .entry(alignment.frag.id.clone())
+            .or_default()
+            .push(alignment);
+    }
+
+    let mut frags = Vec::new();
+    for (group_id, mut group_alignments) in group_to_alignments.into_iter() {
+        group_alignments.sort_by_key(|x| x.ref_start);
+        let mut molecules: Vec<Frag> = Vec::new();
+        let mut molecule_end = i64::MIN;
+        for alignment in group_alignments.into_iter() {
+            let new_molecule = match (molecules.last(), max_group_gap) {
+                (None, _) => true,
+                (Some(_), Some(max_gap)) => alignment.ref_start - molecule_end > max_gap,
+                (Some(_), None) => false,
+            };
+            if new_molecule {
+                molecules.push(alignment.frag);
+                molecule_end = alignment.ref_end;
+            } else {
+                merge_frags(molecules.last_mut().unwrap(), &alignment.frag);
+                molecule_end = molecule_end.max(alignment.ref_end);
+            }
+        }
+        if molecules.len() > 1 {
+            for (i, molecule) in molecules.iter_mut().enumerate() {
+                molecule.id = format!("{}_mol{}", group_id, i);
+            }
+        }
+        frags.append(&mut molecules);
+    }
+    frags
+}
+
+const REVERSE_MASK: u16 = 16;
+const MATE_MASK: u16 = 64 | 128;
+const SUPPLEMENTARY_MASK: u16 = 2048;
+
+//One alignment of a read. Reads with split alignments (a primary and supplementary alignments)
+//have several segments which we check for consistency before merging them into one fragment.
+//primary_elsewhere is set for supplementary alignments whose primary alignment is on another
+//contig. read_id tells the mates of a pair apart while read_name is the read's name in the bam.
+pub struct AlignedSegment {
+    pub read_id: String,
+    pub read_name: String,
+    pub ref_start: i64,
+    pub ref_end: i64,
+    pub reverse: bool,
+    pub supplementary: bool,
+    pub primary_elsewhere: bool,
+    pub frag: Frag,
+}
+
+//Number of reads with split alignments, how many of them were stitched back into one fragment
+//and how many were broken into separate fragments because their segments were inconsistent.
+#[derive(Default, Debug)]
+pub struct SplitAlignmentStats {
+    pub reads_with_splits: usize,
+    pub reads_stitched: usize,
+    pub reads_split: usize,
+}
+
+//Mates of a read pair share a name but are different reads.
+fn get_read_id(record: &bam::Record, flags: u16) -> String {
+    format!(
+        "{}/{}",
+        String::from_utf8_lossy(record.qname()),
+        flags & MATE_MASK
+    )
+}
+
+//Add the alleles of one fragment to another.
+fn merge_frags(frag: &mut Frag, other: &Frag) {
+    for (i, (pos, geno)) in other.alleles().enumerate() {
+        update_frag(frag, geno, other.quals[i], pos);
+    }
+    for read_name in other.read_names.iter() {
+        if !frag.read_names.contains(read_name) {
+            frag.read_names.push(read_name.clone());
+        }
+    }
+}
+
+//Whether the primary alignment of a supplementary alignment is on another contig. The first
+//alignment of the SA tag of a supplementary alignment is its primary alignment.
+fn is_primary_elsewhere(record: &bam::Record, ref_chrom: &[u8]) -> bool {
+    match record.aux(b"SA") {
+        Some(bam::record::Aux::String(value)) => match value.split(|x| *x == b',').next() {
+            Some(primary_chrom) => primary_chrom != ref_chrom,
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+//Two consecutive segments of a read are consistent if they are on the same strand, close on
+//the reference and do not disagree on any SNP they both cover. Chimeric reads mapping to two
+//distant loci or with inverted pieces are not.
+pub fn segments_consistent(prev: &AlignedSegment, next: &AlignedSegment, max_split_gap: i64) -> bool {
+    if prev.reverse != next.reverse {
+        return false;
+    }
+    if next.ref_start - prev.ref_end > max_split_gap {
+        return false;
+    }
+    let (_same, diff) = utils_frags::distance(&prev.frag, &next.frag);
+    diff == 0
+}
+
+//Turn the alignment segments of a contig into fragments. The segments of each read are sorted
+//along the reference and chained while consecutive segments are consistent. The chain containing
+//the primary alignment is merged into the read's group (see merge_grouped_alignments); other
+//chains become fragments of their own, named after the group and the read : group_read_split1, or
+//read_split1 when grouping by read name. Supplementary alignments whose primary alignment is on
+//another contig are chimeric pieces, so all their chains become fragments of their own.
+pub fn stitch_split_alignments(
+    segments: Vec<AlignedSegment>,
+    max_group_gap: Option<i64>,
+    max_split_gap: i64,
+    stats: &mut SplitAlignmentStats,
+) -> Vec<Frag> {
+    let mut read_to_segments: FxHashMap<String, Vec<AlignedSegment>> = FxHashMap::default();
+    for segment in segments.into_iter() {
+        read_to_segments
+            .entry(segment.read_id.clone())
+            .or_default()
+            .push(segment);
+    }
+
+    let mut primary_alignments = Vec::new();
+    let mut split_frags = Vec::new();
+
+    for (_read_id, mut read_segments) in read_to_segments.into_iter() {
+        read_segments.sort_by_key(|x| x.ref_start);
+        let mut chains: Vec<Vec<AlignedSegment>> = Vec::new();
+        for segment in read_segments.into_iter() {
+            let extend = match chains.last() {
+                Some(chain) => segments_consistent(chain.last().unwrap(), &segment, max_split_gap),
+                None => false,
+            };
+            if extend {
+                chains.last_mut().unwrap().push(segment);
+            } else {
+                chains.push(vec![segment]);
+            }
+        }
+
+        let cross_contig = chains.iter().flatten().any(|x| x.primary_elsewhere);
+        let num_segments: usize = chains.iter().map(|x| x.len()).sum();
+        if num_segments > 1 || cross_contig {
+            stats.reads_with_splits += 1;
+            if chains.len() == 1 && !cross_contig {
+                stats.reads_stitched += 1;
+            } else {
+                stats.reads_split += 1;
+            }
+        }
+
+        //Without a primary alignment on this contig (e.g. it covers no SNPs) the first chain
+        //stands in for it, unless the primary alignment is on another contig.
+        let primary_chain = match chains
+            .iter()
+            .position(|chain| chain.iter().any(|x| !x.supplementary))
+        {
+            Some(i) => Some(i),
+            None if cross_contig => None,
+            None => Some(0),
+        };
+
+        for (i, chain) in chains.into_iter().enumerate() {
+            let ref_start = chain[0].ref_start;
+            let chain_read_name = chain[0].read_name.clone();
+            let ref_end = chain.iter().map(|x| x.ref_end).max().unwrap();
+            let mut chain = chain.into_iter();
+            let mut frag = chain.next().unwrap().frag;
+            for segment in chain {
+                merge_frags(&mut frag, &segment.frag);
+            }
+            if Some(i) == primary_chain {
+                primary_alignments.push(GroupedAlignment {
+                    ref_start,
+                    ref_end,
+                    frag,
+                });
+            } else {
+                frag.id = if frag.id == chain_read_name {
+                    format!("{}_split{}", chain_read_name, i)
+                } else {
+                    format!("{}_{}_split{}", frag.id, chain_read_name, i)
+                };
+                split_frags.push(frag);
+            }
+        }
+    }
+
+    let mut frags = merge_grouped_alignments(primary_alignments, max_group_gap);
+    frags.append(&mut split_frags);
+    frags
+}
+
+//Given a vcf file and a bam file, we get a vector of frags. Alignments are merged into
+//fragments according to group_key. Alignments on different contigs are never merged. When
+//grouping by a tag, a group is also split where consecutive alignments are more than
+//max_group_gap bases apart; read names (e.g. Hi-C pairs) are never split. Split alignments of
+//a read further apart than max_split_gap bases, or otherwise inconsistent, become separate
+//fragments.
+pub fn get_frags_from_bamvcf<P>(
+    vcf_file: P,
+    bam_file: P,
+    group_key: &FragGroupKey,
+    max_group_gap: i64,
+    max_split_gap: i64,
+) -> FxHashMap<String, Vec<Frag>>
 where
     P: AsRef<Path>,
 {
@@ -272,8 +570,10 @@ where
 
     //This may be important : We assume that distinct reads have different names. I can see this
     //being a problem in some weird bad cases, so be careful.
-    let mut ref_id_to_frag = FxHashMap::default();
-    let mut counter_id = 0;
+    //
+    //Every alignment (primary or supplementary) of a read gets its own segment first so that split
+    //alignments can be checked before they are merged into fragments.
+    let mut ref_id_to_segments = FxHashMap::default();
 
     //Scan the pileup table for every position on the genome which contains a SNP to get the aligned reads corresponding to the SNP. TODO : There should be a way to index into the bam.pileup() object so we don't have to iterate through positions which we already know are not SNPs.
     for p in bam.pileup() {
@@ -304,11 +604,10 @@ where
                 let flags = aln_record.flags();
                 let errors_mask = 1796;
                 let secondary_mask = 256;
-                let id_to_frag = ref_id_to_frag
+                let id_to_segment = ref_id_to_segments
                     .entry(ref_chrom)
                     .or_insert(FxHashMap::default());
 
-                let id_string = String::from_utf8(aln_record.qname().to_vec()).unwrap();
                 //Erroneous alignment, skip
                 if flags & errors_mask > 0 {
                     //dbg!(&flags,&id_string);
@@ -321,13 +620,9 @@ where
                     continue;
                 }
 
-                let id_string2 = id_string.clone();
-
-                if !id_to_frag.contains_key(&id_string) {
-                    counter_id += 1;
-                }
-
-                let frag_to_ins = build_frag(id_string, counter_id);
+                let read_id = get_read_id(&aln_record, flags);
+                let reverse = flags & REVERSE_MASK > 0;
+                let segment_key = (read_id.clone(), aln_record.pos(), reverse);
 
                 let readbase = alignment.record().seq()[alignment.qpos().unwrap()];
                 let qualbase = alignment.record().qual()[alignment.qpos().unwrap()];
@@ -342,8 +637,23 @@ where
                 {
                     //Only build the frag if the base is one of the SNP alleles.
                     if readbase == *allele {
-                        let mut frag = id_to_frag.entry(id_string2).or_insert(frag_to_ins);
-                        update_frag(&mut frag, i, qualbase, *snp_id);
+                        let segment = id_to_segment.entry(segment_key).or_insert_with(|| {
+                            let read_name = String::from_utf8_lossy(aln_record.qname()).to_string();
+                            let mut frag = build_frag(get_group_id(&aln_record, group_key), 0);
+                            frag.read_names = vec![read_name.clone()];
+                            AlignedSegment {
+                                read_id,
+                                read_name,
+                                ref_start: aln_record.pos(),
+                                ref_end: aln_record.cigar().end_pos(),
+                                reverse,
+                                supplementary: flags & SUPPLEMENTARY_MASK > 0,
+                                primary_elsewhere: flags & SUPPLEMENTARY_MASK > 0
+                                    && is_primary_elsewhere(&aln_record, ref_chrom),
+                                frag,
+                            }
+                        });
+                        update_frag(&mut segment.frag, i, qualbase, *snp_id);
                         break;
                     }
                 }
@@ -351,28 +661,69 @@ where
         }
     }
 
+    let max_group_gap = match group_key {
+        FragGroupKey::ReadName => None,
+        FragGroupKey::Tag(_) => Some(max_group_gap),
+    };
     let mut ref_vec_frags = FxHashMap::default();
-    let mut keys = FxHashSet::default();
-    for ref_chrom in ref_id_to_frag.keys() {
-        ref_vec_frags.insert(String::from_utf8(ref_chrom.to_vec()).unwrap(), Vec::new());
-        keys.insert(ref_chrom.clone());
-    }
-    for ref_chrom in keys {
-        let vec_frags = ref_vec_frags
-            .get_mut(&String::from_utf8(ref_chrom.to_vec()).unwrap())
-            .unwrap();
-        let id_to_frag = ref_id_to_frag.get_mut(ref_chrom).unwrap();
-        let id_to_frag = mem::replace(id_to_frag, FxHashMap::default());
-        for (_id, frag) in id_to_frag.into_iter() {
+    let mut split_stats = SplitAlignmentStats::default();
+    let mut counter_id = 0;
+    for (ref_chrom, id_to_segment) in ref_id_to_segments.into_iter() {
+        let segments = id_to_segment.into_iter().map(|x| x.1).collect();
+        let frags = stitch_split_alignments(segments, max_group_gap, max_split_gap, &mut split_stats);
+        let mut vec_frags = Vec::new();
+        for mut frag in frags.into_iter() {
             if frag.positions.len() > 1 {
+                counter_id += 1;
+                frag.counter_id = counter_id;
                 vec_frags.push(frag);
             }
         }
+        ref_vec_frags.insert(String::from_utf8(ref_chrom.to_vec()).unwrap(), vec_frags);
     }
 
+    println!(
+        "{} reads have split/supplementary alignments : {} stitched into one fragment, {} split into separate fragments.",
+        split_stats.reads_with_splits, split_stats.reads_stitched, split_stats.reads_split
+    );
+
     ref_vec_frags
 }
 
+//Get fragments from several bam files, e.g. from different sequencing technologies, and merge
+//them per contig. Every fragment remembers which bam it came from and gets the error rate of
+//that bam if one is given. Counter ids are reassigned so they stay unique after merging.
+pub fn get_frags_from_multiple_bams<P>(
+    vcf_file: P,
+    bam_files: &[P],
+    bam_epsilons: &[Option<f64>],
+    group_key: &FragGroupKey,
+    max_group_gap: i64,
+    max_split_gap: i64,
+) -> FxHashMap<String, Vec<Frag>>
+where
+    P: AsRef<Path> + Copy,
+{
+    let mut merged_frags_map: FxHashMap<String, Vec<Frag>> = FxHashMap::default();
+    let mut counter_id = 0;
+    for (source, bam_file) in bam_files.iter().enumerate() {
+        let frags_map =
+            get_frags_from_bamvcf(vcf_file, *bam_file, group_key, max_group_gap, max_split_gap);
+        for (contig, frags) in frags_map.into_iter() {
+            let merged_frags = merged_frags_map.entry(contig).or_default();
+            for mut frag in frags.into_iter() {
+                counter_id += 1;
+                frag.counter_id = counter_id;
+                frag.source = source;
+                frag.epsilon = bam_epsilons[source];
+                merged_frags.push(frag);
+            }
+        }
+    }
+
+    merged_frags_map
+}
+
 //Read a vcf file to get the genotypes. We read genotypes into a dictionary of keypairs where the
 //keys are positions, and the values are dictionaries which encode the genotypes. E.g. the genotype
 //1 1 0 0 at position 5 would be (5,{1 : 2, 0 : 2}).
@@ -479,37 +830,158 @@ where
     (map_positions_vec, map_genotype_dict, vcf_ploidy)
 }
 
-//Convert a fragment which stores sequences in a dictionary format to a block format which makes
-//writing to frag files easier.
+//Genotypes of the SNPs of each contig : SNP -> allele -> number of copies.
+pub type ContigGenotypes = FxHashMap<String, FxHashMap<usize, FxHashMap<usize, usize>>>;
+
+//Read the genotypes of a parent from its VCF and key them by the SNPs of the offspring, given the
+//genome position of every SNP of the offspring on each contig. SNPs are matched by contig and
+//genome position, so the alleles must be coded the same way in both VCFs (e.g. both split from a
+//joint VCF). Returns the genotypes of each contig, as in get_genotypes_from_vcf_hts, and the
+//ploidy of the parent.
+pub fn get_parent_genotypes<P>(
+    parent_vcf: P,
+    snp_to_genome_pos_map: &FxHashMap<String, Vec<usize>>,
+) -> (ContigGenotypes, usize)
+where
+    P: AsRef<Path>,
+{
+    let (parent_snp_to_genome_pos_map, parent_genotype_dict_map, parent_ploidy) =
+        get_genotypes_from_vcf_hts(parent_vcf);
+    let mut genotype_dict_map = FxHashMap::default();
+    for (contig, snp_to_genome_pos) in snp_to_genome_pos_map.iter() {
+        let (parent_snp_to_genome_pos, parent_genotype_dict) = match (
+            parent_snp_to_genome_pos_map.get(contig),
+            parent_genotype_dict_map.get(contig),
+        ) {
+            (Some(positions), Some(genotypes)) => (positions, genotypes),
+            _ => continue,
+        };
+        let genome_pos_to_snp: FxHashMap<usize, usize> = snp_to_genome_pos
+            .iter()
+            .enumerate()
+            .map(|(i, genome_pos)| (*genome_pos, i + 1))
+            .collect();
+        let mut genotype_dict = FxHashMap::default();
+        for (i, genome_pos) in parent_snp_to_genome_pos.iter().enumerate() {
+            if let (Some(snp), Some(genotype)) =
+                (genome_pos_to_snp.get(genome_pos), parent_genotype_dict.get(&(i + 1)))
+            {
+                genotype_dict.insert(*snp, genotype.clone());
+            }
+        }
+        genotype_dict_map.insert(contig.clone(), genotype_dict);
+    }
+    (genotype_dict_map, parent_ploidy)
+}
+
+//Read the haplotypes of a phased reference panel VCF, e.g. of related cultivars, keyed by the SNPs
+//of the sample being phased as in get_parent_genotypes. Every sample of the panel gives one
+//haplotype per allele of its phased genotypes. A sample is only used at a SNP if every allele after
+//the first is phased, since the alleles of an unphased genotype can't be put on haplotypes.
+//Returns the haplotypes of each contig as maps from SNP to allele; missing alleles are left out.
+pub fn get_panel_haplotypes<P>(
+    panel_vcf: P,
+    snp_to_genome_pos_map: &FxHashMap<String, Vec<usize>>,
+) -> FxHashMap<String, Vec<FxHashMap<usize, usize>>>
+where
+    P: AsRef<Path>,
+{
+    let mut vcf = match bcf::Reader::from_path(panel_vcf) {
+        Ok(vcf) => vcf,
+        Err(_) => panic!("rust_htslib had an error while reading the panel VCF file. Exiting."),
+    };
+    let header = vcf.header().clone();
+    let num_samples = header.sample_count() as usize;
+    let genome_pos_to_snp_map: FxHashMap<&String, FxHashMap<usize, usize>> = snp_to_genome_pos_map
+        .iter()
+        .map(|(contig, snp_to_genome_pos)| {
+            let genome_pos_to_snp = snp_to_genome_pos
+                .iter()
+                .enumerate()
+                .map(|(i, genome_pos)| (*genome_pos, i + 1))
+                .collect();
+            (contig, genome_pos_to_snp)
+        })
+        .collect();
+
+    //Haplotypes of each contig, indexed by sample and allele of the sample's genotype.
+    let mut contig_haplotypes: FxHashMap<String, Vec<Vec<FxHashMap<usize, usize>>>> = FxHashMap::default();
+    for rec in vcf.records() {
+        let mut unr = rec.unwrap();
+        if unr.alleles().iter().any(|allele| allele.len() > 1) {
+            continue;
+        }
+        let contig = String::from_utf8(header.rid2name(unr.rid().unwrap()).unwrap().to_vec()).unwrap();
+        //+1 because htslib is 0 index by default
+        let snp = match genome_pos_to_snp_map
+            .get(&contig)
+            .and_then(|genome_pos_to_snp| genome_pos_to_snp.get(&(unr.pos() as usize + 1)))
+        {
+            Some(snp) => *snp,
+            None => continue,
+        };
+        let genotypes = match unr.genotypes() {
+            Ok(genotypes) => genotypes,
+            Err(_) => continue,
+        };
+        let haplotypes = contig_haplotypes
+            .entry(contig)
+            .or_insert_with(|| vec![Vec::new(); num_samples]);
+        for (sample, sample_haplotypes) in haplotypes.iter_mut().enumerate() {
+            let genotype = genotypes.get(sample);
+            if !genotype
+                .iter()
+                .skip(1)
+                .all(|allele| matches!(allele, GenotypeAllele::Phased(_)))
+            {
+                continue;
+            }
+            for (k, allele) in genotype.iter().enumerate() {
+                if sample_haplotypes.len() <= k {
+                    sample_haplotypes.push(FxHashMap::default());
+                }
+                match allele {
+                    GenotypeAllele::Unphased(x) | GenotypeAllele::Phased(x) => {
+                        sample_haplotypes[k].insert(snp, *x as usize);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    contig_haplotypes
+        .into_iter()
+        .map(|(contig, haplotypes)| (contig, haplotypes.into_iter().flatten().collect()))
+        .collect()
+}
+
+//Convert a fragment to a block format of consecutive alleles which makes writing to frag files
+//easier.
 fn convert_dict_to_block(frag: Frag) -> (Vec<usize>, Vec<Vec<usize>>, Vec<u8>) {
-    let d = frag.seq_dict;
-    let vec_d: BTreeMap<usize, usize> = d.into_iter().collect();
-    let vec_q: BTreeMap<usize, u8> = frag.qual_dict.into_iter().collect();
     let mut prev_pos = 0;
     let mut block_start_pos = Vec::new();
     let mut blocks = Vec::new();
     let mut block = Vec::new();
     let mut qual_block = Vec::new();
 
-    for (pos, var) in &vec_d {
+    for (pos, var) in frag.alleles() {
         if prev_pos == 0 {
-            prev_pos = *pos;
-            block.push(*var);
-            block_start_pos.push(*pos);
+            prev_pos = pos;
+            block.push(var);
+            block_start_pos.push(pos);
         } else if pos - prev_pos > 1 {
             blocks.push(block);
-            block = vec![*var];
-            block_start_pos.push(*pos);
-            prev_pos = *pos;
+            block = vec![var];
+            block_start_pos.push(pos);
+            prev_pos = pos;
         } else if pos - prev_pos == 1 {
-            block.push(*var);
-            prev_pos = *pos;
+            block.push(var);
+            prev_pos = pos;
         }
     }
 
-    for (_pos, q) in &vec_q {
-        qual_block.push(*q);
-    }
+    qual_block.extend_from_slice(&frag.quals);
 
     blocks.push(block);
     (block_start_pos, blocks, qual_block)
@@ -546,8 +1018,16 @@ pub fn write_frags_file(frags: Vec<Frag>, filename: String) {
     }
 }
 
+//Write the read partition, one haplotype after another and then the unassigned reads, with one
+//read per line as (name) (first SNP) (last SNP) (error rate of the read against its haplotype
+//after local clustering, see local_clustering::get_read_error_stats) (log likelihood margin
+//between the read's best and second best haplotype, see vcf_polishing::get_assignment_margins).
+//Error rates and margins which were not computed are NA.
 pub fn write_output_partition_to_file<P>(
     part: &Vec<FxHashSet<&Frag>>,
+    unassigned: &[&Frag],
+    read_stats: &FxHashMap<&Frag, (usize, usize)>,
+    margins: &FxHashMap<&Frag, f64>,
     out_bam_part_dir: P,
     contig: &String,
 ) where
@@ -560,19 +1040,224 @@ pub fn write_output_partition_to_file<P>(
     let file = File::create(contig_path).expect("Can't create file");
     let mut file = LineWriter::new(file);
 
+    let read_line = |frag: &Frag| {
+        let error_rate = match read_stats.get(frag) {
+            Some((good, bad)) => format!("{}", *bad as f64 / (good + bad) as f64),
+            None => "NA".to_string(),
+        };
+        let margin = match margins.get(frag) {
+            Some(margin) => format!("{}", margin),
+            None => "NA".to_string(),
+        };
+        format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            frag.id, frag.first_position, frag.last_position, error_rate, margin
+        )
+    };
+
     for (i, set) in part.iter().enumerate() {
         let mut vec_part: Vec<&&Frag> = set.into_iter().collect();
         vec_part.sort_by(|a, b| a.first_position.cmp(&b.first_position));
         write!(file, "#{}\n", i).unwrap();
         for frag in vec_part{
-            write!(
+            file.write_all(read_line(frag).as_bytes()).unwrap();
+        }
+    }
+    writeln!(file, "#unassigned").unwrap();
+    for frag in unassigned.iter() {
+        file.write_all(read_line(frag).as_bytes()).unwrap();
+    }
+}
+
+//Write the local blocks which were clustered and then linked, one block per line as
+//(first SNP) (last SNP) (first genome position) (last genome position).
+pub fn write_local_blocks_to_file<P>(
+    blocks: &[LocalBlock],
+    snp_to_genome: &[usize],
+    out_bam_part_dir: P,
+    contig: &String,
+) where
+    P: AsRef<Path>,
+{
+    fs::create_dir_all(&out_bam_part_dir).unwrap();
+    let contig_path = out_bam_part_dir
+        .as_ref()
+        .join(format!("{}_blocks.txt", contig));
+    let file = File::create(contig_path).expect("Can't create file");
+    let mut file = LineWriter::new(file);
+
+    for block in blocks.iter() {
+        if snp_to_genome.is_empty() {
+            writeln!(file, "{}\t{}\tNA\tNA", block.start, block.end).unwrap();
+        } else {
+            writeln!(
                 file,
-                "{}\t{}\t{}\n",
-                frag.id.clone(),
-                frag.first_position,
-                frag.last_position
+                "{}\t{}\t{}\t{}",
+                block.start,
+                block.end,
+                snp_to_genome[block.start - 1],
+                snp_to_genome[block.end - 1]
             )
             .unwrap();
         }
     }
 }
+
+//Write the switch errors of a contig (see vcf_polishing::find_switch_errors), one per line : the
+//contig, the first SNP after the switch and its genome position, the two haplotypes (numbered
+//from 0 as in the partition output), the number of reads supporting the switch and the decrease
+//in errors from swapping the haplotypes there.
+pub fn write_switch_errors_to_file<P>(
+    filename: P,
+    switch_errors: &[SwitchError],
+    snp_to_genome: &[usize],
+    first_iter: bool,
+    contig: &String,
+) where
+    P: AsRef<Path>,
+{
+    let file = if first_iter {
+        File::create(filename).expect("Can't create file")
+    } else {
+        OpenOptions::new().append(true).open(filename).unwrap()
+    };
+    let mut file = LineWriter::new(file);
+
+    for switch_error in switch_errors.iter() {
+        let genome_pos = if snp_to_genome.is_empty() {
+            "NA".to_string()
+        } else {
+            snp_to_genome[switch_error.position - 1].to_string()
+        };
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            contig,
+            switch_error.position,
+            genome_pos,
+            switch_error.haps.0,
+            switch_error.haps.1,
+            switch_error.num_reads,
+            switch_error.gain
+        )
+        .unwrap();
+    }
+}
+
+//Write the strains of a contig found in metagenomic mode, one per line as (contig) (strain,
+//numbered from 0 as in the -o and -P outputs) (number of reads) (relative abundance, the fraction
+//of the reads of the contig on the strain).
+pub fn write_strain_abundances_to_file<P>(
+    filename: P,
+    part: &[FxHashSet<&Frag>],
+    first_iter: bool,
+    contig: &String,
+) where
+    P: AsRef<Path>,
+{
+    let file = if first_iter {
+        File::create(filename).expect("Can't create file")
+    } else {
+        OpenOptions::new().append(true).open(filename).unwrap()
+    };
+    let mut file = LineWriter::new(file);
+
+    let num_reads: usize = part.iter().map(|reads| reads.len()).sum();
+    for (i, reads) in part.iter().enumerate() {
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{}",
+            contig,
+            i,
+            reads.len(),
+            reads.len() as f64 / num_reads as f64
+        )
+        .unwrap();
+    }
+}
+
+//Write the haplotype probabilities of the reads of a contig (see
+//vcf_polishing::get_assignment_probabilities), one read per line as (read) (contig) (probability
+//of haplotype 0) ... (probability of haplotype ploidy - 1), sorted by first SNP. Probabilities are
+//in scientific notation since most of them are tiny. The first contig starts the file with a
+//header line.
+pub fn write_read_probabilities_to_file<P>(
+    filename: P,
+    probabilities: &FxHashMap<&Frag, Vec<f64>>,
+    ploidy: usize,
+    first_iter: bool,
+    contig: &String,
+) where
+    P: AsRef<Path>,
+{
+    let file = if first_iter {
+        File::create(filename).expect("Can't create file")
+    } else {
+        OpenOptions::new().append(true).open(filename).unwrap()
+    };
+    let mut file = LineWriter::new(file);
+
+    if first_iter {
+        let columns: Vec<String> = (0..ploidy).map(|i| format!("p_{}", i)).collect();
+        writeln!(file, "#read\tcontig\t{}", columns.join("\t")).unwrap();
+    }
+
+    let mut reads: Vec<&&Frag> = probabilities.keys().collect();
+    reads.sort_by_key(|read| (read.first_position, read.counter_id));
+    for read in reads {
+        let values: Vec<String> = probabilities[*read].iter().map(|p| format!("{:e}", p)).collect();
+        writeln!(file, "{}\t{}\t{}", read.id, contig, values.join("\t")).unwrap();
+    }
+}
+
+//Write the epsilon estimate of a contig : a line "#(contig) (epsilon) (lower) (upper) (read error
+//rate)" with the 95% confidence interval of epsilon, then one line per sampled block with the contig, the first and last
+//SNP and their genome positions, the error rate of the block, the number of alleles compared and
+//the fraction of them on haplotypes with a low error rate.
+pub fn write_epsilon_estimate_to_file<P>(
+    filename: P,
+    estimate: &EpsilonEstimate,
+    snp_to_genome: &[usize],
+    first_iter: bool,
+    contig: &String,
+) where
+    P: AsRef<Path>,
+{
+    let file = if first_iter {
+        File::create(filename).expect("Can't create file")
+    } else {
+        OpenOptions::new().append(true).open(filename).unwrap()
+    };
+    let mut file = LineWriter::new(file);
+
+    writeln!(
+        file,
+        "#{}\t{}\t{}\t{}\t{}",
+        contig, estimate.epsilon, estimate.lower, estimate.upper, estimate.read_epsilon
+    )
+    .unwrap();
+    for block_estimate in estimate.block_estimates.iter() {
+        let block = block_estimate.block;
+        let (genome_start, genome_end) = if snp_to_genome.is_empty() {
+            ("NA".to_string(), "NA".to_string())
+        } else {
+            (
+                snp_to_genome[block.start - 1].to_string(),
+                snp_to_genome[block.end - 1].to_string(),
+            )
+        };
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+            contig,
+            block.start,
+            block.end,
+            genome_start,
+            genome_end,
+            block_estimate.epsilon,
+            block_estimate.num_bases,
+            block_estimate.clean_fraction
+        )
+        .unwrap();
+    }
+}
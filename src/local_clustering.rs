@@ -1,4 +1,6 @@
-use crate::types_structs::{Frag, HapBlock};
+use crate::types_structs::{BlockEpsilon, EpsilonEstimate, Frag, FragIndex, HapBlock, LocalBlock, MaxFragSpan, ParentGenotypes};
+use rayon::prelude::*;
+use std::mem;
 use crate::vcf_polishing;
 use rand::prelude::*;
 use rand_pcg::Pcg64;
@@ -24,12 +26,14 @@ macro_rules! debug {
 //use std::time::Instant;
 
 //Return the set of reads for which every read covers at least one position in the interval
-//(inclusive)
+//(inclusive). Outlier fragments (e.g. weird mappings) spanning more than max_frag_span are
+//ignored; see utils_frags::get_max_frag_span.
 pub fn find_reads_in_interval<'a>(
     start: usize,
     end: usize,
     //position_to_reads : &FxHashMap<usize,FxHashSet<&Frag>>,
     all_frags: &'a Vec<Frag>,
+    max_frag_span: MaxFragSpan,
 ) -> FxHashSet<&'a Frag> {
     let mut final_set = FxHashSet::default();
     //Original method of doing this : This is slower than just iterating thorugh the entire fragment list. We can speed this up by
@@ -52,12 +56,8 @@ pub fn find_reads_in_interval<'a>(
             break;
         }
 
-        //Currently we use 1/3 quantile as the length of the block, i.e.
-        //end-start. If a mapping is weird and the fragment
-        //spans several regions, we ignore the fragment. TODO make this an adjustable parameter. 
-        let max_read_length = 150000;
-        let len_of_snp = 45;
-        if frag.last_position - frag.first_position > (max_read_length/len_of_snp as usize){
+        //If a mapping is weird and the fragment spans several regions, we ignore the fragment.
+        if max_frag_span.is_outlier(frag) {
             continue;
         }
 
@@ -72,16 +72,45 @@ pub fn generate_hap_block<'a>(
     end: usize,
     //position_to_reads : &'a FxHashMap<usize,FxHashSet<&Frag>>,
     ploidy: usize,
-    all_frags: &'a Vec<Frag>,
+    frag_index: &FragIndex<'a>,
     epsilon: f64,
+    init: ClusterInit,
 ) -> Vec<FxHashSet<&'a Frag>> {
     //debug!(start);
     //debug!(end);
-    let all_reads = find_reads_in_interval(start, end, all_frags);
-    let partition = cluster_reads(&all_reads, ploidy, epsilon);
+    let all_reads = frag_index.reads_in_interval(start, end);
+    let partition = cluster_reads(&all_reads, ploidy, epsilon, init);
     partition
 }
 
+//Move a random fraction of the reads of a partition to random haplotypes, so that the
+//optimization of a local block can be restarted from a different clustering. The random numbers
+//come from stream `stream` of the generator seeded by `seed`.
+pub fn perturb_partition<'a>(
+    partition: &[FxHashSet<&'a Frag>],
+    fraction: f64,
+    seed: u64,
+    stream: u64,
+) -> Vec<FxHashSet<&'a Frag>> {
+    let mut rng = Pcg64::new(seed as u128, stream as u128);
+    let mut reads: Vec<(&'a Frag, usize)> = partition
+        .iter()
+        .enumerate()
+        .flat_map(|(i, cluster)| cluster.iter().map(move |read| (*read, i)))
+        .collect();
+    reads.sort_by_key(|(read, i)| (read.counter_id, *i));
+
+    let mut perturbed = vec![FxHashSet::default(); partition.len()];
+    for (read, i) in reads {
+        if rng.gen::<f64>() < fraction {
+            perturbed[rng.gen_range(0, partition.len())].insert(read);
+        } else {
+            perturbed[i].insert(read);
+        }
+    }
+    perturbed
+}
+
 //Compute distance between two reads from the precomputed hash map which can be useful for speeding
 //up. We don't end up using this
 //method right now because there is some weirdness with hashmaps being slow, I think I fixed this
@@ -106,18 +135,45 @@ pub fn dist_from_graph(
     }
 }
 
+//How the reads of a local block are split into the initial clusters which optimize_clustering
+//starts from : seeded by a greedy max-distance k-clique of the read graph (clique_clusters), by
+//k-medoids on the binomial distances between reads (kmedoids_clusters), or by spectral clustering
+//of the read graph (spectral_clusters).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClusterInit {
+    Clique,
+    KMedoids,
+    Spectral,
+}
+
+//Parse the initial clustering given on the command line : clique, kmedoids or spectral.
+pub fn parse_cluster_init(init: &str) -> Option<ClusterInit> {
+    match init {
+        "clique" => Some(ClusterInit::Clique),
+        "kmedoids" => Some(ClusterInit::KMedoids),
+        "spectral" => Some(ClusterInit::Spectral),
+        _ => None,
+    }
+}
+
 //Local clustering method for a set of reads -> partition.
-//Given a set of reads covering an interval, we greedily find a max k-clique and break this
-//k-clique up iinto k different clusters. iteratively add reads to the best k-clique where the max
-//of the intracluster distances is minimized.
-//Importantly, the order in which we itertively add reads is sorted by the minimum of the maximum
-//overlap of the read within the clusters.
+//We build the read graph, where overlapping reads are joined by an edge weighted by the binomial
+//distance between them, and split it into ploidy initial clusters with the given method (see
+//ClusterInit). optimize_clustering then improves the clusters.
 pub fn cluster_reads<'a>(
     all_reads: &FxHashSet<&'a Frag>,
     ploidy: usize,
     epsilon: f64,
+    init: ClusterInit,
 ) -> Vec<FxHashSet<&'a Frag>> {
+    //A single haplotype (e.g. one strain in metagenomic mode) gets all the reads.
+    if ploidy == 1 {
+        return vec![all_reads.clone()];
+    }
+
     let use_binomial_dist = true;
+    //The binomial distances are scaled down by this factor.
+    let div_factor = 100.0;
 
     //Get the largest distance edge
     let mut vec_all_edges = Vec::new();
@@ -161,8 +217,8 @@ pub fn cluster_reads<'a>(
                     * stable_binom_cdf_p_rev(
                         (same + mec_dist) as usize,
                         mec_dist as usize,
-                        2.0 * epsilon * (1.0 - epsilon),
-                        100.0,
+                        utils_frags::get_pair_epsilon(r1, r2, epsilon),
+                        div_factor,
                     );
             } else {
                 dist = mec_dist as f64;
@@ -188,6 +244,37 @@ pub fn cluster_reads<'a>(
         return clusters;
     }
 
+    let clusters = match init {
+        ClusterInit::Clique => clique_clusters(&mut vec_all_edges, &vec_all_reads, &adj_list_edges, ploidy),
+        ClusterInit::KMedoids => kmedoids_clusters(&adj_list_edges, ploidy),
+        ClusterInit::Spectral => spectral_clusters(&adj_list_edges, ploidy, div_factor),
+    };
+
+    //Turn the vertex indices into actual fragments -- could probably come up with a more elegant
+    //solution using some sort of map function...
+    let mut partition = Vec::new();
+    for cluster in clusters.iter() {
+        let mut frag_set = FxHashSet::default();
+        for vertex in cluster.iter() {
+            let vertex_usize = *vertex as usize;
+            frag_set.insert(*vec_all_reads[vertex_usize]);
+        }
+        partition.push(frag_set)
+    }
+    partition
+}
+
+//Given a set of reads covering an interval, we greedily find a max k-clique and break this
+//k-clique up iinto k different clusters. iteratively add reads to the best k-clique where the max
+//of the intracluster distances is minimized.
+//Importantly, the order in which we itertively add reads is sorted by the minimum of the maximum
+//overlap of the read within the clusters.
+fn clique_clusters(
+    vec_all_edges: &mut [(f64, i32, i32)],
+    vec_all_reads: &[&&Frag],
+    adj_list_edges: &[Vec<(f64, i32)>],
+    ploidy: usize,
+) -> Vec<FxHashSet<i32>> {
     //Finding max clique
     //println!("{:?}",vec_all_edges);
     vec_all_edges.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
@@ -302,31 +389,289 @@ pub fn cluster_reads<'a>(
     populate_clusters3(
         &mut clusters,
         &mut used_vertices,
-        &vec_all_reads,
-        &adj_list_edges,
+        vec_all_reads,
+        adj_list_edges,
         ploidy,
         10,
     );
 
-    //Turn the vertex indices into actual fragments -- could probably come up with a more elegant
-    //solution using some sort of map function...
-    let mut partition = Vec::new();
-    for cluster in clusters.iter() {
-        let mut frag_set = FxHashSet::default();
-        for vertex in cluster.iter() {
-            let vertex_usize = *vertex as usize;
-            frag_set.insert(*vec_all_reads[vertex_usize]);
+    clusters
+}
+
+//Binomial distances between the overlapping reads of the read graph, one map from read to
+//distance per read. Reads which don't overlap are at distance 0, neither alike nor different, and
+//are left out. The distance of a read to itself is the minimum of 0 and its distance to its
+//closest read.
+fn distance_lists(adj_list_edges: &[Vec<(f64, i32)>]) -> Vec<FxHashMap<usize, f64>> {
+    let mut dists = Vec::with_capacity(adj_list_edges.len());
+    for (i, edges) in adj_list_edges.iter().enumerate() {
+        let mut read_dists = FxHashMap::default();
+        let mut min_dist: f64 = 0.0;
+        for (dist, j) in edges.iter() {
+            read_dists.insert(*j as usize, *dist);
+            min_dist = min_dist.min(*dist);
         }
-        partition.push(frag_set)
+        read_dists.insert(i, min_dist);
+        dists.push(read_dists);
     }
-    partition
+    dists
+}
+
+//Split the reads into ploidy clusters by k-medoids on the binomial distances. The medoids are
+//chosen greedily to lower the total distance of the reads to their closest medoid the most (the
+//BUILD step of PAM), and then every medoid is moved to the read of its cluster with the lowest
+//total distance to the others until the clusters don't change. Unlike the clique, a read with
+//many errors is far from every read and is not a good medoid. Only the distances between
+//overlapping reads are stored, so each step takes time linear in the number of edges.
+fn kmedoids_clusters(adj_list_edges: &[Vec<(f64, i32)>], ploidy: usize) -> Vec<FxHashSet<i32>> {
+    let dists = distance_lists(adj_list_edges);
+    let dist = |i: usize, j: usize| -> f64 { dists[i].get(&j).copied().unwrap_or(0.0) };
+    let num_reads = dists.len();
+    let mut medoids: Vec<usize> = Vec::new();
+    let mut closest_dist = vec![f64::MAX; num_reads];
+    while medoids.len() < std::cmp::min(ploidy, num_reads) {
+        //The cost of a candidate is the cost with every read at distance 0 from it, corrected for
+        //the reads overlapping it.
+        let base_cost: f64 = closest_dist.iter().map(|dist| dist.min(0.0)).sum();
+        let mut best_medoid = 0;
+        let mut best_cost = f64::MAX;
+        for (candidate, candidate_dists) in dists.iter().enumerate() {
+            if medoids.contains(&candidate) {
+                continue;
+            }
+            let cost: f64 = base_cost
+                + candidate_dists
+                    .iter()
+                    .map(|(j, dist)| closest_dist[*j].min(*dist) - closest_dist[*j].min(0.0))
+                    .sum::<f64>();
+            if cost < best_cost {
+                best_cost = cost;
+                best_medoid = candidate;
+            }
+        }
+        medoids.push(best_medoid);
+        for (j, closest) in closest_dist.iter_mut().enumerate() {
+            *closest = closest.min(dist(j, best_medoid));
+        }
+    }
+
+    let max_iters = 100;
+    let mut assignment = vec![0; num_reads];
+    for _ in 0..max_iters {
+        for (j, cluster) in assignment.iter_mut().enumerate() {
+            *cluster = match medoids.iter().position(|medoid| *medoid == j) {
+                Some(k) => k,
+                None => (0..medoids.len())
+                    .min_by(|a, b| dist(j, medoids[*a]).partial_cmp(&dist(j, medoids[*b])).unwrap())
+                    .unwrap(),
+            };
+        }
+
+        let mut changed = false;
+        for (k, medoid) in medoids.iter_mut().enumerate() {
+            let total_dist = |candidate: usize| -> f64 {
+                dists[candidate]
+                    .iter()
+                    .filter(|(j, _)| assignment[**j] == k)
+                    .map(|(_, dist)| dist)
+                    .sum()
+            };
+            let mut best_medoid = *medoid;
+            let mut best_dist = total_dist(*medoid);
+            for candidate in (0..num_reads).filter(|j| assignment[*j] == k) {
+                let dist = total_dist(candidate);
+                if dist < best_dist {
+                    best_dist = dist;
+                    best_medoid = candidate;
+                }
+            }
+            if best_medoid != *medoid {
+                *medoid = best_medoid;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+
+    let mut clusters = vec![FxHashSet::default(); ploidy];
+    for (j, k) in assignment.into_iter().enumerate() {
+        clusters[k].insert(j as i32);
+    }
+    clusters
+}
+
+//Split the reads into ploidy clusters by spectral clustering. Two overlapping reads are joined with
+//the weight exp(-distance * div_factor), i.e. the binomial p-value of their mismatches if they
+//are from the same haplotype. The reads are embedded by the top ploidy eigenvectors of the
+//normalized weight matrix, found by subspace iteration, and then clustered by k-means. The weight
+//matrix is kept sparse, with one row of (read, weight) pairs per read, so an iteration takes time
+//linear in the number of edges.
+fn spectral_clusters(adj_list_edges: &[Vec<(f64, i32)>], ploidy: usize, div_factor: f64) -> Vec<FxHashSet<i32>> {
+    let num_reads = adj_list_edges.len();
+    let num_vectors = std::cmp::min(ploidy, num_reads);
+    let mut weights: Vec<Vec<(usize, f64)>> = adj_list_edges
+        .iter()
+        .enumerate()
+        .map(|(i, edges)| {
+            std::iter::once((i, 1.0))
+                .chain(edges.iter().map(|(dist, j)| (*j as usize, (-dist * div_factor).exp().min(1.0))))
+                .collect()
+        })
+        .collect();
+    let inv_sqrt_degrees: Vec<f64> = weights
+        .iter()
+        .map(|row| 1.0 / row.iter().map(|(_, weight)| weight).sum::<f64>().sqrt())
+        .collect();
+    //Shifted by the identity so that all eigenvalues are positive. The first entry of each row is
+    //the diagonal.
+    for (i, row) in weights.iter_mut().enumerate() {
+        for (j, weight) in row.iter_mut() {
+            *weight *= inv_sqrt_degrees[i] * inv_sqrt_degrees[*j];
+        }
+        row[0].1 += 1.0;
+    }
+
+    //Subspace iteration from a fixed start, with the vectors as columns of an num_reads x
+    //num_vectors matrix.
+    let mut rng = Pcg64::seed_from_u64(0);
+    let mut vectors: Vec<Vec<f64>> = (0..num_reads)
+        .map(|_| (0..num_vectors).map(|_| rng.gen_range(-1.0, 1.0)).collect())
+        .collect();
+    let num_iters = 100;
+    for _ in 0..num_iters {
+        let mut product = vec![vec![0.0; num_vectors]; num_reads];
+        for (i, row) in weights.iter().enumerate() {
+            for (j, weight) in row.iter() {
+                if *weight == 0.0 {
+                    continue;
+                }
+                for k in 0..num_vectors {
+                    product[i][k] += weight * vectors[*j][k];
+                }
+            }
+        }
+        //Gram-Schmidt
+        for k in 0..num_vectors {
+            for l in 0..k {
+                let dot: f64 = product.iter().map(|row| row[k] * row[l]).sum();
+                for row in product.iter_mut() {
+                    row[k] -= dot * row[l];
+                }
+            }
+            let norm = product.iter().map(|row| row[k] * row[k]).sum::<f64>().sqrt();
+            if norm > 0.0 {
+                for row in product.iter_mut() {
+                    row[k] /= norm;
+                }
+            }
+        }
+        vectors = product;
+    }
+
+    for row in vectors.iter_mut() {
+        let norm = row.iter().map(|x| x * x).sum::<f64>().sqrt();
+        if norm > 0.0 {
+            row.iter_mut().for_each(|x| *x /= norm);
+        }
+    }
+    //k-means on the embedded reads, keeping the best of a few k-means++ starts.
+    let num_starts = 10;
+    let mut assignment = Vec::new();
+    let mut best_inertia = f64::MAX;
+    for _ in 0..num_starts {
+        let (start_assignment, inertia) = kmeans(&vectors, num_vectors, num_iters, &mut rng);
+        if inertia < best_inertia {
+            best_inertia = inertia;
+            assignment = start_assignment;
+        }
+    }
+
+    let mut clusters = vec![FxHashSet::default(); ploidy];
+    for (j, k) in assignment.into_iter().enumerate() {
+        clusters[k].insert(j as i32);
+    }
+    clusters
+}
+
+fn squared_distance(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b.iter()).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+//k-means clustering of points into k clusters with k-means++ seeds. Returns the cluster of every
+//point and the total squared distance of the points to their cluster centers.
+fn kmeans(points: &[Vec<f64>], k: usize, max_iters: usize, rng: &mut Pcg64) -> (Vec<usize>, f64) {
+    let num_points = points.len();
+    let closest_center = |point: &[f64], centers: &[Vec<f64>]| -> (usize, f64) {
+        let mut best = (0, f64::MAX);
+        for (i, center) in centers.iter().enumerate() {
+            let dist = squared_distance(point, center);
+            if dist < best.1 {
+                best = (i, dist);
+            }
+        }
+        best
+    };
+
+    let mut centers = vec![points[rng.gen_range(0, num_points)].clone()];
+    while centers.len() < k {
+        let dists: Vec<f64> = points.iter().map(|point| closest_center(point, &centers).1).collect();
+        let total: f64 = dists.iter().sum();
+        if total <= 0.0 {
+            centers.push(points[rng.gen_range(0, num_points)].clone());
+            continue;
+        }
+        let mut target = rng.gen::<f64>() * total;
+        let mut chosen = num_points - 1;
+        for (i, dist) in dists.iter().enumerate() {
+            if target < *dist {
+                chosen = i;
+                break;
+            }
+            target -= dist;
+        }
+        centers.push(points[chosen].clone());
+    }
+
+    let mut assignment = vec![usize::MAX; num_points];
+    for _ in 0..max_iters {
+        let mut changed = false;
+        for (point, cluster) in points.iter().zip(assignment.iter_mut()) {
+            let closest = closest_center(point, &centers).0;
+            if *cluster != closest {
+                *cluster = closest;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+        for (i, center) in centers.iter_mut().enumerate() {
+            let members: Vec<&Vec<f64>> = points
+                .iter()
+                .zip(assignment.iter())
+                .filter(|(_, cluster)| **cluster == i)
+                .map(|(point, _)| point)
+                .collect();
+            if members.is_empty() {
+                continue;
+            }
+            for (l, x) in center.iter_mut().enumerate() {
+                *x = members.iter().map(|member| member[l]).sum::<f64>() / members.len() as f64;
+            }
+        }
+    }
+
+    let inertia = points.iter().map(|point| closest_center(point, &centers).1).sum();
+    (assignment, inertia)
 }
 
 fn populate_clusters3(
-    clusters: &mut Vec<FxHashSet<i32>>,
+    clusters: &mut [FxHashSet<i32>],
     used_vertices: &mut FxHashSet<i32>,
-    vec_all_reads: &Vec<&&Frag>,
-    adj_list_edges: &Vec<Vec<(f64, i32)>>,
+    vec_all_reads: &[&&Frag],
+    adj_list_edges: &[Vec<(f64, i32)>],
     ploidy: usize,
     iters: usize,
 ) {
@@ -363,8 +708,8 @@ fn populate_clusters3(
                     if cluster.contains(&edge.1) {
                         let edge_index = edge.1 as usize;
                         let read2 = vec_all_reads[edge_index];
-                        let t: Vec<_> = read.positions.intersection(&read2.positions).collect();
-                        let overlap_len = t.len();
+                        let (same, diff) = utils_frags::distance(read, read2);
+                        let overlap_len = (same + diff) as usize;
                         if overlap_len > read_overlaps_between_clusters[j] {
                             read_overlaps_between_clusters[j] = overlap_len;
                         }
@@ -570,8 +915,8 @@ fn populate_clusters1(
                     if cluster.contains(&edge.1) {
                         let edge_index = edge.1 as usize;
                         let read2 = vec_all_reads[edge_index];
-                        let t: Vec<_> = read.positions.intersection(&read2.positions).collect();
-                        let overlap_len = t.len();
+                        let (same, diff) = utils_frags::distance(read, read2);
+                        let overlap_len = (same + diff) as usize;
                         if overlap_len > read_overlaps_between_clusters[j] {
                             read_overlaps_between_clusters[j] = overlap_len;
                         }
@@ -633,18 +978,18 @@ fn populate_clusters1(
 //partition : the partition
 //epislon : read fragment error rate
 //genotype_dict : the known genotypes at positions
+//parents : the genotypes of the parents, which constrain polishing if given
 //polish : if we polish or not
 //max_iters : the maximum number of iterations we do.
-//div_factor : a normalizing factor for the binomial test to make the sample size smaller.
-//use_mec : we can also use MEC score instead of UPEM is desired
+//objective : the score which is maximized, e.g. UpemObjective. We can also use PEM or MEC.
 pub fn optimize_clustering<'a>(
     partition: Vec<FxHashSet<&'a Frag>>,
     epsilon: f64,
     genotype_dict: &FxHashMap<usize, FxHashMap<usize, usize>>,
+    parents: Option<&ParentGenotypes>,
     polish: bool,
     max_iters: usize,
-    div_factor: f64,
-    use_mec: bool,
+    objective: &dyn PartitionObjective,
 ) -> (f64, Vec<FxHashSet<&'a Frag>>, HapBlock) {
     let mut not_empty = false;
     for part in partition.iter() {
@@ -671,34 +1016,28 @@ pub fn optimize_clustering<'a>(
     let position_vec: Vec<usize> = set_of_positions.into_iter().collect();
 
     if polish {
-        prev_hap_block =
-            vcf_polishing::polish_using_vcf(genotype_dict, &prev_hap_block, &position_vec);
+        prev_hap_block = polish_hap_block(genotype_dict, parents, &prev_hap_block, &position_vec);
     }
 
+    //Reads from different technologies may have different error rates, so each haplotype is
+    //scored against the error rate of the reads assigned to it.
+    let mut hap_epsilons = utils_frags::get_partition_epsilons(&partition, &prev_hap_block, epsilon);
     let (binom_vec, freq_vec) = get_partition_stats(&partition, &prev_hap_block);
-    let mut prev_score = get_upem_score(&binom_vec, &freq_vec, epsilon, div_factor);
-
-    if use_mec {
-        //prev_score = get_mec_score(&binom_vec, &freq_vec, epsilon, div_factor);
-        prev_score = get_pem_score(&binom_vec, &freq_vec, epsilon, div_factor);
-    }
+    let mut prev_score = objective.score(&binom_vec, &freq_vec, &hap_epsilons);
 
     let mut best_part = partition;
 
-    //Iterate until an iteration yields a lower UPEM score -- return partition corresponding
-    //to the best UPEM score.
+    //Iterate until an iteration yields a lower score -- return partition corresponding
+    //to the best score.
     for _i in 0..max_iters {
-        let new_part = opt_iterate(&best_part, &prev_hap_block, epsilon, div_factor);
+        let new_part = opt_iterate(&best_part, &prev_hap_block, &hap_epsilons, objective);
         let mut new_block = utils_frags::hap_block_from_partition(&new_part);
         if polish {
-            new_block = vcf_polishing::polish_using_vcf(genotype_dict, &new_block, &position_vec);
+            new_block = polish_hap_block(genotype_dict, parents, &new_block, &position_vec);
         }
+        let new_hap_epsilons = utils_frags::get_partition_epsilons(&new_part, &new_block, epsilon);
         let (new_binom_vec, new_freq_vec) = get_partition_stats(&new_part, &new_block);
-        let mut new_score = get_upem_score(&new_binom_vec, &new_freq_vec, epsilon, div_factor);
-        if use_mec {
-            //new_score = get_mec_score(&new_binom_vec, &new_freq_vec, epsilon, div_factor);
-            new_score = get_pem_score(&new_binom_vec, &new_freq_vec, epsilon, div_factor);
-        }
+        let new_score = objective.score(&new_binom_vec, &new_freq_vec, &new_hap_epsilons);
 
 //        if new_score > prev_score{
 //            dbg!(_i, &new_binom_vec, new_score, prev_score, &new_freq_vec);
@@ -712,6 +1051,7 @@ pub fn optimize_clustering<'a>(
             prev_score = new_score;
             best_part = new_part;
             prev_hap_block = new_block;
+            hap_epsilons = new_hap_epsilons;
         } else {
             return (prev_score, best_part, prev_hap_block);
         }
@@ -720,8 +1060,131 @@ pub fn optimize_clustering<'a>(
     return (prev_score, best_part, prev_hap_block);
 }
 
+//Polish a haplotype block at positions with the genotypes. With parents, each haplotype is assigned
+//to a parent on the SNPs between the first and last of positions and only given alleles its parent
+//can pass on (see vcf_polishing::polish_using_parents).
+fn polish_hap_block(
+    genotype_dict: &FxHashMap<usize, FxHashMap<usize, usize>>,
+    parents: Option<&ParentGenotypes>,
+    hap_block: &HapBlock,
+    positions: &Vec<usize>,
+) -> HapBlock {
+    match (parents, positions.iter().min(), positions.iter().max()) {
+        (Some(parents), Some(start), Some(end)) => {
+            let costs = vcf_polishing::get_parent_costs(hap_block, parents, *start, *end);
+            let (hap_parents, _) = vcf_polishing::assign_haplotypes_to_parents(&costs, &parents.contributions);
+            vcf_polishing::polish_using_parents(genotype_dict, hap_block, positions, parents, &hap_parents)
+        }
+        _ => vcf_polishing::polish_using_vcf(genotype_dict, hap_block, positions),
+    }
+}
+
+//Refine a linked partition with opt_iterate moves on windows of window_len SNPs which slide
+//across the contig by half a window. Local blocks are only optimized on their own, so this can
+//move reads which were misassigned near block boundaries once the haplotypes span the
+//boundaries. Unlike optimize_clustering, the haplotypes of a window are built from all reads
+//overlapping the reads of the window, so the reads just outside the window are taken into
+//account.
+//
+//max_iters : the maximum number of iterations for each window.
+//The other arguments are as in optimize_clustering.
+#[allow(clippy::too_many_arguments)]
+pub fn refine_partition<'a>(
+    mut partition: Vec<FxHashSet<&'a Frag>>,
+    frag_index: &FragIndex<'a>,
+    length_gn: usize,
+    window_len: usize,
+    epsilon: f64,
+    genotype_dict: &FxHashMap<usize, FxHashMap<usize, usize>>,
+    parents: Option<&ParentGenotypes>,
+    polish: bool,
+    max_iters: usize,
+    objective: &dyn PartitionObjective,
+) -> Vec<FxHashSet<&'a Frag>> {
+    let ploidy = partition.len();
+    let window_len = std::cmp::max(window_len, 1);
+    let step = std::cmp::max(window_len / 2, 1);
+
+    let mut start = 1;
+    while start <= length_gn {
+        let end = std::cmp::min(start + window_len - 1, length_gn);
+        let window_reads = frag_index.reads_in_interval(start, end);
+        if window_reads.is_empty() {
+            if end == length_gn {
+                break;
+            }
+            start += step;
+            continue;
+        }
+
+        //Reads overlapping the window reads, which are kept where they are.
+        let context_start = window_reads.iter().map(|read| read.first_position).min().unwrap();
+        let context_end = window_reads.iter().map(|read| read.last_position).max().unwrap();
+        let context_reads = frag_index.reads_in_interval(context_start, context_end);
+        let mut fixed_part = vec![FxHashSet::default(); ploidy];
+        let mut window_part = vec![FxHashSet::default(); ploidy];
+        for (i, reads) in partition.iter().enumerate() {
+            for read in context_reads.iter() {
+                if reads.contains(read) {
+                    if window_reads.contains(read) {
+                        window_part[i].insert(*read);
+                    } else {
+                        fixed_part[i].insert(*read);
+                    }
+                }
+            }
+        }
+
+        let score_window = |window_part: &Vec<FxHashSet<&'a Frag>>| -> (f64, HapBlock) {
+            let mut all_reads = fixed_part.clone();
+            for (reads, window_reads_hap) in all_reads.iter_mut().zip(window_part.iter()) {
+                reads.extend(window_reads_hap.iter().copied());
+            }
+            let mut hap_block = utils_frags::hap_block_from_partition(&all_reads);
+            if polish {
+                let positions: Vec<usize> = (context_start..context_end + 1).collect();
+                hap_block = polish_hap_block(genotype_dict, parents, &hap_block, &positions);
+            }
+            let hap_epsilons = utils_frags::get_partition_epsilons(window_part, &hap_block, epsilon);
+            let (binom_vec, freq_vec) = get_partition_stats(window_part, &hap_block);
+            (objective.score(&binom_vec, &freq_vec, &hap_epsilons), hap_block)
+        };
+
+        let (mut best_score, mut best_block) = score_window(&window_part);
+        let mut best_part = window_part;
+        for _i in 0..max_iters {
+            let hap_epsilons = utils_frags::get_partition_epsilons(&best_part, &best_block, epsilon);
+            let new_part = opt_iterate(&best_part, &best_block, &hap_epsilons, objective);
+            let (new_score, new_block) = score_window(&new_part);
+            if new_score > best_score {
+                best_score = new_score;
+                best_part = new_part;
+                best_block = new_block;
+            } else {
+                break;
+            }
+        }
+
+        for reads in partition.iter_mut() {
+            for read in window_reads.iter() {
+                reads.remove(read);
+            }
+        }
+        for (reads, new_reads) in partition.iter_mut().zip(best_part) {
+            reads.extend(new_reads);
+        }
+
+        if end == length_gn {
+            break;
+        }
+        start += step;
+    }
+
+    partition
+}
+
 //Get the chiq-square log p value from a vector of frequencies.
-fn chi_square_p(freqs: &Vec<usize>) -> f64 {
+fn chi_square_p(freqs: &[usize]) -> f64 {
     let dof = (freqs.len() - 1) as f64;
 
     let mean: usize = freqs.iter().sum();
@@ -741,6 +1204,29 @@ fn chi_square_p(freqs: &Vec<usize>) -> f64 {
     return rv_res.ln();
 }
 
+//Get the chi-square log p value of the read counts of the haplotypes against the expected
+//proportions of the reads on the haplotypes. Haplotypes are unlabelled, so the counts are sorted
+//from largest to smallest and compared to the proportions sorted the same way.
+fn multinomial_fit_p(freqs: &[usize], proportions: &[f64]) -> f64 {
+    let total: usize = freqs.iter().sum();
+    if total == 0 {
+        return 0.0;
+    }
+    let mut sorted_freqs = freqs.to_vec();
+    sorted_freqs.sort_unstable_by(|a, b| b.cmp(a));
+    let mut chi_stat = 0.0;
+    for (freq, proportion) in sorted_freqs.iter().zip(proportions.iter()) {
+        let expected = total as f64 * proportion;
+        chi_stat += (*freq as f64 - expected).powf(2.0) / expected;
+    }
+    if chi_stat <= 0.00 {
+        return 0.000;
+    }
+    let rv = ChiSquared::new((freqs.len() - 1) as f64).unwrap();
+    let rv_res = 1.0 - rv.cdf(chi_stat);
+    rv_res.ln()
+}
+
 //Can also use a normal approximation. This formula is taken from wikipedia.
 pub fn log_erfc(x: f64) -> f64 {
     let p = 0.47047;
@@ -814,7 +1300,7 @@ pub fn stable_binom_cdf_p_rev(n: usize, k: usize, p: f64, div_factor: f64) -> f6
 //Get a vector of read frequencies and error rates from a partition and its corresponding
 //haplotype block.
 pub fn get_partition_stats(
-    partition: &Vec<FxHashSet<&Frag>>,
+    partition: &[FxHashSet<&Frag>],
     hap_block: &HapBlock,
 ) -> (Vec<(usize, usize)>, Vec<usize>) {
     let mut binom_vec = Vec::new();
@@ -836,31 +1322,31 @@ pub fn get_partition_stats(
     (binom_vec, freq_vec)
 }
 
-//Return pem score
+//Return pem score. p contains the error rate of each haplotype.
 fn get_pem_score(
-    binom_vec: &Vec<(usize, usize)>,
-    _freq_vec: &Vec<usize>,
-    p: f64,
+    binom_vec: &[(usize, usize)],
+    _freq_vec: &[usize],
+    p: &[f64],
     div_factor: f64,
 ) -> f64 {
     let mut score = 0.0;
-    for stat in binom_vec.iter() {
-        let bincdf = stable_binom_cdf_p_rev(stat.0 + stat.1, stat.1, p, div_factor);
+    for (i, stat) in binom_vec.iter().enumerate() {
+        let bincdf = stable_binom_cdf_p_rev(stat.0 + stat.1, stat.1, p[i], div_factor);
         score += bincdf;
     }
     score
 }
 
-//Return upem score
+//Return upem score. p contains the error rate of each haplotype.
 fn get_upem_score(
-    binom_vec: &Vec<(usize, usize)>,
-    freq_vec: &Vec<usize>,
-    p: f64,
+    binom_vec: &[(usize, usize)],
+    freq_vec: &[usize],
+    p: &[f64],
     div_factor: f64,
 ) -> f64 {
     let mut score = 0.0;
-    for stat in binom_vec.iter() {
-        let bincdf = stable_binom_cdf_p_rev(stat.0 + stat.1, stat.1, p, div_factor);
+    for (i, stat) in binom_vec.iter().enumerate() {
+        let bincdf = stable_binom_cdf_p_rev(stat.0 + stat.1, stat.1, p[i], div_factor);
         score += bincdf;
     }
     score += chi_square_p(freq_vec);
@@ -869,8 +1355,8 @@ fn get_upem_score(
 
 //Return mec score
 pub fn get_mec_score(
-    binom_vec: &Vec<(usize, usize)>,
-    _freq_vec: &Vec<usize>,
+    binom_vec: &[(usize, usize)],
+    _freq_vec: &[usize],
     _p: f64,
     _div_factor: f64,
 ) -> f64 {
@@ -883,42 +1369,277 @@ pub fn get_mec_score(
 
 }
 
+//BIC of a partition of the reads of a block into strains, used to choose the number of strains
+//of the block : lower is better. The alleles of the reads on each strain disagree with the strain
+//at rate epsilon, and each read is on a strain with probability the fraction of the reads on the
+//strain. Every SNP covered by a strain and every strain fraction is a parameter, and the sample
+//size is the number of alleles of the reads.
+pub fn get_strain_bic(partition: &[FxHashSet<&Frag>], epsilon: f64) -> f64 {
+    let hap_block = utils_frags::hap_block_from_partition(partition);
+    let (binom_vec, freq_vec) = get_partition_stats(partition, &hap_block);
+    let num_reads: usize = freq_vec.iter().sum();
+    let num_bases: usize = binom_vec.iter().map(|stat| stat.0 + stat.1).sum();
+    if num_reads == 0 {
+        return 0.0;
+    }
+
+    let mut log_lik = 0.0;
+    for (stat, freq) in binom_vec.iter().zip(freq_vec.iter()) {
+        log_lik += stat.0 as f64 * (1.0 - epsilon).ln() + stat.1 as f64 * epsilon.ln();
+        if *freq > 0 {
+            log_lik += *freq as f64 * (*freq as f64 / num_reads as f64).ln();
+        }
+    }
+    let num_strains = freq_vec.iter().filter(|freq| **freq > 0).count();
+    let num_snps: usize = hap_block.blocks.iter().map(|hap| hap.len()).sum();
+    let num_params = (num_snps + num_strains - 1) as f64;
+    -2.0 * log_lik + num_params * (num_bases as f64).ln()
+}
+
+//Leave out the strains without reads and order the others from most to least reads.
+pub fn sort_strains(partition: Vec<FxHashSet<&Frag>>) -> Vec<FxHashSet<&Frag>> {
+    let mut strains: Vec<FxHashSet<&Frag>> =
+        partition.into_iter().filter(|reads| !reads.is_empty()).collect();
+    strains.sort_by_key(|strain| std::cmp::Reverse(strain.len()));
+    strains
+}
+
+//A read moved from haplotype `from` to haplotype `to`. from_stats and to_stats are the (good
+//bases, errors) of the read against the two haplotypes.
+pub struct ReadMove {
+    pub from: usize,
+    pub to: usize,
+    pub from_stats: (usize, usize),
+    pub to_stats: (usize, usize),
+}
+
+//The score of a partition which optimize_clustering, refine_partition and opt_iterate maximize.
+//A partition is scored from the (good bases, errors) of the reads on each haplotype (binom_vec),
+//the number of reads on each haplotype (freq_vec) and the error rate of each haplotype
+//(epsilons), as returned by get_partition_stats and utils_frags::get_partition_epsilons.
+//
+//move_delta is the change of score from moving a single read, which opt_iterate evaluates for
+//every read and haplotype. By default it rescores the whole partition; objectives which are sums
+//over haplotypes should only rescore the two haplotypes changed by the move.
+pub trait PartitionObjective: Sync {
+    fn score(&self, binom_vec: &[(usize, usize)], freq_vec: &[usize], epsilons: &[f64]) -> f64;
+
+    fn move_delta(
+        &self,
+        binom_vec: &[(usize, usize)],
+        freq_vec: &[usize],
+        epsilons: &[f64],
+        read_move: &ReadMove,
+    ) -> f64 {
+        let (new_binom_vec, new_freq_vec) = apply_move(binom_vec, freq_vec, read_move);
+        self.score(&new_binom_vec, &new_freq_vec, epsilons) - self.score(binom_vec, freq_vec, epsilons)
+    }
+}
+
+//The partition stats after a read move.
+fn apply_move(
+    binom_vec: &[(usize, usize)],
+    freq_vec: &[usize],
+    read_move: &ReadMove,
+) -> (Vec<(usize, usize)>, Vec<usize>) {
+    let mut binom_vec = binom_vec.to_vec();
+    let mut freq_vec = freq_vec.to_vec();
+    binom_vec[read_move.from].0 -= read_move.from_stats.0;
+    binom_vec[read_move.from].1 -= read_move.from_stats.1;
+    binom_vec[read_move.to].0 += read_move.to_stats.0;
+    binom_vec[read_move.to].1 += read_move.to_stats.1;
+    freq_vec[read_move.from] -= 1;
+    freq_vec[read_move.to] += 1;
+    (binom_vec, freq_vec)
+}
+
+//Change of the binomial terms of the two haplotypes changed by a read move, which is the change
+//of the PEM score.
+fn binom_move_delta(
+    binom_vec: &[(usize, usize)],
+    epsilons: &[f64],
+    read_move: &ReadMove,
+    div_factor: f64,
+) -> f64 {
+    let binom_term = |bases: usize, errors: usize, hap: usize| {
+        stable_binom_cdf_p_rev(bases + errors, errors, epsilons[hap], div_factor)
+    };
+    let (from_bases, from_errors) = binom_vec[read_move.from];
+    let (to_bases, to_errors) = binom_vec[read_move.to];
+    let new_score = binom_term(
+        from_bases - read_move.from_stats.0,
+        from_errors - read_move.from_stats.1,
+        read_move.from,
+    ) + binom_term(
+        to_bases + read_move.to_stats.0,
+        to_errors + read_move.to_stats.1,
+        read_move.to,
+    );
+    let old_score =
+        binom_term(from_bases, from_errors, read_move.from) + binom_term(to_bases, to_errors, read_move.to);
+    new_score - old_score
+}
+
+//UPEM : binomial error terms for every haplotype plus a chi-square term for how evenly the reads
+//are spread over the haplotypes. div_factor scales down the sample size of the binomial test.
+pub struct UpemObjective {
+    pub div_factor: f64,
+}
+
+impl PartitionObjective for UpemObjective {
+    fn score(&self, binom_vec: &[(usize, usize)], freq_vec: &[usize], epsilons: &[f64]) -> f64 {
+        get_upem_score(binom_vec, freq_vec, epsilons, self.div_factor)
+    }
+
+    fn move_delta(
+        &self,
+        binom_vec: &[(usize, usize)],
+        freq_vec: &[usize],
+        epsilons: &[f64],
+        read_move: &ReadMove,
+    ) -> f64 {
+        let mut new_freq_vec = freq_vec.to_vec();
+        new_freq_vec[read_move.from] -= 1;
+        new_freq_vec[read_move.to] += 1;
+        binom_move_delta(binom_vec, epsilons, read_move, self.div_factor) + chi_square_p(&new_freq_vec)
+            - chi_square_p(freq_vec)
+    }
+}
+
+//PEM : UPEM without the chi-square term, for haplotypes with unbalanced coverage.
+pub struct PemObjective {
+    pub div_factor: f64,
+}
+
+impl PartitionObjective for PemObjective {
+    fn score(&self, binom_vec: &[(usize, usize)], freq_vec: &[usize], epsilons: &[f64]) -> f64 {
+        get_pem_score(binom_vec, freq_vec, epsilons, self.div_factor)
+    }
+
+    fn move_delta(
+        &self,
+        binom_vec: &[(usize, usize)],
+        _freq_vec: &[usize],
+        epsilons: &[f64],
+        read_move: &ReadMove,
+    ) -> f64 {
+        binom_move_delta(binom_vec, epsilons, read_move, self.div_factor)
+    }
+}
+
+//PEM plus a chi-square term for how well the read counts of the haplotypes fit the expected
+//proportions of the reads on the haplotypes, for haplotypes with known unequal coverage. UPEM is
+//the case of equal proportions.
+pub struct CoverageObjective {
+    pub div_factor: f64,
+    proportions: Vec<f64>,
+}
+
+impl CoverageObjective {
+    //The proportions are normalized to sum to 1 and need not be in any order. They must be
+    //positive, since the chi-square term divides by the expected read counts.
+    pub fn new(div_factor: f64, proportions: &[f64]) -> CoverageObjective {
+        if proportions.iter().any(|proportion| !(*proportion > 0.0 && proportion.is_finite())) {
+            panic!("Haplotype proportions must be positive, got {:?}.", proportions);
+        }
+        let total: f64 = proportions.iter().sum();
+        let mut proportions: Vec<f64> = proportions.iter().map(|proportion| proportion / total).collect();
+        proportions.sort_by(|a, b| b.partial_cmp(a).unwrap());
+        CoverageObjective {
+            div_factor,
+            proportions,
+        }
+    }
+
+    //The normalized proportions from largest to smallest.
+    pub fn proportions(&self) -> &[f64] {
+        &self.proportions
+    }
+}
+
+impl PartitionObjective for CoverageObjective {
+    fn score(&self, binom_vec: &[(usize, usize)], freq_vec: &[usize], epsilons: &[f64]) -> f64 {
+        get_pem_score(binom_vec, freq_vec, epsilons, self.div_factor)
+            + multinomial_fit_p(freq_vec, &self.proportions)
+    }
+
+    fn move_delta(
+        &self,
+        binom_vec: &[(usize, usize)],
+        freq_vec: &[usize],
+        epsilons: &[f64],
+        read_move: &ReadMove,
+    ) -> f64 {
+        let mut new_freq_vec = freq_vec.to_vec();
+        new_freq_vec[read_move.from] -= 1;
+        new_freq_vec[read_move.to] += 1;
+        binom_move_delta(binom_vec, epsilons, read_move, self.div_factor)
+            + multinomial_fit_p(&new_freq_vec, &self.proportions)
+            - multinomial_fit_p(freq_vec, &self.proportions)
+    }
+}
+
+//Expected proportions of the reads on the haplotypes given on the command line : either
+//comma separated proportions, or estimate to estimate them (see estimate_hap_proportions).
+#[derive(Clone, Debug, PartialEq)]
+pub enum HapCoverage {
+    Given(Vec<f64>),
+    Estimate,
+}
+
+//Parse the haplotype coverage given on the command line. The proportions must be positive.
+pub fn parse_hap_coverage(value: &str) -> Option<HapCoverage> {
+    if value == "estimate" {
+        return Some(HapCoverage::Estimate);
+    }
+    let mut proportions = Vec::new();
+    for field in value.split(',') {
+        match field.trim().parse::<f64>() {
+            Ok(proportion) if proportion > 0.0 && proportion.is_finite() => proportions.push(proportion),
+            _ => return None,
+        }
+    }
+    Some(HapCoverage::Given(proportions))
+}
+
+//MEC : minus the number of alleles of the reads which disagree with their haplotype.
+pub struct MecObjective;
+
+impl PartitionObjective for MecObjective {
+    fn score(&self, binom_vec: &[(usize, usize)], freq_vec: &[usize], _epsilons: &[f64]) -> f64 {
+        get_mec_score(binom_vec, freq_vec, 0.0, 1.0)
+    }
+
+    fn move_delta(
+        &self,
+        _binom_vec: &[(usize, usize)],
+        _freq_vec: &[usize],
+        _epsilons: &[f64],
+        read_move: &ReadMove,
+    ) -> f64 {
+        read_move.from_stats.1 as f64 - read_move.to_stats.1 as f64
+    }
+}
+
 fn opt_iterate<'a>(
     partition: &Vec<FxHashSet<&'a Frag>>,
     hap_block: &HapBlock,
-    epsilon: f64,
-    div_factor: f64,
+    epsilons: &[f64],
+    objective: &dyn PartitionObjective,
 ) -> Vec<FxHashSet<&'a Frag>> {
     let ploidy = partition.len();
     let (binom_vec, freq_vec) = get_partition_stats(partition, hap_block);
     let mut freq_vec = freq_vec;
-    let mut binom_p_vec = Vec::new();
-    let chi_square_val = chi_square_p(&freq_vec);
-
-    for bases_errors in binom_vec.iter() {
-        let bases = bases_errors.0;
-        let errors = bases_errors.1;
-        let binom_logp_val = stable_binom_cdf_p_rev(bases + errors, errors, epsilon, div_factor);
-        binom_p_vec.push(binom_logp_val);
-    }
 
     let mut best_moves = Vec::new();
 
-    for i in 0..ploidy {
-        if partition[i].len() <= 1 {
+    for (i, reads) in partition.iter().enumerate() {
+        if reads.len() <= 1 {
             continue;
         }
-        for read in partition[i].iter() {
+        for read in reads.iter() {
             let haplo_i = &hap_block.blocks[i];
-            let (bases_good_read, errors_read) = utils_frags::distance_read_haplo(read, haplo_i);
-            let bases_good_after = binom_vec[i].0 - bases_good_read;
-            let errors_after = binom_vec[i].1 - errors_read;
-            let new_binom_val_i = stable_binom_cdf_p_rev(
-                bases_good_after + errors_after,
-                errors_after,
-                epsilon,
-                div_factor,
-            );
+            let from_stats = utils_frags::distance_read_haplo(read, haplo_i);
             for j in 0..ploidy {
                 if j == i {
                     continue;
@@ -926,31 +1647,16 @@ fn opt_iterate<'a>(
 
                 //Test out new move
                 let haplo_j = &hap_block.blocks[j];
-                let (read_bases_good_movej, read_errors_movej) =
-                    utils_frags::distance_read_haplo(read, haplo_j);
-
-                let bases_good_after_movej = binom_vec[j].0 + read_bases_good_movej;
-                let errors_after_movej = binom_vec[j].1 + read_errors_movej;
-                let new_binom_val_j = stable_binom_cdf_p_rev(
-                    bases_good_after_movej + errors_after_movej,
-                    errors_after_movej,
-                    epsilon,
-                    div_factor,
-                );
-
-                freq_vec[j] += 1;
-                freq_vec[i] -= 1;
-                let new_chi_square_val = chi_square_p(&freq_vec);
-
-                let new_score = new_binom_val_i + new_binom_val_j + new_chi_square_val;
-                let old_score = binom_p_vec[i] + binom_p_vec[j] + chi_square_val;
-                if new_score - old_score > 0.0 {
-                    best_moves.push((new_score - old_score, (i, read, j)));
-                    //dbg!(new_score,old_score,read_bases_good_movej,read_errors_movej);
+                let read_move = ReadMove {
+                    from: i,
+                    to: j,
+                    from_stats,
+                    to_stats: utils_frags::distance_read_haplo(read, haplo_j),
+                };
+                let delta = objective.move_delta(&binom_vec, &freq_vec, epsilons, &read_move);
+                if delta > 0.0 {
+                    best_moves.push((delta, (i, read, j)));
                 }
-
-                freq_vec[i] += 1;
-                freq_vec[j] -= 1;
             }
         }
     }
@@ -986,44 +1692,390 @@ fn opt_iterate<'a>(
     new_part
 }
 
+//Log likelihood of errors out of bases for a binomial error rate p.
+fn binom_log_lik(bases: usize, errors: usize, p: f64) -> f64 {
+    let p = p.clamp(1e-6, 0.5);
+    (errors as f64) * p.ln() + ((bases - errors) as f64) * (1.0 - p).ln()
+}
+
+//Fit a mixture of two binomial error rates to the (good bases, errors) of haplotypes by EM. Well
+//clustered haplotypes only have sequencing errors, while badly clustered or noisy ones have a
+//higher error rate. Returns the lower error rate and, for each haplotype, the probability that
+//it belongs to it. A single error rate is fit instead if the mixture does not improve the BIC.
+pub fn fit_error_mixture(stats: &[(usize, usize)]) -> (f64, Vec<f64>) {
+    let total_bases: usize = stats.iter().map(|stat| stat.0 + stat.1).sum();
+    let total_errors: usize = stats.iter().map(|stat| stat.1).sum();
+    if total_bases == 0 {
+        return (0.0, vec![1.0; stats.len()]);
+    }
+    let pooled = total_errors as f64 / total_bases as f64;
+    let single_log_lik: f64 = stats
+        .iter()
+        .map(|stat| binom_log_lik(stat.0 + stat.1, stat.1, pooled))
+        .sum();
+
+    let mut rates: Vec<f64> = stats
+        .iter()
+        .filter(|stat| stat.0 + stat.1 > 0)
+        .map(|stat| stat.1 as f64 / (stat.0 + stat.1) as f64)
+        .collect();
+    rates.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mut p0 = rates[rates.len() / 4];
+    let mut p1 = rates[rates.len() * 3 / 4].max(2.0 * p0 + 0.001);
+    let mut w0: f64 = 0.5;
+    let mut resp = vec![1.0; stats.len()];
+    let mut mixture_log_lik = 0.0;
+
+    for _ in 0..200 {
+        //E step
+        mixture_log_lik = 0.0;
+        for (stat, r) in stats.iter().zip(resp.iter_mut()) {
+            let l0 = w0.ln() + binom_log_lik(stat.0 + stat.1, stat.1, p0);
+            let l1 = (1.0 - w0).ln() + binom_log_lik(stat.0 + stat.1, stat.1, p1);
+            let max_l = l0.max(l1);
+            *r = 1.0 / (1.0 + (l1 - l0).exp());
+            mixture_log_lik += max_l + ((l0 - max_l).exp() + (l1 - max_l).exp()).ln();
+        }
+
+        //M step
+        let mut sums = [(0.0, 0.0); 2];
+        for (stat, r) in stats.iter().zip(resp.iter()) {
+            sums[0].0 += r * (stat.0 + stat.1) as f64;
+            sums[0].1 += r * stat.1 as f64;
+            sums[1].0 += (1.0 - r) * (stat.0 + stat.1) as f64;
+            sums[1].1 += (1.0 - r) * stat.1 as f64;
+        }
+        let new_p0 = if sums[0].0 > 0.0 { sums[0].1 / sums[0].0 } else { p0 };
+        let new_p1 = if sums[1].0 > 0.0 { sums[1].1 / sums[1].0 } else { p1 };
+        w0 = (resp.iter().sum::<f64>() / stats.len() as f64).clamp(1e-6, 1.0 - 1e-6);
+        let change = (new_p0 - p0).abs() + (new_p1 - p1).abs();
+        p0 = new_p0;
+        p1 = new_p1;
+        if change < 1e-9 {
+            break;
+        }
+    }
+
+    if p0 > p1 {
+        mem::swap(&mut p0, &mut p1);
+        resp.iter_mut().for_each(|r| *r = 1.0 - *r);
+    }
+
+    let num_haps = stats.len() as f64;
+    let single_bic = -2.0 * single_log_lik + num_haps.ln();
+    let mixture_bic = -2.0 * mixture_log_lik + 3.0 * num_haps.ln();
+    if mixture_bic < single_bic {
+        (p0, resp)
+    } else {
+        (pooled, vec![1.0; stats.len()])
+    }
+}
+
+//(good bases, errors) of a read against the other reads on its haplotype hap, skipping positions
+//where no other read is on the haplotype or where the other reads are tied.
+fn read_leave_one_out_stats(read: &Frag, hap: &FxHashMap<usize, FxHashMap<usize, usize>>) -> (usize, usize) {
+    let mut good = 0;
+    let mut bad = 0;
+    for (pos, var) in read.alleles() {
+        let site_map = &hap[&pos];
+        let read_count = site_map[&var] - 1;
+        let other_count = site_map
+            .iter()
+            .filter(|(allele, _)| **allele != var)
+            .map(|(_, count)| *count)
+            .max()
+            .unwrap_or(0);
+        if read_count > other_count {
+            good += 1;
+        } else if read_count < other_count {
+            bad += 1;
+        }
+    }
+    (good, bad)
+}
+
+//(good bases, errors) of each haplotype of a partition, where every read is compared to the
+//haplotype of the other reads on it. Comparing a read to a haplotype it is part of underestimates
+//the error rate, e.g. a haplotype of one read never has errors. Positions where no other read
+//is on the haplotype or where the other reads are tied are skipped.
+pub fn get_leave_one_out_stats(partition: &Vec<FxHashSet<&Frag>>) -> Vec<(usize, usize)> {
+    let hap_block = utils_frags::hap_block_from_partition(partition);
+    let mut stats = Vec::new();
+    for (reads, hap) in partition.iter().zip(hap_block.blocks.iter()) {
+        let mut good = 0;
+        let mut bad = 0;
+        for read in reads.iter() {
+            let (read_good, read_bad) = read_leave_one_out_stats(read, hap);
+            good += read_good;
+            bad += read_bad;
+        }
+        stats.push((good, bad));
+    }
+    stats
+}
+
+//(good bases, errors) of every read against the other reads on its haplotype, summed over the
+//partitions of all local blocks the read is in. Reads which are never compared, e.g. because
+//they are alone on their haplotype, are left out.
+pub fn get_read_error_stats<'a>(parts: &[Vec<FxHashSet<&'a Frag>>]) -> FxHashMap<&'a Frag, (usize, usize)> {
+    let mut read_stats: FxHashMap<&Frag, (usize, usize)> = FxHashMap::default();
+    for partition in parts.iter() {
+        let hap_block = utils_frags::hap_block_from_partition(partition);
+        for (reads, hap) in partition.iter().zip(hap_block.blocks.iter()) {
+            for read in reads.iter() {
+                let (good, bad) = read_leave_one_out_stats(read, hap);
+                if good + bad == 0 {
+                    continue;
+                }
+                let stats = read_stats.entry(read).or_insert((0, 0));
+                stats.0 += good;
+                stats.1 += bad;
+            }
+        }
+    }
+    read_stats
+}
+
+//Reads with an error rate above max_read_error, or if it is not given, above three times the median
+//read error rate and at least 0.1 so that accurate reads are not left out for a few errors. Only
+//reads with at least min_bases compared alleles are tested, since the error rate of a read with a
+//few alleles is too noisy.
+pub fn get_high_error_reads<'a>(
+    read_stats: &FxHashMap<&'a Frag, (usize, usize)>,
+    max_read_error: Option<f64>,
+    min_bases: usize,
+) -> FxHashSet<&'a Frag> {
+    let error_rate = |stats: &(usize, usize)| stats.1 as f64 / (stats.0 + stats.1) as f64;
+    let tested_reads: Vec<(&Frag, f64)> = read_stats
+        .iter()
+        .filter(|(_, stats)| stats.0 + stats.1 >= min_bases)
+        .map(|(read, stats)| (*read, error_rate(stats)))
+        .collect();
+    if tested_reads.is_empty() {
+        return FxHashSet::default();
+    }
+    let max_read_error = match max_read_error {
+        Some(max_read_error) => max_read_error,
+        None => {
+            let mut rates: Vec<f64> = tested_reads.iter().map(|(_, rate)| *rate).collect();
+            rates.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            (3.0 * rates[rates.len() / 2]).max(0.1)
+        }
+    };
+    tested_reads
+        .into_iter()
+        .filter(|(_, rate)| *rate > max_read_error)
+        .map(|(read, _)| read)
+        .collect()
+}
+
+//Smallest proportion of the reads on a haplotype estimated by estimate_hap_proportions.
+pub const MIN_HAP_PROPORTION: f64 = 0.01;
+
+//Estimate the proportions of the reads on each haplotype from num_tries local blocks sampled
+//with the given seed. The blocks are clustered with init and optimized with objective, which
+//should not assume balanced haplotypes (e.g. PemObjective), and the read counts of the
+//haplotypes of each block are sorted and summed over the blocks. Sorting spreads out the counts
+//of haplotypes with equal coverage a little. A haplotype without reads in the sampled blocks would
+//get a proportion of 0, so the proportions are floored at MIN_HAP_PROPORTION. Returns the
+//proportions from largest to smallest, or None if the sampled blocks have no reads.
+#[allow(clippy::too_many_arguments)]
+pub fn estimate_hap_proportions(
+    blocks: &[LocalBlock],
+    num_tries: usize,
+    ploidy: usize,
+    frag_index: &FragIndex,
+    epsilon: f64,
+    init: ClusterInit,
+    objective: &dyn PartitionObjective,
+    seed: u64,
+) -> Option<Vec<f64>> {
+    let max_iters = 10;
+    let mut rng = Pcg64::seed_from_u64(seed);
+    let sampled_blocks: Vec<LocalBlock> = blocks
+        .choose_multiple(&mut rng, num_tries)
+        .copied()
+        .collect();
+    let genotype_dict = FxHashMap::default();
+
+    let block_counts: Vec<Vec<usize>> = sampled_blocks
+        .par_iter()
+        .map(|block| {
+            let part = generate_hap_block(block.start, block.end, ploidy, frag_index, epsilon, init);
+            let (_score, part, _hap_block) =
+                optimize_clustering(part, epsilon, &genotype_dict, None, false, max_iters, objective);
+            let mut counts: Vec<usize> = part.iter().map(|reads| reads.len()).collect();
+            counts.sort_unstable_by(|a, b| b.cmp(a));
+            counts
+        })
+        .collect();
+
+    let mut total_counts = vec![0; ploidy];
+    for counts in block_counts.iter() {
+        for (total, count) in total_counts.iter_mut().zip(counts.iter()) {
+            *total += count;
+        }
+    }
+    let total: usize = total_counts.iter().sum();
+    if total == 0 {
+        return None;
+    }
+    let floored: Vec<f64> = total_counts
+        .into_iter()
+        .map(|count| (count as f64 / total as f64).max(MIN_HAP_PROPORTION))
+        .collect();
+    let floored_total: f64 = floored.iter().sum();
+    Some(floored.into_iter().map(|proportion| proportion / floored_total).collect())
+}
+
+//Smallest epsilon estimate_epsilon gives for clustering.
+pub const MIN_EPSILON: f64 = 0.01;
+
+//Epsilon for clustering from the in sample error rates of haplotypes : their 10% quantile, at
+//least MIN_EPSILON.
+fn quantile_epsilon(mut in_sample_epsilons: Vec<f64>) -> f64 {
+    in_sample_epsilons.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    in_sample_epsilons[in_sample_epsilons.len() / 10].max(MIN_EPSILON)
+}
+
+//Partition of the reads of a block into 1 to max_strains strains clustered with init, with the
+//number of strains whose partition has the lowest BIC at error rate epsilon (see get_strain_bic).
+fn cluster_strains<'a>(
+    start: usize,
+    end: usize,
+    max_strains: usize,
+    frag_index: &FragIndex<'a>,
+    epsilon: f64,
+    init: ClusterInit,
+) -> Vec<FxHashSet<&'a Frag>> {
+    let mut best_part = Vec::new();
+    let mut best_bic = f64::MAX;
+    for num_strains in 1..max_strains + 1 {
+        let part = generate_hap_block(start, end, num_strains, frag_index, epsilon, init);
+        let bic = get_strain_bic(&part, epsilon);
+        if bic < best_bic {
+            best_bic = bic;
+            best_part = part;
+        }
+    }
+    best_part
+}
+
+//Estimate the error rates of the reads from num_tries local blocks sampled with the given seed.
+//The blocks are clustered with init into ploidy haplotypes, or with choose_num_strains into the
+//number of strains up to ploidy chosen by cluster_strains, since clustering into too many strains
+//would lower the error rates. The UPEM score compares reads to haplotypes which include them, so
+//clustering uses the epsilon of quantile_epsilon, with a 95% confidence interval from
+//bootstrapping the sampled blocks. The error rate of the reads themselves is fit to the errors of
+//the reads against the other reads on their haplotypes (see fit_error_mixture and
+//get_leave_one_out_stats). Returns None if the sampled blocks have no reads.
+#[allow(clippy::too_many_arguments)]
 pub fn estimate_epsilon(
-    num_iters: usize,
+    blocks: &[LocalBlock],
     num_tries: usize,
     ploidy: usize,
-    all_frags: &Vec<Frag>,
-    block_len: usize,
+    choose_num_strains: bool,
+    frag_index: &FragIndex,
     initial_epsilon: f64,
-) -> f64 {
-    let mut rng = Pcg64::seed_from_u64(1);
-    let mut random_vec = Vec::new();
-
-    let mut epsilons = Vec::new();
-
-    for _ in 0..num_tries {
-        random_vec.push(rng.gen_range(0, num_iters));
-    }
-
-    for i in random_vec.into_iter() {
-        let part = generate_hap_block(
-            i * block_len,
-            (i + 1) * block_len,
-            ploidy,
-            all_frags,
-            initial_epsilon,
-        );
-        let block = utils_frags::hap_block_from_partition(&part);
-        let (binom_vec, _freq_vec) = get_partition_stats(&part, &block);
-        for (good, bad) in binom_vec {
-            if good + bad == 0 {
-                break;
-            }
-            let epsilon = (bad as f64) / ((good + bad) as f64);
-            epsilons.push(epsilon);
+    init: ClusterInit,
+    seed: u64,
+) -> Option<EpsilonEstimate> {
+    let mut rng = Pcg64::seed_from_u64(seed);
+    let mut sampled_blocks: Vec<LocalBlock> = blocks
+        .choose_multiple(&mut rng, num_tries)
+        .copied()
+        .collect();
+    sampled_blocks.sort_by_key(|block| block.start);
+
+    //(good bases, errors) of every haplotype of every block, leave-one-out and in sample.
+    type HapStats = ((usize, usize), (usize, usize));
+    let block_stats: Vec<Vec<HapStats>> = sampled_blocks
+        .par_iter()
+        .map(|block| {
+            let part = if choose_num_strains {
+                cluster_strains(block.start, block.end, ploidy, frag_index, initial_epsilon, init)
+            } else {
+                generate_hap_block(block.start, block.end, ploidy, frag_index, initial_epsilon, init)
+            };
+            let hap_block = utils_frags::hap_block_from_partition(&part);
+            let (binom_vec, _freq_vec) = get_partition_stats(&part, &hap_block);
+            get_leave_one_out_stats(&part)
+                .into_iter()
+                .zip(binom_vec)
+                .filter(|((good, bad), _)| good + bad > 0)
+                .collect()
+        })
+        .collect();
+
+    let in_sample_epsilons: Vec<Vec<f64>> = block_stats
+        .iter()
+        .map(|stats| {
+            stats
+                .iter()
+                .map(|(_, (good, bad))| *bad as f64 / (good + bad) as f64)
+                .filter(|epsilon| epsilon.is_finite())
+                .collect()
+        })
+        .collect();
+    if in_sample_epsilons.iter().all(|epsilons| epsilons.is_empty()) {
+        return None;
+    }
+    let epsilon = quantile_epsilon(in_sample_epsilons.concat());
+
+    let block_stats: Vec<Vec<(usize, usize)>> = block_stats
+        .into_iter()
+        .map(|stats| stats.into_iter().map(|stat| stat.0).collect())
+        .collect();
+    let loo_stats: Vec<(usize, usize)> = block_stats.iter().flatten().copied().collect();
+    let (read_epsilon, resp) = fit_error_mixture(&loo_stats);
+
+    let mut block_estimates = Vec::new();
+    let mut k = 0;
+    for (block, stats) in sampled_blocks.iter().zip(block_stats.iter()) {
+        let num_bases: usize = stats.iter().map(|stat| stat.0 + stat.1).sum();
+        let num_errors: usize = stats.iter().map(|stat| stat.1).sum();
+        let clean_bases: f64 = stats
+            .iter()
+            .zip(resp[k..k + stats.len()].iter())
+            .map(|(stat, r)| r * (stat.0 + stat.1) as f64)
+            .sum();
+        k += stats.len();
+        if num_bases == 0 {
+            continue;
         }
+        block_estimates.push(BlockEpsilon {
+            block: *block,
+            epsilon: num_errors as f64 / num_bases as f64,
+            num_bases,
+            clean_fraction: clean_bases / num_bases as f64,
+        });
     }
 
-    let percentile_index = epsilons.len() / 10;
+    //Bootstrap the blocks for the confidence interval.
+    let num_bootstraps = 200;
+    let mut bootstrap_epsilons = Vec::new();
+    for _ in 0..num_bootstraps {
+        let resampled: Vec<f64> = (0..in_sample_epsilons.len())
+            .flat_map(|_| in_sample_epsilons[rng.gen_range(0, in_sample_epsilons.len())].iter().copied())
+            .collect();
+        if resampled.is_empty() {
+            continue;
+        }
+        bootstrap_epsilons.push(quantile_epsilon(resampled));
+    }
+    bootstrap_epsilons.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let (lower, upper) = if bootstrap_epsilons.is_empty() {
+        (epsilon, epsilon)
+    } else {
+        let n = bootstrap_epsilons.len();
+        (bootstrap_epsilons[n * 25 / 1000], bootstrap_epsilons[(n * 975 / 1000).min(n - 1)])
+    };
 
-    epsilons.sort_by(|a, b| a.partial_cmp(&b).unwrap());
-    epsilons[percentile_index]
+    Some(EpsilonEstimate {
+        epsilon,
+        lower,
+        upper,
+        read_epsilon,
+        block_estimates,
+    })
 }
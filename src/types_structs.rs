@@ -2,15 +2,29 @@ use fxhash::{FxHashMap,FxHashSet};
 use std::hash::{Hash, Hasher};
 
 //Positions are inclusive 
-#[derive(Eq,Debug,Clone)]
+//source is the index of the input file (e.g. which BAM) the fragment came from, and epsilon is
+//the error rate of that source's sequencing technology if known. Fragments without their own
+//epsilon use the global error rate.
+//
+//read_names are the names of the reads merged into the fragment, e.g. all the reads of a barcode
+//or the mates of a pair. build_frag starts it with the fragment id, which is the read name for
+//fragments read from a fragment file.
+//
+//Alleles are stored compactly: positions is sorted and seqs/quals hold the allele and base
+//quality at the SNP with the same index. Use update_frag to add alleles so the vectors stay
+//sorted and aligned.
+#[derive(Debug,Clone)]
 pub struct Frag{
     pub id : String,
     pub counter_id : usize,
-    pub seq_dict : FxHashMap<usize,usize>,
-    pub qual_dict : FxHashMap<usize,u8>,
-    pub positions : FxHashSet<usize>,
+    pub positions : Vec<usize>,
+    pub seqs : Vec<u8>,
+    pub quals : Vec<u8>,
     pub first_position : usize,
     pub last_position : usize,
+    pub source : usize,
+    pub epsilon : Option<f64>,
+    pub read_names : Vec<String>,
 }
 
 impl Hash for Frag {
@@ -26,30 +40,162 @@ impl PartialEq for Frag {
     }
 }
 
+impl Eq for Frag {}
+
+impl Frag{
+    //Build a fragment from the old dictionary representation.
+    pub fn from_dicts(id : String, counter_id : usize, seq_dict : &FxHashMap<usize,usize>, qual_dict : &FxHashMap<usize,u8>) -> Frag{
+        let mut frag = build_frag(id, counter_id);
+        let mut sorted_positions : Vec<&usize> = seq_dict.keys().collect();
+        sorted_positions.sort();
+        for pos in sorted_positions{
+            update_frag(&mut frag, seq_dict[pos], qual_dict[pos], *pos);
+        }
+        frag
+    }
+
+    //Allele at a SNP position, if the fragment covers it.
+    pub fn allele(&self, pos : usize) -> Option<usize>{
+        match self.positions.binary_search(&pos){
+            Ok(i) => Some(self.seqs[i] as usize),
+            Err(_) => None,
+        }
+    }
+
+    pub fn qual(&self, pos : usize) -> Option<u8>{
+        match self.positions.binary_search(&pos){
+            Ok(i) => Some(self.quals[i]),
+            Err(_) => None,
+        }
+    }
+
+    //(position, allele) pairs in increasing order of position.
+    pub fn alleles(&self) -> impl Iterator<Item = (usize,usize)> + '_{
+        self.positions.iter().zip(self.seqs.iter()).map(|(pos,geno)| (*pos, *geno as usize))
+    }
+
+    //Adapters returning the alleles and qualities as dictionaries keyed by position.
+    pub fn seq_dict(&self) -> FxHashMap<usize,usize>{
+        self.alleles().collect()
+    }
+
+    pub fn qual_dict(&self) -> FxHashMap<usize,u8>{
+        self.positions.iter().copied().zip(self.quals.iter().copied()).collect()
+    }
+}
+
+//Allele counts of the reads of each haplotype at each SNP. For a partition of the reads of several
+//samples, utils_frags::hap_block_from_samples also fills sample_blocks with the counts of each
+//sample's reads alone, indexed by sample and then haplotype; it is empty otherwise.
 pub struct HapBlock{
     pub blocks: Vec<FxHashMap<usize,FxHashMap<usize,usize>>>,
+    pub sample_blocks: Vec<Vec<FxHashMap<usize,FxHashMap<usize,usize>>>>,
+}
+
+//A local haplotype block : the reads covering the SNPs in [start, end] (inclusive) are clustered
+//together before the blocks are linked.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub struct LocalBlock{
+    pub start : usize,
+    pub end : usize,
+}
+
+//A possible switch error between the haplotypes haps.0 and haps.1 : the reads of the two
+//haplotypes spanning the SNP position have gain fewer errors if the haplotypes are swapped from
+//position on. num_reads is the number of reads whose alleles switch haplotype near position.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct SwitchError{
+    pub position : usize,
+    pub haps : (usize,usize),
+    pub num_reads : usize,
+    pub gain : i64,
+}
+
+//Genotypes of the parents of an offspring keyed by SNP, and the number of homologs each parent
+//passes on. A parent without genotypes passes on any allele.
+#[derive(Debug,Clone,PartialEq)]
+pub struct ParentGenotypes<'a>{
+    pub genotypes : Vec<&'a FxHashMap<usize, FxHashMap<usize, usize>>>,
+    pub contributions : Vec<usize>,
+}
+
+//Epsilon for clustering with a 95% confidence interval [lower, upper]. The UPEM score compares
+//reads to haplotypes built from them, which have fewer errors than the reads, so epsilon is a low
+//quantile of the error rates measured that way. read_epsilon is the error rate of the reads
+//themselves, and block_estimates the estimate of each local block it was fit on.
+#[derive(Debug,Clone,PartialEq)]
+pub struct EpsilonEstimate{
+    pub epsilon : f64,
+    pub lower : f64,
+    pub upper : f64,
+    pub read_epsilon : f64,
+    pub block_estimates : Vec<BlockEpsilon>,
+}
+
+//Error rate of the reads of a clustered local block against their haplotypes. num_bases is the
+//number of alleles compared and clean_fraction the fraction of them on haplotypes fitting the
+//low error rate of the estimate; a low fraction means the block is noisy or badly clustered.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub struct BlockEpsilon{
+    pub block : LocalBlock,
+    pub epsilon : f64,
+    pub num_bases : usize,
+    pub clean_fraction : f64,
+}
+
+//A subgenome of an allopolyploid, e.g. the A, B or D genome of wheat, and the number of
+//homologous haplotypes it has. Reads from different subgenomes are never phased together.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Subgenome{
+    pub name : String,
+    pub ploidy : usize,
 }
 
 pub fn build_frag(id : String, counter_id : usize) -> Frag{
 
     let toret = Frag
     {
+        read_names : vec![id.clone()],
         id : id,
         counter_id : counter_id,
-        seq_dict : FxHashMap::default(),
-        qual_dict : FxHashMap::default(),
-        positions : FxHashSet::default(),
+        positions : Vec::new(),
+        seqs : Vec::new(),
+        quals : Vec::new(),
         first_position : usize::MAX,
         last_position : usize::MIN,
+        source : 0,
+        epsilon : None,
     };
 
     toret
 }
 
+//Add an allele to a fragment. If the position is already covered (e.g. overlapping mates or
+//several reads sharing a barcode) we keep the allele with the higher base quality.
 pub fn update_frag(frag : &mut Frag, geno : usize, qual : u8, snp_pos : usize ){
-    frag.seq_dict.insert(snp_pos,geno);
-    frag.qual_dict.insert(snp_pos,qual);
-    frag.positions.insert(snp_pos);
+    //Alleles are almost always added in increasing order, so check the end first.
+    let appended = match frag.positions.last(){
+        Some(last) => *last < snp_pos,
+        None => true,
+    };
+    let index = if appended{
+        frag.positions.len()
+    }
+    else{
+        match frag.positions.binary_search(&snp_pos){
+            Ok(i) => {
+                if frag.quals[i] < qual{
+                    frag.seqs[i] = geno as u8;
+                    frag.quals[i] = qual;
+                }
+                return;
+            }
+            Err(i) => i,
+        }
+    };
+    frag.positions.insert(index,snp_pos);
+    frag.seqs.insert(index,geno as u8);
+    frag.quals.insert(index,qual);
     if snp_pos < frag.first_position{
         frag.first_position = snp_pos;
     }
@@ -58,3 +204,99 @@ pub fn update_frag(frag : &mut Frag, geno : usize, qual : u8, snp_pos : usize ){
     }
 
 }
+
+//Largest span of a fragment before we treat it as an outlier, e.g. a chimeric read or a weird
+//mapping spanning several regions. Snps is a number of SNPs; Bases is a number of bases between
+//the first and last SNP of the fragment, given the genome position of every SNP.
+#[derive(Debug,Clone,Copy)]
+pub enum MaxFragSpan<'g>{
+    Snps(usize),
+    Bases(usize, &'g [usize]),
+}
+
+impl MaxFragSpan<'_>{
+    pub fn is_outlier(&self, frag : &Frag) -> bool{
+        match self{
+            MaxFragSpan::Snps(max_snps) => frag.last_position - frag.first_position > *max_snps,
+            MaxFragSpan::Bases(max_bases, snp_to_genome) => {
+                snp_to_genome[frag.last_position - 1] - snp_to_genome[frag.first_position - 1]
+                    > *max_bases
+            }
+        }
+    }
+}
+
+//Index over the fragments of a contig for finding the fragments overlapping an interval of SNPs.
+//Outlier fragments (see MaxFragSpan) are left out. Fragments are sorted by first position and
+//max_last is a segment tree over that order holding the largest last position of the fragments
+//under each node, with the leaves at max_last[size..]. A query takes the fragments starting at or
+//before the end of the interval by binary search and only descends into nodes with a fragment
+//ending at or after its start, so it costs O(log n) per fragment found, whatever the spans are.
+pub struct FragIndex<'a>{
+    frags : Vec<&'a Frag>,
+    first_positions : Vec<usize>,
+    max_last : Vec<usize>,
+    size : usize,
+}
+
+impl<'a> FragIndex<'a>{
+    pub fn new(all_frags : &'a [Frag], max_frag_span : MaxFragSpan) -> FragIndex<'a>{
+        let mut frags : Vec<&Frag> = all_frags
+            .iter()
+            .filter(|frag| !max_frag_span.is_outlier(frag))
+            .collect();
+        frags.sort_by_key(|frag| frag.first_position);
+        let first_positions = frags.iter().map(|frag| frag.first_position).collect();
+        let size = frags.len().next_power_of_two();
+        let mut max_last = vec![0; 2 * size];
+        for (i, frag) in frags.iter().enumerate(){
+            max_last[size + i] = frag.last_position;
+        }
+        for node in (1..size).rev(){
+            max_last[node] = max_last[2 * node].max(max_last[2 * node + 1]);
+        }
+
+        FragIndex{
+            frags,
+            first_positions,
+            max_last,
+            size,
+        }
+    }
+
+    //All indexed fragments covering at least one position in [start, end] (inclusive).
+    pub fn reads_in_interval(&self, start : usize, end : usize) -> FxHashSet<&'a Frag>{
+        let mut final_set = FxHashSet::default();
+        let stop = self.first_positions.partition_point(|x| *x <= end);
+        self.collect_overlapping(1, 0, self.size, stop, start, &mut final_set);
+        final_set
+    }
+
+    //Add the fragments under node, which covers the sorted fragments [node_start, node_end), that
+    //are before stop and end at or after start.
+    fn collect_overlapping(&self, node : usize, node_start : usize, node_end : usize, stop : usize, start : usize, final_set : &mut FxHashSet<&'a Frag>){
+        if node_start >= stop || self.max_last[node] < start{
+            return;
+        }
+        if node >= self.size{
+            final_set.insert(self.frags[node - self.size]);
+            return;
+        }
+        let mid = (node_start + node_end) / 2;
+        self.collect_overlapping(2 * node, node_start, mid, stop, start, final_set);
+        self.collect_overlapping(2 * node + 1, mid, node_end, stop, start, final_set);
+    }
+
+    //The indexed fragments, sorted by first position.
+    pub fn frags(&self) -> &[&'a Frag]{
+        &self.frags
+    }
+
+    pub fn len(&self) -> usize{
+        self.frags.len()
+    }
+
+    pub fn is_empty(&self) -> bool{
+        self.frags.is_empty()
+    }
+}
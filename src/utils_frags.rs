@@ -1,18 +1,33 @@
-use crate::types_structs::Frag;
+use crate::types_structs::{Frag, FragIndex};
 use crate::types_structs::HapBlock;
+use crate::types_structs::LocalBlock;
+use crate::types_structs::MaxFragSpan;
 use fxhash::{FxHashMap, FxHashSet};
 
 // Get the number # of different bases between the
-// two fragments
+// two fragments. Positions are sorted so we merge the two position lists.
 pub fn distance(r1: &Frag, r2: &Frag) -> (i32,i32) {
     let mut diff = 0;
     let mut same = 0;
+    if r1.last_position < r2.first_position || r2.last_position < r1.first_position {
+        return (same,diff);
+    }
 
-    for pos in r1.positions.intersection(&r2.positions) {
-        if r1.seq_dict.get(pos) == r2.seq_dict.get(pos) {
-            same += 1;
+    let mut i = 0;
+    let mut j = 0;
+    while i < r1.positions.len() && j < r2.positions.len() {
+        if r1.positions[i] < r2.positions[j] {
+            i += 1;
+        } else if r1.positions[i] > r2.positions[j] {
+            j += 1;
         } else {
-            diff += 1;
+            if r1.seqs[i] == r2.seqs[j] {
+                same += 1;
+            } else {
+                diff += 1;
+            }
+            i += 1;
+            j += 1;
         }
     }
 
@@ -25,20 +40,19 @@ pub fn distance_read_haplo(
 ) -> (usize, usize) {
     let mut diff = 0;
     let mut same = 0;
-    for pos in r1.positions.iter() {
-        if !hap.contains_key(pos) {
+    for (pos, frag_var) in r1.alleles() {
+        if !hap.contains_key(&pos) {
             continue;
         }
 
-        let frag_var = r1.seq_dict.get(pos).unwrap();
         let consensus_var = hap
-            .get(pos)
+            .get(&pos)
             .unwrap()
             .iter()
             .max_by_key(|entry| entry.1)
             .unwrap()
             .0;
-        if *frag_var == *consensus_var {
+        if frag_var == *consensus_var {
             same += 1;
         } else {
             diff += 1;
@@ -56,20 +70,19 @@ pub fn distance_read_haplo_range(
 ) -> (usize, usize) {
     let mut diff = 0;
     let mut same = 0;
-    for pos in r1.positions.iter() {
-        if !hap.contains_key(pos) || *pos > end || *pos < start {
+    for (pos, frag_var) in r1.alleles() {
+        if !hap.contains_key(&pos) || pos > end || pos < start {
             continue;
         }
 
-        let frag_var = r1.seq_dict.get(pos).unwrap();
         let consensus_var = hap
-            .get(pos)
+            .get(&pos)
             .unwrap()
             .iter()
             .max_by_key(|entry| entry.1)
             .unwrap()
             .0;
-        if *frag_var == *consensus_var {
+        if frag_var == *consensus_var {
             same += 1;
         } else {
             diff += 1;
@@ -121,29 +134,43 @@ pub fn check_overlap(r1: &Frag, r2: &Frag) -> bool {
     if r2.last_position < r1.first_position {
         return false;
     }
-    let t: Vec<_> = r1.positions.intersection(&r2.positions).collect();
-    if t.len() == 0 {
-        return false;
-    } else {
-        return true;
-    }
+    let (same, diff) = distance(r1, r2);
+    same + diff > 0
 }
 
-pub fn hap_block_from_partition(part: &Vec<FxHashSet<&Frag>>) -> HapBlock {
+pub fn hap_block_from_partition(part: &[FxHashSet<&Frag>]) -> HapBlock {
     let mut block_vec = Vec::new();
     for reads in part.iter() {
         let mut hap_map = FxHashMap::default();
         for frag in reads.iter() {
-            for pos in frag.positions.iter() {
-                let var_at_pos = frag.seq_dict.get(pos).unwrap();
-                let sites = hap_map.entry(*pos).or_insert(FxHashMap::default());
-                let site_counter = sites.entry(*var_at_pos).or_insert(0);
+            for (pos, var_at_pos) in frag.alleles() {
+                let sites = hap_map.entry(pos).or_insert(FxHashMap::default());
+                let site_counter = sites.entry(var_at_pos).or_insert(0);
                 *site_counter += 1;
             }
         }
         block_vec.push(hap_map);
     }
-    HapBlock { blocks: block_vec }
+    HapBlock { blocks: block_vec, sample_blocks: Vec::new() }
+}
+
+//Haplotype block of a partition of the reads of several samples, which also has the allele counts
+//of each sample's reads. source_samples gives the sample of the reads of each input file (see
+//Frag::source).
+pub fn hap_block_from_samples(part: &[FxHashSet<&Frag>], source_samples: &[usize], num_samples: usize) -> HapBlock {
+    let mut hap_block = hap_block_from_partition(part);
+    hap_block.sample_blocks = vec![vec![FxHashMap::default(); part.len()]; num_samples];
+    for (i, reads) in part.iter().enumerate() {
+        for frag in reads.iter() {
+            let hap_map = &mut hap_block.sample_blocks[source_samples[frag.source]][i];
+            for (pos, var_at_pos) in frag.alleles() {
+                let sites = hap_map.entry(pos).or_default();
+                let site_counter = sites.entry(var_at_pos).or_insert(0);
+                *site_counter += 1;
+            }
+        }
+    }
+    hap_block
 }
 
 pub fn get_avg_length(all_frags : &Vec<Frag>, quantile : f64) -> usize{
@@ -164,3 +191,251 @@ pub fn get_length_gn(all_frags : &Vec<Frag>) -> usize{
     }
     last_pos
 }
+
+//Local blocks of length_block SNPs each. Consecutive blocks share their boundary SNP and SNPs
+//after the last full block are left out.
+pub fn get_fixed_blocks(length_gn: usize, length_block: usize) -> Vec<LocalBlock> {
+    (0..length_gn / length_block)
+        .map(|x| LocalBlock {
+            start: x * length_block + 1,
+            end: std::cmp::min((x + 1) * length_block + 1, length_gn),
+        })
+        .collect()
+}
+
+//Number of indexed fragments covering each SNP position in 0..=length_gn.
+pub fn get_snp_coverage(frag_index: &FragIndex, length_gn: usize) -> Vec<usize> {
+    let mut coverage_diff = vec![0i64; length_gn + 2];
+    for frag in frag_index.frags().iter() {
+        if frag.first_position > length_gn {
+            continue;
+        }
+        coverage_diff[frag.first_position] += 1;
+        coverage_diff[frag.last_position.min(length_gn) + 1] -= 1;
+    }
+
+    let mut coverage = Vec::with_capacity(length_gn + 1);
+    let mut running = 0;
+    for diff in coverage_diff.iter().take(length_gn + 1) {
+        running += diff;
+        coverage.push(running as usize);
+    }
+    coverage
+}
+
+//Choose local blocks from the reads instead of cutting the genome into equal pieces. The length
+//of each block is the block_len_quant quantile of the SNP spans of the reads starting near the
+//start of the block, so blocks are shorter where reads cover few SNPs (e.g. SNP dense regions) and longer
+//otherwise. The block then ends at the SNP within half a block length of this target which the
+//fewest reads cover, so that few reads are split between blocks and coverage dips become block
+//boundaries. Consecutive blocks share their boundary SNP, and the blocks cover every SNP.
+pub fn get_adaptive_blocks(
+    frag_index: &FragIndex,
+    length_gn: usize,
+    length_block: usize,
+    block_len_quant: f64,
+) -> Vec<LocalBlock> {
+    let mut blocks = Vec::new();
+    if length_gn == 0 {
+        return blocks;
+    }
+
+    let coverage = get_snp_coverage(frag_index, length_gn);
+    let min_length = std::cmp::max(length_block / 2, 1);
+    let max_length = std::cmp::max(length_block * 2, 1);
+    let mut start = 1;
+
+    loop {
+        //Long reads are more likely to overlap the block, so only take the reads starting in it.
+        let mut spans: Vec<usize> = frag_index
+            .reads_in_interval(start, start + length_block)
+            .iter()
+            .filter(|frag| frag.first_position >= start)
+            .map(|frag| frag.last_position - frag.first_position)
+            .collect();
+        let target = if spans.is_empty() {
+            length_block
+        } else {
+            spans.sort_unstable();
+            let index = ((spans.len() as f64 * block_len_quant) as usize).min(spans.len() - 1);
+            spans[index].max(min_length).min(max_length)
+        };
+
+        if start + target + target / 2 >= length_gn {
+            blocks.push(LocalBlock {
+                start,
+                end: length_gn,
+            });
+            break;
+        }
+
+        //Cut at the least covered SNP, preferring the one closest to the target on ties.
+        let target_end = start + target;
+        let distance = |end: usize| (end as i64 - target_end as i64).abs();
+        let first_end = start + std::cmp::max(target / 2, 1);
+        //Leave at least half a block for the last block.
+        let last_end = std::cmp::min(target_end + target / 2, length_gn - target / 2);
+        let mut best_end = first_end;
+        for end in first_end..last_end + 1 {
+            if coverage[end] < coverage[best_end]
+                || (coverage[end] == coverage[best_end] && distance(end) < distance(best_end))
+            {
+                best_end = end;
+            }
+        }
+
+        blocks.push(LocalBlock {
+            start,
+            end: best_end,
+        });
+        start = best_end;
+    }
+
+    blocks
+}
+
+//Make consecutive blocks overlap by extending every block but the last by overlap SNPs.
+pub fn extend_blocks(blocks: &mut [LocalBlock], overlap: usize, length_gn: usize) {
+    let num_blocks = blocks.len();
+    for block in blocks.iter_mut().take(num_blocks.saturating_sub(1)) {
+        block.end = std::cmp::min(block.end + overlap, length_gn);
+    }
+}
+
+//The error rate of a fragment : its sequencing technology's epsilon if known, otherwise the
+//global epsilon.
+pub fn get_frag_epsilon(frag: &Frag, epsilon: f64) -> f64 {
+    frag.epsilon.unwrap_or(epsilon)
+}
+
+//Probability that two fragments from the same haplotype disagree at a site. This is
+//2e(1-e) when both fragments have the same error rate e.
+pub fn get_pair_epsilon(r1: &Frag, r2: &Frag, epsilon: f64) -> f64 {
+    let e1 = get_frag_epsilon(r1, epsilon);
+    let e2 = get_frag_epsilon(r2, epsilon);
+    e1 * (1.0 - e2) + e2 * (1.0 - e1)
+}
+
+//For each haplotype in a partition, get the error rate of its reads weighted by how many
+//bases each read covers on the haplotype. Haplotypes with reads from different technologies
+//get an error rate in between those of the technologies.
+pub fn get_partition_epsilons(
+    partition: &[FxHashSet<&Frag>],
+    hap_block: &HapBlock,
+    epsilon: f64,
+) -> Vec<f64> {
+    let mut epsilons = Vec::new();
+    for (i, reads) in partition.iter().enumerate() {
+        let mut total_bases = 0.0;
+        let mut weighted_epsilon = 0.0;
+        for frag in reads.iter() {
+            let (same, diff) = distance_read_haplo(frag, &hap_block.blocks[i]);
+            let bases = (same + diff) as f64;
+            total_bases += bases;
+            weighted_epsilon += bases * get_frag_epsilon(frag, epsilon);
+        }
+        if total_bases > 0.0 {
+            epsilons.push(weighted_epsilon / total_bases);
+        } else {
+            epsilons.push(epsilon);
+        }
+    }
+    epsilons
+}
+
+//Get the largest span a fragment may have before we treat it as an outlier, e.g. a chimeric read
+//or a weird mapping spanning several regions. Spans are measured in bases between the first and
+//last SNP of each fragment, so the cutoff follows the read length distribution and reads in SNP
+//dense regions are not called outliers. Without genome positions (fragment files) the spans are
+//measured in SNPs instead.
+//
+//Read lengths are heavily right skewed, so a fence q75 + iqr_factor * IQR on the raw spans cuts
+//into the tail of ordinary long reads. We take the fence on log spans instead, which only calls
+//fragments outliers when they are many times longer than typical reads.
+pub fn get_max_frag_span<'g>(
+    all_frags: &[Frag],
+    snp_to_genome: &'g [usize],
+    iqr_factor: f64,
+) -> MaxFragSpan<'g> {
+    if all_frags.is_empty() {
+        return MaxFragSpan::Snps(usize::MAX);
+    }
+
+    let use_genome = snp_to_genome.len() > 1
+        && all_frags
+            .iter()
+            .all(|frag| frag.last_position <= snp_to_genome.len());
+
+    let mut log_spans: Vec<f64> = all_frags
+        .iter()
+        .map(|frag| {
+            let span = if use_genome {
+                snp_to_genome[frag.last_position - 1] - snp_to_genome[frag.first_position - 1]
+            } else {
+                frag.last_position - frag.first_position
+            };
+            (span as f64 + 1.0).ln()
+        })
+        .collect();
+    log_spans.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let q25 = log_spans[log_spans.len() / 4];
+    let q75 = log_spans[log_spans.len() * 3 / 4];
+    //With (nearly) equal length reads the IQR is 0, so never call a fragment an outlier unless
+    //it is at least twice the 75% quantile.
+    let mut fence = q75 + iqr_factor * (q75 - q25);
+    if fence < q75 + 2.0_f64.ln() {
+        fence = q75 + 2.0_f64.ln();
+    }
+    let max_span = (fence.exp() - 1.0).ceil() as usize;
+
+    if use_genome {
+        MaxFragSpan::Bases(max_span, snp_to_genome)
+    } else {
+        MaxFragSpan::Snps(max_span)
+    }
+}
+
+//Number of outlier fragments spanning more than max_frag_span.
+pub fn count_long_frags(all_frags: &[Frag], max_frag_span: MaxFragSpan) -> usize {
+    all_frags
+        .iter()
+        .filter(|frag| max_frag_span.is_outlier(frag))
+        .count()
+}
+
+//Ploidy of the genotypes of a contig (position -> allele -> count), the largest number of alleles
+//called at a position, or None without genotypes.
+pub fn get_genotype_ploidy(genotype_dict: &FxHashMap<usize, FxHashMap<usize, usize>>) -> Option<usize> {
+    genotype_dict
+        .values()
+        .map(|genotype| genotype.values().sum())
+        .max()
+}
+
+//Split the fragments of a contig by the subgenome of their reads, given as the index of a
+//subgenome for each read name. A fragment takes the subgenome of the reads merged into it (see
+//Frag::read_names) that have one; fragments whose reads have no subgenome or disagree are returned
+//separately and not phased. Returns the fragments of each subgenome and those other fragments.
+pub fn split_frags_by_subgenome(
+    frags: Vec<Frag>,
+    read_subgenomes: &FxHashMap<String, usize>,
+    num_subgenomes: usize,
+) -> (Vec<Vec<Frag>>, Vec<Frag>) {
+    let mut subgenome_frags = vec![Vec::new(); num_subgenomes];
+    let mut unknown_frags = Vec::new();
+    for frag in frags.into_iter() {
+        let mut subgenomes = frag
+            .read_names
+            .iter()
+            .filter_map(|read_name| read_subgenomes.get(read_name));
+        let subgenome = match subgenomes.next() {
+            Some(first) if subgenomes.all(|x| x == first) => Some(*first),
+            _ => None,
+        };
+        match subgenome {
+            Some(subgenome) => subgenome_frags[subgenome].push(frag),
+            None => unknown_frags.push(frag),
+        }
+    }
+    (subgenome_frags, unknown_frags)
+}
@@ -1,16 +1,14 @@
 use crate::local_clustering;
-use std::io::LineWriter;
-use std::cell::RefCell;
-use crate::types_structs::Frag;
+use crate::types_structs::{Frag, FragIndex};
 use crate::types_structs::HapBlock;
+use crate::types_structs::LocalBlock;
+use crate::types_structs::ParentGenotypes;
+use crate::types_structs::SwitchError;
 use crate::utils_frags;
 use fxhash::{FxHashMap, FxHashSet};
-use permute::permute;
 use rayon::prelude::*;
 use std::mem;
 use std::sync::Mutex;
-use std::fs::File;
-use std::io::Write;
 
 ///This function takes polishes a haplotype block by using genotyping information.
 ///The algorithm used is simple; we sort the calls for what a haplotype should be
@@ -20,6 +18,30 @@ pub fn polish_using_vcf(
     genotype_dict: &FxHashMap<usize, FxHashMap<usize, usize>>,
     hap_block: &HapBlock,
     positions_to_polish: &Vec<usize>,
+) -> HapBlock {
+    polish_block(genotype_dict, hap_block, positions_to_polish, None)
+}
+
+///Polish a haplotype block as in polish_using_vcf, but each haplotype is only given alleles its
+///parent can pass on : the alleles of the haplotypes of a parent at a SNP must be among the alleles
+///of the parent's genotype there. hap_parents is the parent of each haplotype (see
+///assign_haplotypes_to_parents). SNPs where no calls fit the parents are polished as in
+///polish_using_vcf.
+pub fn polish_using_parents(
+    genotype_dict: &FxHashMap<usize, FxHashMap<usize, usize>>,
+    hap_block: &HapBlock,
+    positions_to_polish: &Vec<usize>,
+    parents: &ParentGenotypes,
+    hap_parents: &[usize],
+) -> HapBlock {
+    polish_block(genotype_dict, hap_block, positions_to_polish, Some((parents, hap_parents)))
+}
+
+fn polish_block(
+    genotype_dict: &FxHashMap<usize, FxHashMap<usize, usize>>,
+    hap_block: &HapBlock,
+    positions_to_polish: &Vec<usize>,
+    parents: Option<(&ParentGenotypes, &[usize])>,
 ) -> HapBlock {
     let ploidy = hap_block.blocks.len();
     let mut polished_block = Vec::new();
@@ -63,11 +85,6 @@ pub fn polish_using_vcf(
 
         best_calls_vec.sort_by(|a, b| b.2.partial_cmp(&a.2).unwrap());
         //        println!("{:?}",best_calls_vec);
-        let mut vec_pos_maps = Vec::new();
-        let mut genotype_counter = FxHashMap::default();
-        for _i in 0..ploidy {
-            vec_pos_maps.push(usize::MAX);
-        }
 
         //Very ambiguous calls, dont' do anything
         
@@ -77,25 +94,15 @@ pub fn polish_using_vcf(
             continue;
         }
 
-        for call in best_calls_vec.iter() {
-            let ith_hap = call.0 as usize;
-
-            //Already finished calling for this haplotype
-            if vec_pos_maps[ith_hap] != usize::MAX {
-                continue;
-            }
-
-            let allele = call.1 as usize;
-            let geno_count = genotype_counter.entry(allele).or_insert(0);
-            let geno_count_truth = genotype_dict.get(pos).unwrap().get(&allele).unwrap_or(&0);
-            if (*geno_count_truth as i32) - ((*geno_count) as i32 + 1) >= 0 {
-                vec_pos_maps[ith_hap] = allele;
-                *geno_count += 1;
-                //                println!("{:?} vec_pos_maps, {:?} geno_count",&vec_pos_maps,genotype_counter);
-                if genotype_counter == *genotype_dict.get(pos).unwrap() {
-                    break;
-                }
-            }
+        let genotype = genotype_dict.get(pos).unwrap();
+        let mut vec_pos_maps = vec![usize::MAX; ploidy];
+        if let Some((parents, hap_parents)) = parents {
+            let parent_genotypes: Vec<Option<&FxHashMap<usize, usize>>> =
+                parents.genotypes.iter().map(|genotypes| genotypes.get(pos)).collect();
+            vec_pos_maps = call_alleles(&best_calls_vec, ploidy, genotype, &parent_genotypes, hap_parents);
+        }
+        if vec_pos_maps.contains(&usize::MAX) {
+            vec_pos_maps = call_alleles(&best_calls_vec, ploidy, genotype, &[], &[]);
         }
 
         for i in 0..ploidy {
@@ -109,67 +116,280 @@ pub fn polish_using_vcf(
 
     HapBlock {
         blocks: polished_block,
+        sample_blocks: Vec::new(),
     }
 }
 
+//Call the allele of each haplotype at a SNP, taking the calls [ith_hap,allele_call,error] from
+//best to worst as long as the genotype has copies of the allele left. With parent_genotypes, a
+//haplotype's allele also needs a copy left in the genotype of its parent hap_parents[i]; a parent
+//without a genotype at the SNP passes on any allele. Haplotypes without a call are usize::MAX.
+fn call_alleles(
+    best_calls_vec: &[(i32, i32, f64)],
+    ploidy: usize,
+    genotype: &FxHashMap<usize, usize>,
+    parent_genotypes: &[Option<&FxHashMap<usize, usize>>],
+    hap_parents: &[usize],
+) -> Vec<usize> {
+    let mut vec_pos_maps = vec![usize::MAX; ploidy];
+    let mut genotype_counter = FxHashMap::default();
+    let mut parent_counters = vec![FxHashMap::default(); parent_genotypes.len()];
+    for call in best_calls_vec.iter() {
+        let ith_hap = call.0 as usize;
+
+        //Already finished calling for this haplotype
+        if vec_pos_maps[ith_hap] != usize::MAX {
+            continue;
+        }
+
+        let allele = call.1 as usize;
+        if let Some(Some(parent_genotype)) = hap_parents.get(ith_hap).map(|p| parent_genotypes[*p]) {
+            let parent_count = parent_counters[hap_parents[ith_hap]].entry(allele).or_insert(0);
+            if *parent_count >= *parent_genotype.get(&allele).unwrap_or(&0) {
+                continue;
+            }
+        }
+        let geno_count = genotype_counter.entry(allele).or_insert(0);
+        let geno_count_truth = genotype.get(&allele).unwrap_or(&0);
+        if (*geno_count_truth as i32) - ((*geno_count) as i32 + 1) >= 0 {
+            vec_pos_maps[ith_hap] = allele;
+            *geno_count += 1;
+            if let Some(p) = hap_parents.get(ith_hap) {
+                *parent_counters[*p].entry(allele).or_insert(0) += 1;
+            }
+            if genotype_counter == *genotype {
+                break;
+            }
+        }
+    }
+    vec_pos_maps
+}
 
-//Link two partitions by best MEC score permutation. This doesn't help much
-//on the simulated datasets. 
-fn get_best_perms_mec(part1: &Vec<FxHashSet<&Frag>>, part2: &Vec<FxHashSet<&Frag>>) -> Vec<Vec<usize>> {
-    let ploidy = part1.len();
-    let mut best_perms = Vec::new();
-    let rangevec: Vec<usize> = (0..ploidy).collect();
-    let perms = permute(rangevec);
-    for perm in perms {
-        let mut test_part = Vec::new();
-        for i in 0..ploidy {
-            let mut new_set = FxHashSet::default();
-            let j = perm[i];
 
-            let set1 = &part1[i];
-            let set2 = &part2[j];
+//How the local blocks are linked into haplotypes : greedily by shared reads (link_blocks_greedy,
+//or link_blocks_overlap for overlapping blocks), by the best permutation between each pair of
+//consecutive blocks (link_blocks), or by a beam search on the MEC score (link_blocks_heur).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockLinker {
+    Greedy,
+    Pairwise,
+    Beam,
+}
 
-            for read in set1.iter(){
-                new_set.insert(*read);
+//Parse the linker given on the command line : greedy, pairwise or beam.
+pub fn parse_linker(linker: &str) -> Option<BlockLinker> {
+    match linker {
+        "greedy" => Some(BlockLinker::Greedy),
+        "pairwise" => Some(BlockLinker::Pairwise),
+        "beam" => Some(BlockLinker::Beam),
+        _ => None,
+    }
+}
+
+//What is done with the switch errors found after linking (see find_switch_errors) : swap the
+//haplotypes back at each switch, or end the phased block at each switch.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SwitchCorrection {
+    Flip,
+    Split,
+}
+
+//Parse the switch correction given on the command line : flip or split.
+pub fn parse_switch_correction(correction: &str) -> Option<SwitchCorrection> {
+    match correction {
+        "flip" => Some(SwitchCorrection::Flip),
+        "split" => Some(SwitchCorrection::Split),
+        _ => None,
+    }
+}
+
+//Solve the assignment problem for a square weight matrix with the Hungarian algorithm in
+//O(n^3) time : returns the permutation perm maximizing the sum of weights[i][perm[i]]. Among the
+//best permutations, the one fixing the most indices is returned so that unlinked blocks keep
+//their order. Entries of None can not be used; returns None if no permutation avoids them.
+fn solve_assignment(weights: &[Vec<Option<i64>>]) -> Option<Vec<usize>> {
+    let n = weights.len();
+    if n == 0 {
+        return Some(vec![]);
+    }
+
+    //Minimize costs instead. Scaling by n + 1 and subtracting one on the diagonal breaks ties
+    //towards the identity without changing which permutations are optimal.
+    let allowed_cost = |i: usize, j: usize, weight: i64| -> i64 {
+        -(weight * (n as i64 + 1) + (i == j) as i64)
+    };
+    let allowed_costs: Vec<i64> = (0..n)
+        .flat_map(|i| (0..n).map(move |j| (i, j)))
+        .filter_map(|(i, j)| weights[i][j].map(|weight| allowed_cost(i, j, weight)))
+        .collect();
+    if allowed_costs.is_empty() {
+        return None;
+    }
+    //A forbidden entry costs more than the range of all n allowed costs, so a permutation using
+    //one always costs more than any permutation using only allowed entries, whatever the signs
+    //of the weights.
+    let min_cost = *allowed_costs.iter().min().unwrap();
+    let max_cost = *allowed_costs.iter().max().unwrap();
+    let forbidden_cost = max_cost + (n as i64) * (max_cost - min_cost) + 1;
+    let cost = |i: usize, j: usize| -> i64 {
+        match weights[i][j] {
+            Some(weight) => allowed_cost(i, j, weight),
+            None => forbidden_cost,
+        }
+    };
+
+    //Potentials u (rows) and v (columns); row_of_col[j] is the row matched to column j. Index 0
+    //is a dummy column used while augmenting.
+    let mut u = vec![0; n + 1];
+    let mut v = vec![0; n + 1];
+    let mut row_of_col = vec![0; n + 1];
+    let mut way = vec![0; n + 1];
+    for i in 1..n + 1 {
+        row_of_col[0] = i;
+        let mut j0 = 0;
+        let mut min_v = vec![i64::MAX; n + 1];
+        let mut used = vec![false; n + 1];
+        loop {
+            used[j0] = true;
+            let i0 = row_of_col[j0];
+            let mut delta = i64::MAX;
+            let mut j1 = 0;
+            for j in 1..n + 1 {
+                if !used[j] {
+                    let reduced = cost(i0 - 1, j - 1) - u[i0] - v[j];
+                    if reduced < min_v[j] {
+                        min_v[j] = reduced;
+                        way[j] = j0;
+                    }
+                    if min_v[j] < delta {
+                        delta = min_v[j];
+                        j1 = j;
+                    }
+                }
             }
-            for read in set2.iter(){
-                new_set.insert(*read);
+            for j in 0..n + 1 {
+                if used[j] {
+                    u[row_of_col[j]] += delta;
+                    v[j] -= delta;
+                } else {
+                    min_v[j] -= delta;
+                }
+            }
+            j0 = j1;
+            if row_of_col[j0] == 0 {
+                break;
+            }
+        }
+        loop {
+            let j1 = way[j0];
+            row_of_col[j0] = row_of_col[j1];
+            j0 = j1;
+            if j0 == 0 {
+                break;
             }
-
-            test_part.push(new_set);
         }
-        let score = get_mec_from_part(&test_part);
-        best_perms.push((-1*score,perm));
     }
-    best_perms.sort_by(|a,b| b.0.cmp(&a.0));
-    let best_perms : Vec<Vec<usize>> = best_perms.into_iter().map(|x| x.1).collect();
-    best_perms
+
+    let mut perm = vec![0; n];
+    for j in 1..n + 1 {
+        perm[row_of_col[j] - 1] = j - 1;
+    }
+    if (0..n).any(|i| weights[i][perm[i]].is_none()) {
+        return None;
+    }
+    Some(perm)
 }
 
-//Link two partitions by which permutation gives the most amount ofintersections between the sets.
-fn get_best_perms(part1: &Vec<FxHashSet<&Frag>>, part2: &Vec<FxHashSet<&Frag>>) -> Vec<Vec<usize>> {
-    let ploidy = part1.len();
-    let mut best_perms = Vec::new();
-    let rangevec: Vec<usize> = (0..ploidy).collect();
-    let perms = permute(rangevec);
-    for perm in perms {
-        let mut score = 0;
-        for i in 0..ploidy {
-            let j = perm[i];
+///The permutation perm maximizing the sum of weights[i][perm[i]] for a square matrix. Ties are
+///broken towards the permutation fixing the most indices.
+pub fn max_weight_assignment(weights: &[Vec<i64>]) -> Vec<usize> {
+    let weights: Vec<Vec<Option<i64>>> = weights
+        .iter()
+        .map(|row| row.iter().map(|weight| Some(*weight)).collect())
+        .collect();
+    solve_assignment(&weights).unwrap()
+}
 
-            let set1 = &part1[i];
-            let set2 = &part2[j];
+///The num_perms permutations with the largest total weight (see max_weight_assignment), best
+///first, using Murty's algorithm : after a permutation is taken, the remaining permutations are
+///split into subproblems which each force a prefix of it and forbid the next index, so only
+///O(num_perms * n) assignment problems are solved instead of enumerating all n! permutations.
+pub fn k_best_assignments(weights: &[Vec<i64>], num_perms: usize) -> Vec<Vec<usize>> {
+    let n = weights.len();
+    let total_weight = |perm: &Vec<usize>| -> i64 { (0..n).map(|i| weights[i][perm[i]]).sum() };
+    let identity_count = |perm: &Vec<usize>| -> usize { (0..n).filter(|i| perm[*i] == *i).count() };
 
-            let int_vec: Vec<&Frag> = set1.intersection(set2).copied().collect();
-            score += int_vec.len();
+    //Subproblems are (permutation, forced assignments, forbidden assignments).
+    let mut subproblems = Vec::new();
+    let best_perm = max_weight_assignment(weights);
+    subproblems.push((best_perm, vec![], vec![]));
+
+    let mut best_perms = Vec::new();
+    while best_perms.len() < num_perms && !subproblems.is_empty() {
+        //Take the subproblem with the best permutation, ties as in max_weight_assignment.
+        let best_index = (0..subproblems.len())
+            .max_by_key(|k| {
+                let perm = &subproblems[*k].0;
+                (total_weight(perm), identity_count(perm), std::cmp::Reverse(*k))
+            })
+            .unwrap();
+        let (perm, forced, forbidden): (Vec<usize>, Vec<usize>, Vec<(usize, usize)>) =
+            subproblems.swap_remove(best_index);
+
+        //Rows before forced.len() are forced to perm; split on the remaining rows.
+        for i in forced.len()..n.saturating_sub(1) {
+            let mut new_forbidden = forbidden.clone();
+            new_forbidden.push((i, perm[i]));
+            let new_forced: Vec<usize> = perm[..i].to_vec();
+
+            let mut restricted: Vec<Vec<Option<i64>>> = weights
+                .iter()
+                .map(|row| row.iter().map(|weight| Some(*weight)).collect())
+                .collect();
+            for (row, col) in new_forced.iter().enumerate() {
+                for (k, weight) in restricted[row].iter_mut().enumerate() {
+                    if k != *col {
+                        *weight = None;
+                    }
+                }
+                for (k, other_row) in restricted.iter_mut().enumerate() {
+                    if k != row {
+                        other_row[*col] = None;
+                    }
+                }
+            }
+            for (row, col) in new_forbidden.iter() {
+                restricted[*row][*col] = None;
+            }
+
+            if let Some(new_perm) = solve_assignment(&restricted) {
+                subproblems.push((new_perm, new_forced, new_forbidden));
+            }
         }
-        best_perms.push((score,perm));
+        best_perms.push(perm);
     }
-    best_perms.sort_by(|a,b| b.0.cmp(&a.0));
-    let best_perms : Vec<Vec<usize>> = best_perms.into_iter().map(|x| x.1).collect();
+
     best_perms
 }
 
+//Link two partitions by which permutations give the most amount of intersections between the sets.
+fn get_best_perms(part1: &Vec<FxHashSet<&Frag>>, part2: &Vec<FxHashSet<&Frag>>, num_perms : usize) -> Vec<Vec<usize>> {
+    k_best_assignments(&intersection_weights(part1, part2), num_perms)
+}
+
+//Number of reads shared by each pair of haplotypes of two partitions.
+fn intersection_weights(part1: &[FxHashSet<&Frag>], part2: &[FxHashSet<&Frag>]) -> Vec<Vec<i64>> {
+    part1
+        .iter()
+        .map(|set1| {
+            part2
+                .iter()
+                .map(|set2| set2.iter().filter(|read| set1.contains(*read)).count() as i64)
+                .collect()
+        })
+        .collect()
+}
+
 ///Link all partitions in a vector using get_best_perm.
 pub fn link_blocks<'a>(all_parts: &Vec<Vec<FxHashSet<&'a Frag>>>) -> Vec<FxHashSet<&'a Frag>> {
     //Multithreaded version -- not super useful unless ploidy > 6. Might as well though.
@@ -183,8 +403,7 @@ pub fn link_blocks<'a>(all_parts: &Vec<Vec<FxHashSet<&'a Frag>>>) -> Vec<FxHashS
         .collect::<Vec<usize>>()
         .into_par_iter()
         .for_each(|i| {
-//            let best_perm = get_best_perms_mec(&all_parts[i - 1], &all_parts[i])[0].clone();
-            let best_perm = get_best_perms(&all_parts[i - 1], &all_parts[i])[0].clone();
+            let best_perm = get_best_perms(&all_parts[i - 1], &all_parts[i], 1)[0].clone();
             let mut locked_perm = perm_vector.lock().unwrap();
             locked_perm.push((i, best_perm));
         });
@@ -227,20 +446,16 @@ pub fn link_blocks<'a>(all_parts: &Vec<Vec<FxHashSet<&'a Frag>>>) -> Vec<FxHashS
 }
 
 
-
-fn clone_block_range (hap_block : &HapBlock, start : usize,end : usize) -> HapBlock{
-    let mut new_hap_block = HapBlock{blocks : Vec::new()};
-    let ploidy = hap_block.blocks.len();
-    for _i in 0..ploidy{
-        new_hap_block.blocks.push(FxHashMap::default());
-    }
-    for pos in start..end+1{
-        for j in 0..ploidy{
-            let hap = &hap_block.blocks[j];
-            if hap.contains_key(&pos){
-                new_hap_block.blocks[j].insert(pos,hap.get(&pos).unwrap().clone());
-            }
-        }
+//Copy of the haplotypes of a block on the SNPs in [start, end].
+pub fn clone_block_range (hap_block : &HapBlock, start : usize,end : usize) -> HapBlock{
+    let mut new_hap_block = HapBlock{blocks : Vec::new(), sample_blocks : Vec::new()};
+    for hap in hap_block.blocks.iter(){
+        let new_hap = hap
+            .iter()
+            .filter(|(pos, _)| **pos >= start && **pos <= end)
+            .map(|(pos, site_map)| (*pos, site_map.clone()))
+            .collect();
+        new_hap_block.blocks.push(new_hap);
     }
 
     return new_hap_block;
@@ -267,48 +482,33 @@ fn get_mec_from_part(part : &Vec<FxHashSet<&Frag>>) -> i32{
     mec_score as i32
 }
 
-fn get_mec_positions_hap(new_reads : &FxHashSet<&Frag>, hap_block : &mut HapBlock, pos_sort_vec : &Vec<usize>, l : usize) -> usize {
-    
-    let hap = &mut hap_block.blocks[l];
-
-    //Add reads to hap_block
+//Increase in the MEC score of a haplotype when new reads are added to it. Only the positions
+//covered by the new reads change.
+fn get_mec_increase_hap(new_reads : &[&Frag], hap : &FxHashMap<usize,FxHashMap<usize,usize>>) -> usize {
+    let mut new_counts : FxHashMap<usize,FxHashMap<usize,usize>> = FxHashMap::default();
     for read in new_reads.iter(){
-        for pos in read.positions.iter(){
-            let var_at_pos = read.seq_dict.get(pos).unwrap();
-            let sites = hap.entry(*pos).or_insert(FxHashMap::default());
-            let site_counter = sites.entry(*var_at_pos).or_insert(0);
-            *site_counter += 1;
-        }
-    }
-
-    //Compute new MEC for the new reads
-    let mut err = 0;
-    for pos in pos_sort_vec[0]..pos_sort_vec[pos_sort_vec.len()-1]{
-        if hap.contains_key(&pos){
-            let mut total_sites = 0;
-            let mut sites_max_var = 0;
-            let site_map = hap.get(&pos).unwrap();
-            for value in site_map.values(){
-                if *value > sites_max_var{
-                    sites_max_var = *value;
-                }
-                total_sites += *value;
-            }
-            err += total_sites - sites_max_var;
+        for (pos, var_at_pos) in read.alleles(){
+            *new_counts.entry(pos).or_default().entry(var_at_pos).or_insert(0) += 1;
         }
     }
 
-    //Remove reads from hap_block
-    for read in new_reads.iter(){
-        for pos in read.positions.iter(){
-            let var_at_pos = read.seq_dict.get(pos).unwrap();
-            let sites = hap.entry(*pos).or_insert(FxHashMap::default());
-            let site_counter = sites.entry(*var_at_pos).or_insert(0);
-            *site_counter -= 1;
+    let mec_at_site = |site_map : &FxHashMap<usize,usize>| {
+        let total_sites : usize = site_map.values().sum();
+        total_sites - site_map.values().max().unwrap_or(&0)
+    };
+
+    let mut increase = 0;
+    let emptydict = FxHashMap::default();
+    for (pos, added) in new_counts.iter(){
+        let site_map = hap.get(pos).unwrap_or(&emptydict);
+        let mut combined = site_map.clone();
+        for (var, count) in added.iter(){
+            *combined.entry(*var).or_insert(0) += count;
         }
+        increase += mec_at_site(&combined) - mec_at_site(site_map);
     }
 
-    return err;
+    increase
 }
 
 fn get_iqr(all_scores: &Vec<f64>, factor: f64) -> f64 {
@@ -348,7 +548,7 @@ fn fill_left_block<'a>(left_block: &mut Vec<FxHashSet<&'a Frag>>, reads_interval
                 let binomial_dist = -1.0 * local_clustering::stable_binom_cdf_p_rev(
                     (same + mec_dist) as usize,
                     mec_dist as usize,
-                    2.0 * epsilon * (1.0-epsilon),
+                    utils_frags::get_pair_epsilon(frag_in_set, frag, epsilon),
                     100.0
                 );
                     
@@ -367,15 +567,20 @@ fn fill_left_block<'a>(left_block: &mut Vec<FxHashSet<&'a Frag>>, reads_interval
     }
 }
 
+//Fill in blocks with outlying (bad) scores from the block to their left. The filled in block
+//is merged into the block to its left, so blocks and all_scores are updated to match the
+//returned partitions.
 pub fn replace_with_filled_blocks<'a>(
-    all_scores: &Vec<f64>,
+    all_scores: &mut Vec<f64>,
     mut all_parts: Vec<Vec<FxHashSet<&'a Frag>>>,
     factor: f64,
-    length_of_block: usize,
-    all_frags: &'a Vec<Frag>,
-    epsilon : f64
+    blocks: &mut Vec<LocalBlock>,
+    frag_index: &FragIndex<'a>,
+    epsilon : f64,
 ) -> Vec<Vec<FxHashSet<&'a Frag>>> {
     let mut corrected_vec = Vec::new();
+    let mut corrected_blocks = vec![blocks[0]];
+    let mut corrected_scores = vec![all_scores[0]];
     let outlier_score = get_iqr(all_scores, factor);
 
     //Assume the leftmost block is good. Not a great assumption but
@@ -389,165 +594,134 @@ pub fn replace_with_filled_blocks<'a>(
         if score > outlier_score {
             let block_to_push = mem::replace(&mut all_parts[i], Vec::new());
             corrected_vec.push(block_to_push);
+            corrected_blocks.push(blocks[i]);
+            corrected_scores.push(score);
             continue;
         }
 
         println!("Filling in block {}", i);
 
         //Bad block, fill in from left
-        let mut vec_reads_interval: Vec<&Frag> = local_clustering::find_reads_in_interval(
-            i * length_of_block,
-            (i + 1) * length_of_block,
-            all_frags,
-        )
-        .into_iter()
-        .collect();
+        let mut vec_reads_interval: Vec<&Frag> = frag_index
+            .reads_in_interval(blocks[i].start, blocks[i].end)
+            .into_iter()
+            .collect();
         vec_reads_interval.sort_by(|a, b| a.first_position.cmp(&b.first_position));
         fill_left_block(corrected_vec.iter_mut().last().unwrap(), vec_reads_interval,epsilon);
+        corrected_blocks.last_mut().unwrap().end = blocks[i].end;
     }
+
+    *blocks = corrected_blocks;
+    *all_scores = corrected_scores;
     corrected_vec
 }
 
-//Modified beam search ... not working right now and incomplete. Maybe
-//modify for usage in the future. 
-pub fn link_blocks_heur<'a>(all_parts: &Vec<Vec<FxHashSet<&'a Frag>>>, num_sol : usize) -> Vec<FxHashSet<&'a Frag>> {
-    let perm_vector: Mutex<Vec<(usize, Vec<Vec<usize>>)>> = Mutex::new(vec![]);
+//A partial solution of the beam search : MEC score, the haplotype of the last block linked to
+//each final haplotype for every block so far, and the haplotypes near the last block.
+struct BeamCandidate{
+    errors : usize,
+    perms : Vec<Vec<usize>>,
+    hap_block : HapBlock,
+}
+
+///Beam search linking. Blocks are linked from left to right, keeping the beam_width partial
+///solutions with the lowest MEC score. Each solution is extended by the beam_width permutations
+///with the most shared reads (see get_best_perms), and scored by the errors of the reads which
+///are new in the block. Like the other linkers, a read in several blocks ends up on every
+///haplotype it was linked to, so duplicates have to be removed afterwards.
+pub fn link_blocks_heur<'a>(all_parts: &Vec<Vec<FxHashSet<&'a Frag>>>, beam_width : usize) -> Vec<FxHashSet<&'a Frag>> {
     let ploidy = all_parts[0].len();
+    let beam_width = std::cmp::max(beam_width, 1);
+
+    //The reads of each haplotype of a block which are not in an earlier block.
+    let mut used_reads = FxHashSet::default();
+    let mut new_reads : Vec<Vec<Vec<&Frag>>> = Vec::new();
+    for part in all_parts.iter(){
+        let mut new_reads_part = Vec::new();
+        for reads in part.iter(){
+            let mut new_reads_hap : Vec<&Frag> = reads.iter().filter(|read| !used_reads.contains(*read)).copied().collect();
+            new_reads_hap.sort_by_key(|read| read.counter_id);
+            new_reads_part.push(new_reads_hap);
+        }
+        for reads in part.iter(){
+            used_reads.extend(reads.iter().copied());
+        }
+        new_reads.push(new_reads_part);
+    }
 
-    //Get the best permutation for each consecutive pair.
-    (1..all_parts.len())
+    //Get the best permutations for each consecutive pair.
+    let perm_vector : Vec<Vec<Vec<usize>>> = (1..all_parts.len())
         .collect::<Vec<usize>>()
         .into_par_iter()
-        .for_each(|i| {
-            let mut locked_perm = perm_vector.lock().unwrap();
-            locked_perm.push((i, get_best_perms(&all_parts[i - 1], &all_parts[i])));
-        });
-
-    let mut perm_vector = perm_vector.lock().unwrap().to_vec();
-    perm_vector.sort_by(|a, b| a.0.cmp(&b.0));
-    let perm_vector: Vec<Vec<Vec<usize>>> = perm_vector.into_iter().map(|x| x.1).collect();
-
-    //Start with the first k different links.
-    let first_perms = &perm_vector[0];
-    let mut mec_perm_block_part = Vec::new();
-
-    for i in 0..num_sol{
-        let perm = &first_perms[i];
-        let mut new_part = Vec::new();
-
-        for j in 0..ploidy{
-            let set1 = &all_parts[0][j];
-            let set2 = &all_parts[1][perm[j]];
-            let mut union_set = FxHashSet::default();
-            
-            for read in set1.iter(){
-                union_set.insert(*read);
-            }
+        .map(|i| {
+            get_best_perms(&all_parts[i - 1], &all_parts[i], beam_width)
+        })
+        .collect();
 
-            for read in set2.iter(){
-                union_set.insert(*read);
+    let mut beam = vec![BeamCandidate{
+        errors : get_mec_from_part(&all_parts[0]) as usize,
+        perms : vec![(0..ploidy).collect()],
+        hap_block : utils_frags::hap_block_from_partition(&all_parts[0]),
+    }];
+
+    for (i, perms) in perm_vector.iter().enumerate(){
+        let i = i + 1;
+        //Reads of later blocks don't cover positions left of the reads of this block, so the
+        //haplotypes are only kept from there on.
+        let leftmost_pos = all_parts[i]
+            .iter()
+            .flat_map(|reads| reads.iter().map(|read| read.first_position))
+            .min()
+            .unwrap_or(usize::MAX);
+
+        //Extend every solution by every permutation and keep the best ones.
+        let mut extensions = Vec::new();
+        for (j, candidate) in beam.iter().enumerate(){
+            let curr_perm = candidate.perms.last().unwrap();
+            for perm in perms.iter(){
+                let new_perm : Vec<usize> = (0..ploidy).map(|l| perm[curr_perm[l]]).collect();
+                let mut errors = candidate.errors;
+                for l in 0..ploidy{
+                    errors += get_mec_increase_hap(&new_reads[i][new_perm[l]], &candidate.hap_block.blocks[l]);
+                }
+                extensions.push((errors, j, new_perm));
             }
-            new_part.push(union_set);
-        }
-
-        let block = utils_frags::hap_block_from_partition(&new_part);
-        //TODO Polish stuff
-        let (binom_vec,_freq_vec)= local_clustering::get_partition_stats(&new_part,&block);
-
-        let mut errors = 0;
-        for tup in binom_vec.iter(){
-            errors += tup.1;
         }
-
-        let wrapped_block = RefCell::new(block);
-
-        mec_perm_block_part.push((errors,perm.clone(),wrapped_block,new_part));
-    }
-
-    for i in 1..perm_vector.len(){
-        let mut new_mec_perm_complete = Vec::new();
-        let mut new_mec_perm = Vec::new();
-        let perms = &perm_vector[i];
-        let mut leftmost_pos = usize::MAX;
-        let mut rightmost_pos = usize::MIN;
-        //Iterate over each previous candidate solution
-        for j in 0..num_sol{
-            let (errors,curr_perm,wrapped_block,part) = & mec_perm_block_part[j];
-            //Iterate over each permutation to create k^2 different solutions
-            for k in 0..num_sol{
-                let perm = &perms[k];
-                let mut new_errors = *errors;
-                let mut vec_new_part = Vec::new();
-
-                //Iterate over each haplotype
+        extensions.sort_by_key(|extension| extension.0);
+        extensions.truncate(beam_width);
+
+        beam = extensions
+            .into_iter()
+            .map(|(errors, j, new_perm)| {
+                let parent = &beam[j];
+                let mut hap_block = clone_block_range(&parent.hap_block, leftmost_pos, usize::MAX);
                 for l in 0..ploidy{
-                    
-                    let mut list_of_pos_inserts = Vec::new();
-                    let set1 = &part[l];
-                    let set2 = &all_parts[i+1][perm[curr_perm[l]]];
-                    let mut new_reads_set = FxHashSet::default();
-                    let mut union_set = FxHashSet::default();
-
-                    for read in set2.iter(){
-                        list_of_pos_inserts.push(read.first_position);
-                        list_of_pos_inserts.push(read.last_position);
-
-                        if read.first_position < leftmost_pos{
-                            leftmost_pos = read.first_position;
-                        }
-
-                        if read.last_position > rightmost_pos{
-                            rightmost_pos = read.last_position;
-                        }
-
-                        let mut contains_read = false;
-                        for p in 0..ploidy{
-                            if (part[p]).contains(read){
-                               contains_read = true 
-                            }
-                        }
-
-                        if !contains_read{
-                            new_reads_set.insert(*read);
-                            union_set.insert(*read);
+                    for read in new_reads[i][new_perm[l]].iter(){
+                        for (pos, var_at_pos) in read.alleles(){
+                            *hap_block.blocks[l].entry(pos).or_default().entry(var_at_pos).or_insert(0) += 1;
                         }
-
-                    }
-
-                    for read in set1.iter(){
-                        union_set.insert(*read);
                     }
-
-
-                    let emptyset :FxHashSet<&Frag>= FxHashSet::default();
-                    list_of_pos_inserts.sort();
-                    let mec_before_positions = get_mec_positions_hap(&emptyset, &mut wrapped_block.borrow_mut(), &list_of_pos_inserts, l);
-                    let mec_after_positions = get_mec_positions_hap(&new_reads_set, &mut wrapped_block.borrow_mut(), &list_of_pos_inserts, l);
-                    vec_new_part.push(union_set);
-//
-                    new_errors += mec_after_positions - mec_before_positions;
                 }
-                let mut new_curr_perm = vec![];
-                for l in 0..ploidy{
-                    new_curr_perm.push(perm[curr_perm[l]]);
+                let mut perms = parent.perms.clone();
+                perms.push(new_perm);
+                BeamCandidate{
+                    errors,
+                    perms,
+                    hap_block,
                 }
-                new_mec_perm.push((new_errors,new_curr_perm, wrapped_block ,vec_new_part));
-            }
-        }
-
-        //Now have a list of k^2 candidate solutions, sort and get the best ones.
-        new_mec_perm.sort_by(|a,b| a.0.cmp(&b.0));
+            })
+            .collect();
+    }
 
-        for j in 0..num_sol{
-            let (new_errors,new_curr_perm,wrapped_block,new_part) = &mut new_mec_perm[j];
-            let swap_part = mem::replace(new_part, Vec::new());
-            new_mec_perm_complete.push((*new_errors,new_curr_perm.clone(), RefCell::new(clone_block_range(&wrapped_block.borrow(),leftmost_pos,rightmost_pos)),swap_part));
+    let best = &beam[0];
+    let mut final_part = vec![FxHashSet::default(); ploidy];
+    for (i, perm) in best.perms.iter().enumerate(){
+        for l in 0..ploidy{
+            final_part[l].extend(all_parts[i][perm[l]].iter().copied());
         }
-
-        mec_perm_block_part = new_mec_perm_complete;
     }
 
-    mec_perm_block_part.into_iter().nth(0).unwrap().3
+    final_part
 }
 
 pub fn remove_duplicate_reads(
@@ -585,10 +759,129 @@ pub fn remove_duplicate_reads(
 }
 
 
-pub fn map_reads_against_hap_errors(
-    part : &Vec<FxHashSet<&Frag>>,
+//Put each of reads on the haplotype of part it agrees with best, i.e. with the most matching minus
+//mismatching alleles, e.g. reads which were left out of clustering. Counting only mismatches
+//would favour haplotypes with little coverage where the read is.
+pub fn assign_reads_to_haplotypes<'a>(part : &mut Vec<FxHashSet<&'a Frag>>, reads : &[&'a Frag]){
+    let block = utils_frags::hap_block_from_partition(part);
+    for read in reads.iter(){
+        let mut best_hap = 0;
+        let mut best_agreement = i64::MIN;
+        for (i, hap) in block.blocks.iter().enumerate(){
+            let (same, diff) = utils_frags::distance_read_haplo(read, hap);
+            let agreement = same as i64 - diff as i64;
+            if agreement > best_agreement{
+                best_agreement = agreement;
+                best_hap = i;
+            }
+        }
+        part[best_hap].insert(read);
+    }
+}
+
+//Log likelihood of a read's alleles given a haplotype with error rate epsilon. The read's own
+//alleles are not counted if it is on the haplotype. Positions where the haplotype has no other
+//reads or is tied count as either allele being equally likely.
+fn read_hap_log_likelihood(
+    read : &Frag,
+    hap : &FxHashMap<usize,FxHashMap<usize,usize>>,
+    on_hap : bool,
+    epsilon : f64) -> f64{
+    let own_count = on_hap as usize;
+    let mut log_likelihood = 0.0;
+    for (pos, var) in read.alleles(){
+        let (read_count, other_count) = match hap.get(&pos){
+            Some(site_map) => {
+                let read_count = site_map.get(&var).copied().unwrap_or(0) - own_count;
+                let other_count = site_map
+                    .iter()
+                    .filter(|(allele, _)| **allele != var)
+                    .map(|(_, count)| *count)
+                    .max()
+                    .unwrap_or(0);
+                (read_count, other_count)
+            }
+            None => (0, 0),
+        };
+        log_likelihood += if read_count > other_count{
+            (1.0 - epsilon).ln()
+        } else if read_count < other_count{
+            epsilon.ln()
+        } else{
+            0.5_f64.ln()
+        };
+    }
+    log_likelihood
+}
+
+//Log likelihood of a read given each haplotype of block, see read_hap_log_likelihood.
+fn read_log_likelihoods(read : &Frag, part : &[FxHashSet<&Frag>], block : &HapBlock, epsilon : f64) -> Vec<f64>{
+    part.iter()
+        .zip(block.blocks.iter())
+        .map(|(reads, hap)| read_hap_log_likelihood(read, hap, reads.contains(read), epsilon))
+        .collect()
+}
+
+//Log likelihood margin of every read of part between the haplotype of block it fits best and the
+//second best one. Reads which only cover SNPs where the haplotypes agree have a margin of 0.
+pub fn get_assignment_margins<'a>(part : &[FxHashSet<&'a Frag>], block : &HapBlock, epsilon : f64) -> FxHashMap<&'a Frag, f64>{
+    let mut margins = FxHashMap::default();
+    for read in part.iter().flatten(){
+        if margins.contains_key(read){
+            continue;
+        }
+        let mut log_likelihoods = read_log_likelihoods(read, part, block, epsilon);
+        log_likelihoods.sort_by(|a, b| b.partial_cmp(a).unwrap());
+        let margin = if log_likelihoods.len() > 1 { log_likelihoods[0] - log_likelihoods[1] } else { 0.0 };
+        margins.insert(*read, margin);
+    }
+    margins
+}
+
+//Posterior probability of each haplotype of block for every read of part, with the same
+//likelihoods as get_assignment_margins and equal prior probabilities for the haplotypes.
+pub fn get_assignment_probabilities<'a>(part : &[FxHashSet<&'a Frag>], block : &HapBlock, epsilon : f64) -> FxHashMap<&'a Frag, Vec<f64>>{
+    let mut probabilities = FxHashMap::default();
+    for read in part.iter().flatten(){
+        if probabilities.contains_key(read){
+            continue;
+        }
+        let log_likelihoods = read_log_likelihoods(read, part, block, epsilon);
+        let max_log_likelihood = log_likelihoods.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        let likelihoods : Vec<f64> = log_likelihoods.iter().map(|x| (x - max_log_likelihood).exp()).collect();
+        let total : f64 = likelihoods.iter().sum();
+        probabilities.insert(*read, likelihoods.iter().map(|x| x / total).collect());
+    }
+    probabilities
+}
+
+//Take the reads with an assignment margin below min_margin out of part and return them, sorted by
+//first position.
+pub fn remove_ambiguous_reads<'a>(
+    part : &mut Vec<FxHashSet<&'a Frag>>,
+    margins : &FxHashMap<&'a Frag, f64>,
+    min_margin : f64) -> Vec<&'a Frag>{
+    let mut ambiguous_reads : Vec<&Frag> = margins
+        .iter()
+        .filter(|(_, margin)| **margin < min_margin)
+        .map(|(read, _)| *read)
+        .collect();
+    ambiguous_reads.sort_by_key(|read| (read.first_position, read.counter_id));
+    for reads in part.iter_mut(){
+        for read in ambiguous_reads.iter(){
+            reads.remove(read);
+        }
+    }
+    ambiguous_reads
+}
+
+//Test every read for a switch between its haplotype and another one : the read is split in half
+//and both halves are tested against the error rate of the whole read with a binomial test.
+//Returns the log p-value of the worse half, the read and its haplotype, most significant first.
+pub fn map_reads_against_hap_errors<'a>(
+    part : &[FxHashSet<&'a Frag>],
     block : &HapBlock,
-    epsilon : f64){
+    epsilon : f64) -> Vec<(f64, &'a Frag, usize)>{
 
     let mut list_of_errors = Vec::new();
     let ploidy = part.len();
@@ -623,17 +916,185 @@ pub fn map_reads_against_hap_errors(
                 continue;
             }
 
-            list_of_errors.push((min_error_val,&read.id,read.first_position,read.last_position,i,diff_left,diff_right));
+            list_of_errors.push((min_error_val,*read,i));
         }
     }
 
     list_of_errors.sort_by(|a,b| a.0.partial_cmp(&b.0).unwrap()); 
+    list_of_errors
+}
+
+//For every allele of a read, +1 if it disagrees with the consensus of hap1 and agrees with the
+//consensus of hap2, -1 for the opposite and 0 otherwise.
+fn read_switch_gains(
+    read : &Frag,
+    hap1 : &FxHashMap<usize,FxHashMap<usize,usize>>,
+    hap2 : &FxHashMap<usize,FxHashMap<usize,usize>>) -> Vec<(usize,i64)>{
+    read.alleles()
+        .map(|(pos,var)| {
+            let gain = match (consensus_allele(hap1,pos), consensus_allele(hap2,pos)){
+                (Some(allele1), Some(allele2)) => (allele1 != var) as i64 - (allele2 != var) as i64,
+                _ => 0,
+            };
+            (pos,gain)
+        })
+        .collect()
+}
 
-    let file = File::create("possible_breaks.txt").expect("Can't create file");
-    let mut file = LineWriter::new(file);
+//Decrease in errors of the reads of haplotypes a and b spanning position if the two haplotypes
+//are swapped from position on. Spanning reads come from both sides of a switch, so every read is
+//counted against whichever of the two haplotypes it fits best, before and after the swap.
+fn swap_gain(part : &[FxHashSet<&Frag>], block : &HapBlock, a : usize, b : usize, position : usize) -> i64{
+    let mut gain = 0;
+    let spanning : FxHashSet<&Frag> = part[a].iter().chain(part[b].iter())
+        .filter(|read| read.first_position < position && read.last_position >= position)
+        .copied()
+        .collect();
+    for read in spanning{
+        //Errors of the read against a and b on each side of the position.
+        let mut errors = [[0; 2]; 2];
+        for (pos, var) in read.alleles(){
+            let side = (pos >= position) as usize;
+            for (k, hap) in [a,b].iter().enumerate(){
+                if let Some(allele) = consensus_allele(&block.blocks[*hap], pos){
+                    errors[k][side] += (allele != var) as i64;
+                }
+            }
+        }
+        let before = std::cmp::min(errors[0][0] + errors[0][1], errors[1][0] + errors[1][1]);
+        let after = std::cmp::min(errors[0][0] + errors[1][1], errors[1][0] + errors[0][1]);
+        gain += before - after;
+    }
+    gain
+}
 
-    for i in 0..list_of_errors.len(){
-        write!(file,"{},{},{},{}\n",list_of_errors[i].0,list_of_errors[i].1,list_of_errors[i].3,list_of_errors[i].4).unwrap();
+///Find switch errors in a phased partition. Reads whose worse half fails the binomial test of
+///map_reads_against_hap_errors are split where their alleles start agreeing with another
+///haplotype instead of their own. Such splits between the same two haplotypes within half a read
+///length of each other are grouped, and a group of at least min_reads reads is a switch error if
+///swapping the two haplotypes at the median split lowers the errors of the reads spanning it.
+///Returns the switch errors sorted by position.
+pub fn find_switch_errors(
+    part : &[FxHashSet<&Frag>],
+    block : &HapBlock,
+    epsilon : f64,
+    min_reads : usize) -> Vec<SwitchError>{
+
+    let ploidy = part.len();
+    let log_p_cutoff = (0.05_f64).ln();
+
+    //Splits are grouped within half the median read span.
+    let mut spans : Vec<usize> = part.iter()
+        .flat_map(|reads| reads.iter().map(|read| read.last_position - read.first_position))
+        .collect();
+    if spans.is_empty(){
+        return vec![];
+    }
+    spans.sort_unstable();
+    let window = std::cmp::max(spans[spans.len() / 2] / 2, 1);
+
+    //The split of each suspicious read : the two haplotypes and the first SNP after the switch.
+    let mut splits = Vec::new();
+    for (log_p, read, a) in map_reads_against_hap_errors(part, block, epsilon){
+        if log_p > log_p_cutoff{
+            break;
+        }
+        let mut best_split = None;
+        let mut best_gain = 1;
+        for b in (0..ploidy).filter(|b| *b != a){
+            let gains = read_switch_gains(read,&block.blocks[a],&block.blocks[b]);
+            let mut suffix_gain = 0;
+            //Keep at least one allele left of the split.
+            for k in (1..gains.len()).rev(){
+                suffix_gain += gains[k].1;
+                if suffix_gain > best_gain{
+                    best_gain = suffix_gain;
+                    best_split = Some((std::cmp::min(a,b),std::cmp::max(a,b),gains[k].0));
+                }
+            }
+        }
+        if let Some(split) = best_split{
+            splits.push(split);
+        }
+    }
+    splits.sort_unstable();
+
+    let mut switch_errors = Vec::new();
+    let mut k = 0;
+    while k < splits.len(){
+        let (a, b, first_pos) = splits[k];
+        let mut l = k;
+        while l < splits.len() && splits[l].0 == a && splits[l].1 == b && splits[l].2 <= first_pos + window{
+            l += 1;
+        }
+        if l - k >= min_reads{
+            let position = splits[(k + l) / 2].2;
+            let gain = swap_gain(part, block, a, b, position);
+            if gain > 0{
+                switch_errors.push(SwitchError{
+                    position,
+                    haps : (a,b),
+                    num_reads : l - k,
+                    gain,
+                });
+            }
+        }
+        k = l;
+    }
+
+    switch_errors.sort_by_key(|switch_error| switch_error.position);
+    switch_errors
+}
+
+///Correct switch errors (see find_switch_errors) in place. For each switch error, from left to
+///right, the reads starting at or after the switch are swapped between the two haplotypes, and
+///the reads of the two haplotypes spanning the switch are put on whichever of the two they have
+///fewer errors against.
+pub fn fix_switch_errors(part : &mut Vec<FxHashSet<&Frag>>, switch_errors : &[SwitchError]){
+    let ploidy = part.len();
+    //Haplotypes right of the switches fixed so far are relabeled by this permutation.
+    let mut relabel : Vec<usize> = (0..ploidy).collect();
+    for switch_error in switch_errors.iter(){
+        let position = switch_error.position;
+        let a = relabel[switch_error.haps.0];
+        let b = relabel[switch_error.haps.1];
+
+        let right_a : Vec<&Frag> = part[a].iter().filter(|read| read.first_position >= position).copied().collect();
+        let right_b : Vec<&Frag> = part[b].iter().filter(|read| read.first_position >= position).copied().collect();
+        for read in right_a.iter(){
+            part[a].remove(read);
+        }
+        for read in right_b.iter(){
+            part[b].remove(read);
+        }
+        part[a].extend(right_b);
+        part[b].extend(right_a);
+
+        let block = utils_frags::hap_block_from_partition(part);
+        for (hap, other) in [(a,b),(b,a)].iter(){
+            let spanning : Vec<&Frag> = part[*hap]
+                .iter()
+                .filter(|read| read.first_position < position && read.last_position >= position && !part[*other].contains(*read))
+                .copied()
+                .collect();
+            for read in spanning{
+                let (_, errors_hap) = utils_frags::distance_read_haplo(read, &block.blocks[*hap]);
+                let (_, errors_other) = utils_frags::distance_read_haplo(read, &block.blocks[*other]);
+                if errors_other < errors_hap{
+                    part[*hap].remove(read);
+                    part[*other].insert(read);
+                }
+            }
+        }
+
+        for label in relabel.iter_mut(){
+            if *label == a{
+                *label = b;
+            }
+            else if *label == b{
+                *label = a;
+            }
+        }
     }
 }
 
@@ -644,9 +1105,6 @@ pub fn link_blocks_greedy<'a>(all_parts: &Vec<Vec<FxHashSet<&'a Frag>>>,
     let mut final_part = all_parts[0].clone();
     let ploidy = final_part.len();
     let mut part_size_distribution_sum = vec!();
-    let rangevec: Vec<usize> = (0..ploidy).collect();
-    let perms = permute(rangevec);
-
 
     let mut all_used_reads = FxHashSet::default();
     for reads in all_parts[0].iter(){
@@ -658,27 +1116,7 @@ pub fn link_blocks_greedy<'a>(all_parts: &Vec<Vec<FxHashSet<&'a Frag>>>,
     //Find best permutation
     for i in 1..all_parts.len(){
         let part_to_link = &all_parts[i];
-        let mut best_total_intersect = 0;
-        let mut best_perm = &perms[0];
-        for perm in &perms{
-            let mut total_intersect = 0;
-            for j in 0..ploidy{
-
-                let set1 = &final_part[j];
-                let set2 = &part_to_link[perm[j]];
-
-                for read in set2.iter(){
-                    if set1.contains(read){
-                        total_intersect += 1
-                    }
-                }
-            }
-
-            if total_intersect > best_total_intersect{
-                best_total_intersect = total_intersect;
-                best_perm = perm;
-            }
-        }
+        let best_perm = max_weight_assignment(&intersection_weights(&final_part, part_to_link));
 
         for j in 0..ploidy{
             let set1 = &mut final_part[j];
@@ -726,3 +1164,491 @@ pub fn link_blocks_greedy<'a>(all_parts: &Vec<Vec<FxHashSet<&'a Frag>>>,
 
 
 
+
+//Allele with the most support on a haplotype at a position.
+fn consensus_allele(hap: &FxHashMap<usize, FxHashMap<usize, usize>>, pos: usize) -> Option<usize> {
+    hap.get(&pos)
+        .and_then(|site_map| site_map.iter().max_by_key(|entry| entry.1))
+        .map(|entry| *entry.0)
+}
+
+//Number of SNPs in [start, end] where the two haplotypes have the same consensus allele, minus
+//the number of SNPs where they differ.
+fn haplotype_agreement(
+    hap1: &FxHashMap<usize, FxHashMap<usize, usize>>,
+    hap2: &FxHashMap<usize, FxHashMap<usize, usize>>,
+    start: usize,
+    end: usize,
+) -> i64 {
+    let mut agreement = 0;
+    for pos in start..end + 1 {
+        if let (Some(allele1), Some(allele2)) = (consensus_allele(hap1, pos), consensus_allele(hap2, pos)) {
+            if allele1 == allele2 {
+                agreement += 1;
+            } else {
+                agreement -= 1;
+            }
+        }
+    }
+    agreement
+}
+
+///Link overlapping blocks. Like link_blocks_greedy, each block is joined to the partition so far
+///by the permutation with the most shared reads, but the haplotypes of consecutive blocks are also
+///compared on the SNPs the blocks share, adding one for every SNP where they agree and
+///subtracting one for every SNP where they disagree. This links blocks even when few reads
+///cross the boundary. Reads in the overlap which the two blocks put on different haplotypes are
+///kept on the haplotype they have the fewest errors against.
+pub fn link_blocks_overlap<'a>(
+    all_parts: &Vec<Vec<FxHashSet<&'a Frag>>>,
+    blocks: &[LocalBlock],
+    all_frags: &'a Vec<Frag>,
+) -> Vec<FxHashSet<&'a Frag>> {
+    let mut final_part = all_parts[0].clone();
+    let ploidy = final_part.len();
+    let hap_blocks: Vec<HapBlock> = all_parts
+        .par_iter()
+        .map(|part| utils_frags::hap_block_from_partition(part))
+        .collect();
+
+    //The haplotype of the previous block which was linked to each haplotype of final_part.
+    let mut current_perm: Vec<usize> = (0..ploidy).collect();
+
+    for i in 1..all_parts.len() {
+        let part_to_link = &all_parts[i];
+
+        //Score every pairing of a haplotype so far with a haplotype of the new block once.
+        let mut pair_scores = vec![vec![0; ploidy]; ploidy];
+        for (j, pair_scores_j) in pair_scores.iter_mut().enumerate() {
+            let prev_hap = &hap_blocks[i - 1].blocks[current_perm[j]];
+            for (k, pair_score) in pair_scores_j.iter_mut().enumerate() {
+                let shared_reads = part_to_link[k]
+                    .iter()
+                    .filter(|read| final_part[j].contains(*read))
+                    .count() as i64;
+                let agreement = haplotype_agreement(
+                    prev_hap,
+                    &hap_blocks[i].blocks[k],
+                    blocks[i].start,
+                    blocks[i - 1].end,
+                );
+                *pair_score = shared_reads + agreement;
+            }
+        }
+
+        let best_perm = max_weight_assignment(&pair_scores);
+
+        for (j, set1) in final_part.iter_mut().enumerate() {
+            for read in part_to_link[best_perm[j]].iter() {
+                set1.insert(read);
+            }
+        }
+
+        current_perm = best_perm;
+    }
+
+    let hap_block = utils_frags::hap_block_from_partition(&final_part);
+    remove_duplicate_reads(&mut final_part, all_frags, &hap_block);
+    final_part
+}
+
+///Number of SNPs in [start, end] where the consensus allele of each haplotype is not in the
+///genotype of a parent, i.e. the haplotype can't have come from the parent there. Returns one
+///count per haplotype.
+pub fn get_parent_incompatibilities(
+    block : &HapBlock,
+    parent_genotypes : &FxHashMap<usize, FxHashMap<usize, usize>>,
+    start : usize,
+    end : usize) -> Vec<usize>{
+    block.blocks.iter()
+        .map(|hap| {
+            (start..end + 1)
+                .filter(|pos| match (consensus_allele(hap, *pos), parent_genotypes.get(pos)){
+                    (Some(allele), Some(genotype)) => !genotype.contains_key(&allele),
+                    _ => false,
+                })
+                .count()
+        })
+        .collect()
+}
+
+///costs[h][p] is the number of SNPs in [start, end] where haplotype h can't have come from parent p
+///(see get_parent_incompatibilities), for assign_haplotypes_to_parents.
+pub fn get_parent_costs(block : &HapBlock, parents : &ParentGenotypes, start : usize, end : usize) -> Vec<Vec<usize>>{
+    let parent_costs : Vec<Vec<usize>> = parents.genotypes.iter()
+        .map(|genotypes| get_parent_incompatibilities(block, genotypes, start, end))
+        .collect();
+    (0..block.blocks.len())
+        .map(|h| parent_costs.iter().map(|parent_cost| parent_cost[h]).collect())
+        .collect()
+}
+
+///Assign each haplotype to one of two parents, where parent p passes on contributions[p]
+///homologs, such that the haplotypes have as few alleles missing from their parent's genotype as
+///possible. costs[h][p] is the number of such alleles of haplotype h for parent p (see
+///get_parent_incompatibilities). Returns the parent of each haplotype and the total cost.
+pub fn assign_haplotypes_to_parents(costs : &[Vec<usize>], contributions : &[usize]) -> (Vec<usize>, usize){
+    let ploidy = costs.len();
+    let mut best_assignment = vec![1; ploidy];
+    let mut best_cost = usize::MAX;
+    //Every subset of contributions[0] haplotypes is a possible set of haplotypes from the first
+    //parent.
+    for subset in 0..(1_u64 << ploidy){
+        if subset.count_ones() as usize != contributions[0]{
+            continue;
+        }
+        let assignment : Vec<usize> = (0..ploidy).map(|h| (subset >> h & 1 == 0) as usize).collect();
+        let cost = assignment.iter().enumerate().map(|(h, p)| costs[h][*p]).sum();
+        if cost < best_cost{
+            best_cost = cost;
+            best_assignment = assignment;
+        }
+    }
+    (best_assignment, best_cost)
+}
+
+///Find phase errors between haplotypes from different parents. hap_parents is the parent of each
+///haplotype over the whole contig (see assign_haplotypes_to_parents). In each local block after the
+///first, the haplotypes are assigned to parents using only the SNPs of the block; a haplotype from
+///the first parent and one from the second which the block assigns the other way round are a
+///switch error at the start of the block if site_weight times the decrease in alleles missing from
+///the parents' genotypes outweighs the increase in errors of the reads spanning the switch.
+///Returns the switch errors sorted by position, with no supporting reads, for fix_switch_errors.
+pub fn find_parental_switches(
+    part : &[FxHashSet<&Frag>],
+    block : &HapBlock,
+    local_blocks : &[LocalBlock],
+    parents : &ParentGenotypes,
+    hap_parents : &[usize],
+    site_weight : i64) -> Vec<SwitchError>{
+
+    let ploidy = part.len();
+    //Haplotype of the whole contig each haplotype of the block continues, after the switches so far.
+    let mut relabel : Vec<usize> = (0..ploidy).collect();
+    let mut switch_errors = Vec::new();
+    for local_block in local_blocks.iter().skip(1){
+        let costs = get_parent_costs(block, parents, local_block.start, local_block.end);
+        let (local_parents, _) = assign_haplotypes_to_parents(&costs, &parents.contributions);
+
+        let current_parent = |h : usize, relabel : &[usize]| hap_parents[relabel[h]];
+        let from_first : Vec<usize> = (0..ploidy)
+            .filter(|h| current_parent(*h, &relabel) == 0 && local_parents[*h] == 1)
+            .collect();
+        let from_second : Vec<usize> = (0..ploidy)
+            .filter(|h| current_parent(*h, &relabel) == 1 && local_parents[*h] == 0)
+            .collect();
+        for (a, b) in from_first.into_iter().zip(from_second){
+            let parent_gain = (costs[a][0] + costs[b][1]) as i64 - (costs[a][1] + costs[b][0]) as i64;
+            let gain = site_weight * parent_gain + swap_gain(part, block, a, b, local_block.start);
+            if gain > 0{
+                switch_errors.push(SwitchError{
+                    position : local_block.start,
+                    haps : (std::cmp::min(a,b), std::cmp::max(a,b)),
+                    num_reads : 0,
+                    gain,
+                });
+                relabel.swap(a,b);
+            }
+        }
+    }
+    switch_errors
+}
+
+//Number of SNPs in [start, end] where a panel haplotype has the consensus allele of a haplotype,
+//minus the number where it has another allele.
+fn panel_agreement(
+    hap : &FxHashMap<usize, FxHashMap<usize, usize>>,
+    panel_hap : &FxHashMap<usize, usize>,
+    start : usize,
+    end : usize) -> i64{
+    let mut agreement = 0;
+    for pos in start..end + 1{
+        if let (Some(allele), Some(panel_allele)) = (consensus_allele(hap, pos), panel_hap.get(&pos)){
+            if allele == *panel_allele{
+                agreement += 1;
+            }
+            else{
+                agreement -= 1;
+            }
+        }
+    }
+    agreement
+}
+
+///Find phase errors between local blocks using a panel of haplotypes of related samples, e.g. from
+///a phased reference panel VCF. At the start of each local block after the first, a haplotype is
+///scored by its best agreement (see panel_agreement) with a panel haplotype over the previous block
+///and this one. If swapping two haplotypes from the start of the block on raises the summed score
+///of the two, times panel_weight, by more than it raises the errors of the reads spanning the
+///swap, it is a switch error. At most one switch, the one with the largest gain, is kept per block.
+///Returns the switch errors sorted by position, with no supporting reads, for fix_switch_errors.
+pub fn find_panel_switches(
+    part : &[FxHashSet<&Frag>],
+    block : &HapBlock,
+    local_blocks : &[LocalBlock],
+    panel : &[FxHashMap<usize, usize>],
+    panel_weight : f64) -> Vec<SwitchError>{
+
+    let ploidy = part.len();
+    let mut switch_errors = Vec::new();
+    if panel.is_empty(){
+        return switch_errors;
+    }
+    for i in 1..local_blocks.len(){
+        let position = local_blocks[i].start;
+        if position <= local_blocks[i - 1].start{
+            continue;
+        }
+        let left : Vec<Vec<i64>> = block.blocks.iter()
+            .map(|hap| panel.iter().map(|panel_hap| panel_agreement(hap, panel_hap, local_blocks[i - 1].start, position - 1)).collect())
+            .collect();
+        let right : Vec<Vec<i64>> = block.blocks.iter()
+            .map(|hap| panel.iter().map(|panel_hap| panel_agreement(hap, panel_hap, position, local_blocks[i].end)).collect())
+            .collect();
+        //Best agreement of the left part of haplotype x joined to the right part of haplotype y.
+        let joined = |x : usize, y : usize| (0..panel.len()).map(|k| left[x][k] + right[y][k]).max().unwrap();
+
+        let mut best_switch : Option<SwitchError> = None;
+        for a in 0..ploidy{
+            for b in a + 1..ploidy{
+                let panel_gain = joined(a,b) + joined(b,a) - joined(a,a) - joined(b,b);
+                if panel_gain <= 0{
+                    continue;
+                }
+                let gain = (panel_weight * panel_gain as f64).round() as i64 + swap_gain(part, block, a, b, position);
+                if gain > best_switch.map_or(0, |switch_error| switch_error.gain){
+                    best_switch = Some(SwitchError{
+                        position,
+                        haps : (a,b),
+                        num_reads : 0,
+                        gain,
+                    });
+                }
+            }
+        }
+        if let Some(switch_error) = best_switch{
+            switch_errors.push(switch_error);
+        }
+    }
+    switch_errors
+}
+
+//SNPs whose sample assignment is decided by each local block : from the start of the block up to
+//the start of the next one, or the end of the last block.
+fn block_regions(local_blocks : &[LocalBlock]) -> Vec<(usize,usize)>{
+    (0..local_blocks.len())
+        .map(|i| {
+            let end = match local_blocks.get(i + 1){
+                Some(next_block) => next_block.start.saturating_sub(1).max(local_blocks[i].start),
+                None => local_blocks[i].end,
+            };
+            (local_blocks[i].start, end)
+        })
+        .collect()
+}
+
+//All ways a sample of ploidy sample_ploidy can carry haplotypes of a pool of pool_size haplotypes,
+//as nondecreasing lists of pool haplotypes.
+fn get_copy_states(pool_size : usize, sample_ploidy : usize) -> Vec<Vec<usize>>{
+    let mut states = vec![vec![]];
+    for _ in 0..sample_ploidy{
+        let mut new_states = Vec::new();
+        for state in states.iter(){
+            let first = *state.last().unwrap_or(&0);
+            for hap in first..pool_size{
+                let mut new_state = state.clone();
+                new_state.push(hap);
+                new_states.push(new_state);
+            }
+        }
+        states = new_states;
+    }
+    states
+}
+
+//Number of reads of each sample on each pool haplotype of part with an allele in each region,
+//indexed by sample, region and pool haplotype. A read spanning several regions counts in each.
+fn count_sample_reads(
+    part : &[FxHashSet<&Frag>],
+    regions : &[(usize,usize)],
+    source_samples : &[usize],
+    num_samples : usize) -> Vec<Vec<Vec<usize>>>{
+
+    let mut counts = vec![vec![vec![0; part.len()]; regions.len()]; num_samples];
+    for (hap, reads) in part.iter().enumerate(){
+        for read in reads.iter(){
+            let first_region = regions.partition_point(|region| region.1 < read.first_position);
+            for (i, (start, end)) in regions.iter().enumerate().skip(first_region){
+                if *start > read.last_position{
+                    break;
+                }
+                let first_allele = read.positions.partition_point(|pos| pos < start);
+                if matches!(read.positions.get(first_allele), Some(pos) if pos <= end){
+                    counts[source_samples[read.source]][i][hap] += 1;
+                }
+            }
+        }
+    }
+    counts
+}
+
+///Assign the haplotypes of each sample to the shared pool of haplotypes of a jointly phased
+///partition part, one local block at a time. source_samples gives the sample of the reads of each
+///input file (see Frag::source). The copies a sample carries in a local block explain its reads
+///there : a read on a pool haplotype the sample has c of sample_ploidy copies of has probability
+///c / sample_ploidy, mixed with a small probability of being on any pool haplotype for badly
+///clustered reads. Every read counts once, however many alleles it has. Changing the copies
+///between local blocks, i.e. a recombination, costs recombination_cost in log likelihood, and the
+///most likely copies over all local blocks are found by Viterbi. The copies are then ordered so
+///that a copy stays on its pool haplotype from one local block to the next while the sample still
+///carries it.
+///
+///Returns for each sample and each local block the pool haplotype of each of its sample_ploidy
+///copies.
+pub fn assign_samples_to_haplotypes(
+    part : &[FxHashSet<&Frag>],
+    source_samples : &[usize],
+    num_samples : usize,
+    local_blocks : &[LocalBlock],
+    sample_ploidy : usize,
+    recombination_cost : f64) -> Vec<Vec<Vec<usize>>>{
+
+    let pool_size = part.len();
+    let regions = block_regions(local_blocks);
+    let states = get_copy_states(pool_size, sample_ploidy);
+    let mix = 0.05;
+    //Log probability of a read on each pool haplotype for each state.
+    let log_probs : Vec<Vec<f64>> = states
+        .iter()
+        .map(|state| {
+            (0..pool_size)
+                .map(|hap| {
+                    let copies = state.iter().filter(|x| **x == hap).count() as f64;
+                    ((1.0 - mix) * copies / sample_ploidy as f64 + mix / pool_size as f64).ln()
+                })
+                .collect()
+        })
+        .collect();
+
+    let mut sample_haps = Vec::new();
+    for sample_counts in count_sample_reads(part, &regions, source_samples, num_samples){
+        //Viterbi over the local blocks; back[i][s] is the state of block i - 1 on the best path
+        //ending in state s at block i.
+        let mut scores = vec![0.0; states.len()];
+        let mut back : Vec<Vec<usize>> = Vec::new();
+        for (i, support) in sample_counts.iter().enumerate(){
+            let (best_prev, best_prev_score) = scores
+                .iter()
+                .enumerate()
+                .fold((0, f64::MIN), |best, (s, score)| if *score > best.1 { (s, *score) } else { best });
+            let mut new_scores = vec![0.0; states.len()];
+            let mut new_back = vec![0; states.len()];
+            for s in 0..states.len(){
+                let emission : f64 = support.iter().zip(log_probs[s].iter()).map(|(count, log_prob)| *count as f64 * log_prob).sum();
+                if i == 0 || scores[s] >= best_prev_score - recombination_cost{
+                    new_scores[s] = scores[s] + emission;
+                    new_back[s] = s;
+                }
+                else{
+                    new_scores[s] = best_prev_score - recombination_cost + emission;
+                    new_back[s] = best_prev;
+                }
+            }
+            scores = new_scores;
+            back.push(new_back);
+        }
+
+        let mut path = vec![0; regions.len()];
+        if let Some(last) = path.last_mut(){
+            *last = (0..states.len()).fold(0, |best, s| if scores[s] > scores[best] { s } else { best });
+        }
+        for i in (1..regions.len()).rev(){
+            path[i - 1] = back[i][path[i]];
+        }
+
+        let mut assignments : Vec<Vec<usize>> = Vec::new();
+        let mut prev_haps : Vec<usize> = Vec::new();
+        for s in path{
+            let mut remaining = states[s].clone();
+            let mut haps = vec![usize::MAX; sample_ploidy];
+            for (j, prev_hap) in prev_haps.iter().enumerate(){
+                if let Some(k) = remaining.iter().position(|hap| hap == prev_hap){
+                    haps[j] = remaining.remove(k);
+                }
+            }
+            let mut new_haps = remaining.into_iter();
+            for hap in haps.iter_mut(){
+                if *hap == usize::MAX{
+                    *hap = new_haps.next().unwrap();
+                }
+            }
+            assignments.push(haps.clone());
+            prev_haps = haps;
+        }
+        sample_haps.push(assignments);
+    }
+    sample_haps
+}
+
+///Partition of the reads of one sample from a jointly phased partition, given the pool haplotypes
+///of the sample's copies in each local block from assign_samples_to_haplotypes. A read goes on
+///every copy of its pool haplotype in the local block where it starts, so where the sample is
+///homozygous it is on several copies. Reads on a pool haplotype the sample doesn't carry there are
+///put on the copy they agree with best.
+pub fn get_sample_partition<'a>(
+    part : &[FxHashSet<&'a Frag>],
+    local_blocks : &[LocalBlock],
+    sample_haps : &[Vec<usize>],
+    source_samples : &[usize],
+    sample : usize,
+    sample_ploidy : usize) -> Vec<FxHashSet<&'a Frag>>{
+
+    let regions = block_regions(local_blocks);
+    let mut sample_part = vec![FxHashSet::default(); sample_ploidy];
+    let mut unplaced_reads = Vec::new();
+    for (hap, reads) in part.iter().enumerate(){
+        for read in reads.iter().filter(|read| source_samples[read.source] == sample){
+            let block = regions.partition_point(|region| region.0 <= read.first_position).saturating_sub(1);
+            let mut placed = false;
+            if let Some(haps) = sample_haps.get(block){
+                for (j, copy_hap) in haps.iter().enumerate(){
+                    if *copy_hap == hap{
+                        sample_part[j].insert(*read);
+                        placed = true;
+                    }
+                }
+            }
+            if !placed{
+                unplaced_reads.push(*read);
+            }
+        }
+    }
+    assign_reads_to_haplotypes(&mut sample_part, &unplaced_reads);
+    sample_part
+}
+
+//Log likelihood margins of the reads of a sample's partition from get_sample_partition, as in
+//get_assignment_margins but against the sample's own copies : the margin is between the best copy
+//a read is on and the best copy it isn't on. A read is on every copy of its pool haplotype, so a
+//read on all copies, e.g. where the sample is homozygous, has an infinite margin.
+pub fn get_sample_assignment_margins<'a>(sample_part : &[FxHashSet<&'a Frag>], epsilon : f64) -> FxHashMap<&'a Frag, f64>{
+    let block = utils_frags::hap_block_from_partition(sample_part);
+    let mut margins = FxHashMap::default();
+    for read in sample_part.iter().flatten(){
+        if margins.contains_key(read){
+            continue;
+        }
+        let mut best_on = f64::NEG_INFINITY;
+        let mut best_off = f64::NEG_INFINITY;
+        for (reads, log_likelihood) in sample_part.iter().zip(read_log_likelihoods(read, sample_part, &block, epsilon)){
+            if reads.contains(read){
+                best_on = best_on.max(log_likelihood);
+            }
+            else{
+                best_off = best_off.max(log_likelihood);
+            }
+        }
+        margins.insert(*read, best_on - best_off);
+    }
+    margins
+}
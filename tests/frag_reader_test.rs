@@ -1,7 +1,11 @@
 use std::time::Instant;
 use flopp::file_reader;
 use flopp::local_clustering;
+use flopp::local_clustering::PartitionObjective;
 use flopp::utils_frags;
+use flopp::vcf_polishing;
+use flopp::types_structs;
+use flopp::types_structs::MaxFragSpan;
 use fxhash::{FxHashSet,FxHashMap};
 use std::collections::{HashSet,BTreeMap};
 
@@ -55,11 +59,1226 @@ fn local_cluster_test(){
     let frags_map = file_reader::get_frags_container(flopp_dir.to_owned() + "/tests/test_file.txt");
     let frags = frags_map.get("frag_contig").unwrap();
     let indexed_reads = utils_frags::get_all_overlaps(frags);
-    let interval_reads_all  = local_clustering::find_reads_in_interval(1,100,frags);
-    let interval_reads_5= local_clustering::find_reads_in_interval(5,6,frags);
+    let interval_reads_all  = local_clustering::find_reads_in_interval(1,100,frags,MaxFragSpan::Snps(usize::MAX));
+    let interval_reads_5= local_clustering::find_reads_in_interval(5,6,frags,MaxFragSpan::Snps(usize::MAX));
 
     assert_eq!(interval_reads_all.len(),3);
     assert_eq!(interval_reads_5.len(),1);
 
 }
 
+#[test]
+fn group_by_test(){
+    assert_eq!(file_reader::parse_group_key("qname"),Some(file_reader::FragGroupKey::ReadName));
+    assert_eq!(file_reader::parse_group_key("BX"),Some(file_reader::FragGroupKey::Tag(b"BX".to_vec())));
+    assert_eq!(file_reader::parse_group_key("BXX"),None);
+    assert_eq!(file_reader::parse_group_key(""),None);
+
+    //group_by.bam is group_by.sam converted by samtools view -b.
+    let vcf_file = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/test_vcfs/small.vcf");
+    let bam_file = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/test_bams/group_by.bam");
+
+    //By read name, every read is its own fragment.
+    let frags_map = file_reader::get_frags_from_bamvcf(vcf_file,bam_file,&file_reader::FragGroupKey::ReadName,file_reader::DEFAULT_MAX_GROUP_GAP,10000);
+    let mut ids : Vec<String> = frags_map["chr1"].iter().map(|frag| frag.id.clone()).collect();
+    ids.sort();
+    assert_eq!(ids,vec!["bx1a","bx1b","bx2a","bx2b","notag"]);
+
+    //By barcode, reads sharing a BX tag are merged. notag has no BX tag so it keeps its name.
+    let frags_map = file_reader::get_frags_from_bamvcf(vcf_file,bam_file,&file_reader::parse_group_key("BX").unwrap(),file_reader::DEFAULT_MAX_GROUP_GAP,10000);
+    let frags : FxHashMap<String,&types_structs::Frag> = frags_map["chr1"].iter().map(|frag| (frag.id.clone(),frag)).collect();
+    assert_eq!(frags.len(),3);
+    let alleles = |frag : &types_structs::Frag| {
+        let mut alleles : Vec<(usize,usize)> = frag.alleles().collect();
+        alleles.sort();
+        alleles
+    };
+    assert_eq!(alleles(frags["AAA"]),vec![(1,1),(2,1),(3,1),(4,0)]);
+    let mut read_names = frags["AAA"].read_names.clone();
+    read_names.sort();
+    assert_eq!(read_names,vec!["bx1a","bx1b"]);
+    assert_eq!(alleles(frags["notag"]),vec![(1,0),(2,0),(3,0)]);
+
+    //bx2a and bx2b disagree on both SNPs : the allele with the higher base quality wins.
+    assert_eq!(alleles(frags["BBB"]),vec![(4,0),(5,1)]);
+    assert_eq!(frags["BBB"].qual(4),Some(40));
+    assert_eq!(frags["BBB"].qual(5),Some(50));
+
+    //bx1b starts 80 bases after the end of bx1a, so with a smaller gap AAA is two molecules.
+    //bx2a and bx2b overlap and stay together.
+    let frags_map = file_reader::get_frags_from_bamvcf(vcf_file,bam_file,&file_reader::parse_group_key("BX").unwrap(),50,10000);
+    let frags : FxHashMap<String,&types_structs::Frag> = frags_map["chr1"].iter().map(|frag| (frag.id.clone(),frag)).collect();
+    assert_eq!(frags.len(),4);
+    assert_eq!(alleles(frags["AAA_mol0"]),vec![(1,1),(2,1)]);
+    assert_eq!(alleles(frags["AAA_mol1"]),vec![(3,1),(4,0)]);
+    assert_eq!(alleles(frags["BBB"]),vec![(4,0),(5,1)]);
+}
+
+#[test]
+fn multiple_bams_test(){
+    let vcf_file = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/test_vcfs/small.vcf");
+    let bam_file = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/test_bams/group_by.bam");
+    let frags_map = file_reader::get_frags_from_multiple_bams(vcf_file,&[bam_file,bam_file],&[Some(0.05),None],&file_reader::FragGroupKey::ReadName,file_reader::DEFAULT_MAX_GROUP_GAP,10000);
+    let frags = &frags_map["chr1"];
+    assert_eq!(frags.len(),10);
+    let counter_ids : HashSet<usize> = frags.iter().map(|frag| frag.counter_id).collect();
+    assert_eq!(counter_ids.len(),10);
+    for frag in frags.iter(){
+        match frag.source{
+            0 => assert_eq!(frag.epsilon,Some(0.05)),
+            1 => assert_eq!(frag.epsilon,None),
+            _ => panic!("Fragment from an unknown BAM file."),
+        }
+    }
+    assert_eq!(frags.iter().filter(|frag| frag.source == 0).count(),5);
+}
+
+#[test]
+fn frag_epsilon_test(){
+    let mut noisy = types_structs::build_frag("noisy".to_string(),0);
+    noisy.epsilon = Some(0.1);
+    for pos in 1..3{
+        types_structs::update_frag(&mut noisy,0,30,pos);
+    }
+    let mut accurate = types_structs::build_frag("accurate".to_string(),1);
+    for pos in 1..5{
+        types_structs::update_frag(&mut accurate,0,30,pos);
+    }
+
+    //Fragments without their own error rate use the global one.
+    assert_eq!(utils_frags::get_frag_epsilon(&accurate,0.01),0.01);
+    assert!((utils_frags::get_pair_epsilon(&accurate,&accurate,0.01) - 2.0 * 0.01 * 0.99).abs() < 1e-12);
+    assert!((utils_frags::get_pair_epsilon(&noisy,&accurate,0.01) - (0.1 * 0.99 + 0.01 * 0.9)).abs() < 1e-12);
+    assert!((utils_frags::get_pair_epsilon(&accurate,&noisy,0.01) - (0.1 * 0.99 + 0.01 * 0.9)).abs() < 1e-12);
+
+    //A haplotype's error rate is weighted by the bases of each read; empty haplotypes get epsilon.
+    let mut hap = FxHashSet::default();
+    hap.insert(&noisy);
+    hap.insert(&accurate);
+    let partition = vec![hap,FxHashSet::default()];
+    let block = utils_frags::hap_block_from_partition(&partition);
+    let epsilons = utils_frags::get_partition_epsilons(&partition,&block,0.01);
+    assert!((epsilons[0] - (2.0 * 0.1 + 4.0 * 0.01) / 6.0).abs() < 1e-12);
+    assert_eq!(epsilons[1],0.01);
+}
+
+//Allele of haplotype hap at SNP pos when haplotypes alternate alleles.
+fn hap_allele(hap : usize, pos : usize) -> usize{
+    (hap + pos) % 2
+}
+
+//A read with allele(pos) at each SNP pos of positions, all with base quality 30.
+fn simulated_read(id : &str, counter_id : usize, positions : std::ops::RangeInclusive<usize>, allele : impl Fn(usize) -> usize) -> types_structs::Frag{
+    let mut frag = types_structs::build_frag(id.to_string(),counter_id);
+    for pos in positions{
+        types_structs::update_frag(&mut frag,allele(pos),30,pos);
+    }
+    frag
+}
+
+//Three reads of each of two haplotypes a and b (see hap_allele) covering SNPs 1-12 and 13-20,
+//so no read spans the two local blocks, named (haplotype)_(start)_(copy).
+fn two_block_reads() -> Vec<types_structs::Frag>{
+    let mut frags = Vec::new();
+    for (start, end) in [(1,12),(13,20)].iter(){
+        for hap in 0..2{
+            for copy in 0..3{
+                let id = format!("{}_{}_{}",["a","b"][hap],start,copy);
+                frags.push(simulated_read(&id,frags.len(),*start..=*end,|pos| hap_allele(hap,pos)));
+            }
+        }
+    }
+    frags
+}
+
+//A partition of two_block_reads with a switch error : the reads of b are on the first haplotype
+//from SNP 13 on.
+fn switched_partition(frags : &[types_structs::Frag]) -> Vec<FxHashSet<&types_structs::Frag>>{
+    vec![
+        frags.iter().filter(|frag| frag.id.starts_with("a_1_") || frag.id.starts_with("b_13_")).collect(),
+        frags.iter().filter(|frag| frag.id.starts_with("b_1_") || frag.id.starts_with("a_13_")).collect()]
+}
+
+fn two_local_blocks() -> Vec<types_structs::LocalBlock>{
+    vec![types_structs::LocalBlock{start : 1, end : 12},types_structs::LocalBlock{start : 13, end : 20}]
+}
+
+//Whether each haplotype of a partition of two_block_reads has the reads of one of a and b.
+fn is_unswitched(part : &[FxHashSet<&types_structs::Frag>]) -> bool{
+    ["a","b"].iter().enumerate().all(|(hap, name)| part[hap].iter().all(|frag| frag.id.starts_with(name)))
+}
+
+fn aligned_segment(read_id : &str, ref_start : i64, reverse : bool, supplementary : bool, alleles : &[(usize,usize)]) -> file_reader::AlignedSegment{
+    let mut frag = types_structs::build_frag(read_id.to_string(),0);
+    for (pos,geno) in alleles.iter(){
+        types_structs::update_frag(&mut frag,*geno,30,*pos);
+    }
+    file_reader::AlignedSegment{
+        read_id : read_id.to_string(),
+        read_name : read_id.to_string(),
+        ref_start,
+        ref_end : ref_start + 1000,
+        reverse,
+        supplementary,
+        primary_elsewhere : false,
+        frag,
+    }
+}
+
+#[test]
+fn split_alignment_test(){
+    let primary = aligned_segment("r",0,false,false,&[(1,0),(2,1)]);
+    //Co-linear : same strand, a short gap.
+    let colinear = aligned_segment("r",1500,false,true,&[(3,1),(4,0)]);
+    assert!(file_reader::segments_consistent(&primary,&colinear,10000));
+    assert!(!file_reader::segments_consistent(&primary,&colinear,100));
+    //Overlapping segments must agree on the SNPs they share.
+    let overlapping = aligned_segment("r",500,false,true,&[(2,1),(3,1)]);
+    assert!(file_reader::segments_consistent(&primary,&overlapping,10000));
+    let overlapping_disagree = aligned_segment("r",500,false,true,&[(2,0),(3,1)]);
+    assert!(!file_reader::segments_consistent(&primary,&overlapping_disagree,10000));
+    //Inverted : the other strand.
+    let inverted = aligned_segment("r",1500,true,true,&[(3,1),(4,0)]);
+    assert!(!file_reader::segments_consistent(&primary,&inverted,10000));
+
+    let mut stats = file_reader::SplitAlignmentStats::default();
+    let frags = file_reader::stitch_split_alignments(vec![colinear,aligned_segment("r",0,false,false,&[(1,0),(2,1)])],None,10000,&mut stats);
+    assert_eq!(frags.len(),1);
+    assert_eq!(frags[0].positions,vec![1,2,3,4]);
+    assert_eq!((stats.reads_with_splits,stats.reads_stitched,stats.reads_split),(1,1,0));
+
+    let frags = file_reader::stitch_split_alignments(vec![primary,inverted],None,10000,&mut stats);
+    let mut ids : Vec<&str> = frags.iter().map(|frag| frag.id.as_str()).collect();
+    ids.sort();
+    assert_eq!(ids,vec!["r","r_split1"]);
+    assert_eq!((stats.reads_with_splits,stats.reads_stitched,stats.reads_split),(2,1,1));
+
+    //A supplementary alignment whose primary alignment is on another contig is a chimeric piece,
+    //not the read's own fragment.
+    let mut cross_contig = aligned_segment("r",0,false,true,&[(1,0),(2,1)]);
+    cross_contig.primary_elsewhere = true;
+    let frags = file_reader::stitch_split_alignments(vec![cross_contig],None,10000,&mut stats);
+    assert_eq!(frags.len(),1);
+    assert_eq!(frags[0].id,"r_split0");
+    assert_eq!((stats.reads_with_splits,stats.reads_stitched,stats.reads_split),(3,1,2));
+
+    //Split fragments of a read grouped by barcode are named after the barcode and the read so
+    //they stay unique.
+    let mut primary = aligned_segment("r",0,false,false,&[(1,0),(2,1)]);
+    let mut inverted = aligned_segment("r",1500,true,true,&[(3,1),(4,0)]);
+    primary.frag.id = "AAA".to_string();
+    inverted.frag.id = "AAA".to_string();
+    let frags = file_reader::stitch_split_alignments(vec![primary,inverted],None,10000,&mut stats);
+    let mut ids : Vec<&str> = frags.iter().map(|frag| frag.id.as_str()).collect();
+    ids.sort();
+    assert_eq!(ids,vec!["AAA","AAA_r_split1"]);
+
+    //The same cases read from a BAM file. split.bam is split.sam converted by samtools view -b.
+    let vcf_file = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/test_vcfs/small.vcf");
+    let bam_file = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/test_bams/split.bam");
+    let frags_map = file_reader::get_frags_from_bamvcf(vcf_file,bam_file,&file_reader::FragGroupKey::ReadName,file_reader::DEFAULT_MAX_GROUP_GAP,10000);
+    let chr1 : FxHashMap<String,&types_structs::Frag> = frags_map["chr1"].iter().map(|frag| (frag.id.clone(),frag)).collect();
+    let mut ids : Vec<&String> = chr1.keys().collect();
+    ids.sort();
+    assert_eq!(ids,vec!["chimera","colinear","inverted","inverted_split1"]);
+    assert_eq!(chr1["colinear"].positions,vec![1,2,3,4]);
+    assert_eq!(chr1["chimera"].positions,vec![5,6]);
+    assert_eq!(frags_map["chr2"].len(),1);
+    assert_eq!(frags_map["chr2"][0].id,"chimera_split0");
+    assert_eq!(frags_map["chr2"][0].read_names,vec!["chimera"]);
+    assert_eq!(frags_map["chr2"][0].positions,vec![1,2,3]);
+}
+
+#[test]
+fn max_frag_span_test(){
+    let spans = [10,11,10,12,9,10,1000];
+    let mut frags = Vec::new();
+    for (i,span) in spans.iter().enumerate(){
+        let mut frag = types_structs::build_frag(format!("r{}",i),i);
+        types_structs::update_frag(&mut frag,0,30,i+1);
+        types_structs::update_frag(&mut frag,1,30,i+1+span);
+        frags.push(frag);
+    }
+
+    //Without genome positions the cutoff is in SNPs.
+    let max_frag_span = utils_frags::get_max_frag_span(&frags,&[],3.0);
+    match max_frag_span{
+        MaxFragSpan::Snps(max_snps) => {
+            assert!(max_snps >= 12);
+            assert!(max_snps < 1000);
+        }
+        MaxFragSpan::Bases(..) => panic!("No genome positions were given."),
+    }
+    assert_eq!(utils_frags::count_long_frags(&frags,max_frag_span),1);
+    let interval_reads = local_clustering::find_reads_in_interval(1,2000,&frags,max_frag_span);
+    assert_eq!(interval_reads.len(),6);
+
+    //Read lengths with an exponential tail, as in scripts/simulate_frags.py, with a SNP every 50
+    //bases. None of the long reads in the tail are outliers; a read spanning 2Mb is.
+    let snp_to_genome : Vec<usize> = (0..40000).map(|i| i * 50).collect();
+    let mut frags = Vec::new();
+    for i in 0..1000{
+        let quantile = (i as f64 + 0.5) / 1000.0;
+        let span = 2500.0 - 10000.0 * (1.0 - quantile).ln();
+        let mut frag = types_structs::build_frag(format!("r{}",i),i);
+        types_structs::update_frag(&mut frag,0,30,1);
+        types_structs::update_frag(&mut frag,1,30,span as usize / 50 + 1);
+        frags.push(frag);
+    }
+    let mut chimera = types_structs::build_frag("chimera".to_string(),1000);
+    types_structs::update_frag(&mut chimera,0,30,1);
+    types_structs::update_frag(&mut chimera,1,30,40000);
+    frags.push(chimera);
+    let max_frag_span = utils_frags::get_max_frag_span(&frags,&snp_to_genome,3.0);
+    assert!(matches!(max_frag_span,MaxFragSpan::Bases(..)));
+    assert_eq!(utils_frags::count_long_frags(&frags,max_frag_span),1);
+    assert!(max_frag_span.is_outlier(&frags[1000]));
+
+    //Spans are compared in bases : the dense read spans many SNPs of a SNP dense region but no
+    //more bases than the others, so it is not an outlier.
+    let mut snp_to_genome = Vec::new();
+    for i in 0..1200{
+        if i < 20{
+            snp_to_genome.push(i * 100);
+        }
+        else{
+            snp_to_genome.push(2000 + i);
+        }
+    }
+    let mut frags = Vec::new();
+    for i in 0..6{
+        let mut frag = types_structs::build_frag(format!("r{}",i),i);
+        types_structs::update_frag(&mut frag,0,30,i+1);
+        types_structs::update_frag(&mut frag,1,30,i+11);
+        frags.push(frag);
+    }
+    let mut dense_frag = types_structs::build_frag("dense".to_string(),6);
+    types_structs::update_frag(&mut dense_frag,0,30,100);
+    types_structs::update_frag(&mut dense_frag,1,30,1100);
+    frags.push(dense_frag);
+    let mut long_frag = types_structs::build_frag("long".to_string(),7);
+    types_structs::update_frag(&mut long_frag,0,30,1);
+    types_structs::update_frag(&mut long_frag,1,30,1200);
+    frags.push(long_frag);
+
+    let max_frag_span = utils_frags::get_max_frag_span(&frags,&snp_to_genome,3.0);
+    assert!(matches!(max_frag_span,MaxFragSpan::Bases(..)));
+    assert!(!max_frag_span.is_outlier(&frags[6]));
+    assert!(max_frag_span.is_outlier(&frags[7]));
+    assert_eq!(utils_frags::count_long_frags(&frags,max_frag_span),1);
+}
+
+#[test]
+fn frag_index_test(){
+    let mut frags = Vec::new();
+    for i in 0..200{
+        let mut frag = types_structs::build_frag(format!("r{}",i),i);
+        let first = (i * 7) % 150 + 1;
+        let span = (i * 13) % 40 + 1;
+        types_structs::update_frag(&mut frag,0,30,first);
+        types_structs::update_frag(&mut frag,1,30,first+span);
+        frags.push(frag);
+    }
+    frags.sort_by_key(|frag| frag.first_position);
+
+    let frag_index = types_structs::FragIndex::new(&frags,MaxFragSpan::Snps(30));
+    for (start,end) in [(1,1),(5,20),(40,41),(100,180),(190,300)].iter(){
+        let indexed = frag_index.reads_in_interval(*start,*end);
+        let scanned = local_clustering::find_reads_in_interval(*start,*end,&frags,MaxFragSpan::Snps(30));
+        assert_eq!(indexed,scanned);
+    }
+
+    //One long fragment among short ones.
+    let mut long_frag = types_structs::build_frag("long".to_string(),200);
+    types_structs::update_frag(&mut long_frag,0,30,2);
+    types_structs::update_frag(&mut long_frag,1,30,1000);
+    frags.push(long_frag);
+    frags.sort_by_key(|frag| frag.first_position);
+    let frag_index = types_structs::FragIndex::new(&frags,MaxFragSpan::Snps(1000));
+    for (start,end) in [(1,1),(40,41),(190,300),(999,1200),(1001,1200)].iter(){
+        let indexed = frag_index.reads_in_interval(*start,*end);
+        let scanned = local_clustering::find_reads_in_interval(*start,*end,&frags,MaxFragSpan::Snps(1000));
+        assert_eq!(indexed,scanned);
+    }
+    assert_eq!(frag_index.reads_in_interval(999,1200).len(),1);
+
+    let frag_index = types_structs::FragIndex::new(&[],MaxFragSpan::Snps(30));
+    assert!(frag_index.reads_in_interval(1,100).is_empty());
+}
+
+#[test]
+fn compact_frag_test(){
+    //Alleles added out of order are kept sorted; a repeated position keeps the higher quality.
+    let mut frag = types_structs::build_frag("r1".to_string(),0);
+    types_structs::update_frag(&mut frag,1,20,5);
+    types_structs::update_frag(&mut frag,0,30,2);
+    types_structs::update_frag(&mut frag,1,25,9);
+    types_structs::update_frag(&mut frag,0,10,5);
+    types_structs::update_frag(&mut frag,0,40,9);
+    assert_eq!(frag.positions,vec![2,5,9]);
+    assert_eq!(frag.allele(5),Some(1));
+    assert_eq!(frag.allele(9),Some(0));
+    assert_eq!(frag.qual(9),Some(40));
+    assert_eq!(frag.allele(3),None);
+    assert_eq!((frag.first_position,frag.last_position),(2,9));
+
+    let copy = types_structs::Frag::from_dicts("r1".to_string(),1,&frag.seq_dict(),&frag.qual_dict());
+    assert_eq!(copy.positions,frag.positions);
+    assert_eq!(copy.seqs,frag.seqs);
+    assert_eq!(copy.quals,frag.quals);
+
+    let mut other = types_structs::build_frag("r2".to_string(),2);
+    for (pos,geno) in [(1,0),(2,0),(5,0),(9,0),(12,1)].iter(){
+        types_structs::update_frag(&mut other,*geno,30,*pos);
+    }
+    assert_eq!(utils_frags::distance(&frag,&other),(2,1));
+    assert_eq!(utils_frags::distance(&other,&frag),(2,1));
+    assert!(utils_frags::check_overlap(&frag,&other));
+}
+
+#[test]
+fn adaptive_blocks_test(){
+    //Two reads spanning 10 SNPs start at every SNP, except that no reads start at SNPs 26-34.
+    let mut frags = Vec::new();
+    for first in 1..91{
+        if first > 25 && first < 35{
+            continue;
+        }
+        for _ in 0..2{
+            let mut frag = types_structs::build_frag(format!("r{}",frags.len()),frags.len());
+            types_structs::update_frag(&mut frag,0,30,first);
+            types_structs::update_frag(&mut frag,1,30,first+10);
+            frags.push(frag);
+        }
+    }
+    let frag_index = types_structs::FragIndex::new(&frags,MaxFragSpan::Snps(usize::MAX));
+    let coverage = utils_frags::get_snp_coverage(&frag_index,100);
+    assert_eq!((coverage[1],coverage[20],coverage[34],coverage[100]),(2,22,4,2));
+
+    let fixed_blocks = utils_frags::get_fixed_blocks(100,30);
+    assert_eq!(fixed_blocks.iter().map(|block| (block.start,block.end)).collect::<Vec<_>>(),vec![(1,31),(31,61),(61,91)]);
+
+    //Blocks cover every SNP, share their boundary SNPs, and one of them ends at the coverage dip.
+    let blocks = utils_frags::get_adaptive_blocks(&frag_index,100,10,0.33);
+    assert_eq!(blocks[0].start,1);
+    assert_eq!(blocks.last().unwrap().end,100);
+    for i in 1..blocks.len(){
+        assert_eq!(blocks[i].start,blocks[i-1].end);
+        assert!(blocks[i].end - blocks[i].start >= 5);
+    }
+    assert!(blocks.iter().any(|block| coverage[block.end] == 4));
+}
+
+#[test]
+fn overlap_linking_test(){
+    let mut blocks = utils_frags::get_fixed_blocks(30,10);
+    utils_frags::extend_blocks(&mut blocks,3,30);
+    assert_eq!(blocks.iter().map(|block| (block.start,block.end)).collect::<Vec<_>>(),vec![(1,14),(11,24),(21,30)]);
+
+    //No read is in two blocks, but the haplotypes of consecutive blocks agree on the shared SNPs.
+    let mut frags = Vec::new();
+    for (i,block) in blocks.iter().enumerate(){
+        for allele in 0..2{
+            let mut frag = types_structs::build_frag(format!("b{}_h{}",i,allele),frags.len());
+            for pos in block.start..block.end+1{
+                types_structs::update_frag(&mut frag,allele,30,pos);
+            }
+            frags.push(frag);
+        }
+    }
+    let mut all_parts = Vec::new();
+    for i in 0..3{
+        let mut part = vec![FxHashSet::default(),FxHashSet::default()];
+        //The second block lists its haplotypes in the other order.
+        let swap = if i == 1 {1} else {0};
+        part[swap].insert(&frags[2*i]);
+        part[1-swap].insert(&frags[2*i+1]);
+        all_parts.push(part);
+    }
+
+    let final_part = vcf_polishing::link_blocks_overlap(&all_parts,&blocks,&frags);
+    for (hap,allele) in final_part.iter().zip(0..2){
+        let mut ids : Vec<&String> = hap.iter().map(|frag| &frag.id).collect();
+        ids.sort();
+        assert_eq!(ids,vec![&format!("b0_h{}",allele),&format!("b1_h{}",allele),&format!("b2_h{}",allele)]);
+    }
+}
+
+#[test]
+fn beam_linking_test(){
+    assert_eq!(vcf_polishing::parse_linker("beam"),Some(vcf_polishing::BlockLinker::Beam));
+    assert_eq!(vcf_polishing::parse_linker("hungarian"),None);
+
+    //Ploidy 2 has fewer permutations than the beam width and ploidy 5 is above 4.
+    for ploidy in [2,5].iter(){
+        let ploidy = *ploidy;
+        let mut frags = Vec::new();
+        for start in (1..60).step_by(5){
+            for hap in 0..ploidy{
+                let mut frag = types_structs::build_frag(format!("h{}_{}",hap,start),frags.len());
+                for pos in start..start+10{
+                    types_structs::update_frag(&mut frag,(hap >> (pos % 3)) & 1,30,pos);
+                }
+                frags.push(frag);
+            }
+        }
+
+        //Local blocks clustered perfectly, but with their haplotypes in a different order.
+        let blocks = utils_frags::get_fixed_blocks(70,10);
+        let frag_index = types_structs::FragIndex::new(&frags,MaxFragSpan::Snps(usize::MAX));
+        let mut all_parts = Vec::new();
+        for (i,block) in blocks.iter().enumerate(){
+            let mut part = vec![FxHashSet::default();ploidy];
+            for frag in frag_index.reads_in_interval(block.start,block.end){
+                let hap : usize = frag.id[1..frag.id.find('_').unwrap()].parse().unwrap();
+                part[(hap + i) % ploidy].insert(frag);
+            }
+            all_parts.push(part);
+        }
+
+        let final_part = vcf_polishing::link_blocks_heur(&all_parts,4);
+        for reads in final_part.iter(){
+            let haps : HashSet<&str> = reads.iter().map(|frag| frag.id.split('_').next().unwrap()).collect();
+            assert_eq!(haps.len(),1);
+        }
+    }
+}
+
+//All permutations of 0..n, for checking the assignment solver against brute force.
+fn all_permutations(n : usize) -> Vec<Vec<usize>>{
+    if n == 0{
+        return vec![vec![]];
+    }
+    let mut perms = Vec::new();
+    for perm in all_permutations(n - 1){
+        for i in 0..n{
+            let mut new_perm = perm.clone();
+            new_perm.insert(i,n - 1);
+            perms.push(new_perm);
+        }
+    }
+    perms
+}
+
+#[test]
+fn assignment_linking_test(){
+    use rand::prelude::*;
+    let mut rng = rand_pcg::Pcg64::seed_from_u64(1);
+
+    //The Hungarian solver and Murty's k-best permutations agree with brute force.
+    for n in 1..6{
+        let perms = all_permutations(n);
+        for _ in 0..20{
+            let weights : Vec<Vec<i64>> = (0..n).map(|_| (0..n).map(|_| rng.gen_range(-5,6)).collect()).collect();
+            let total = |perm : &Vec<usize>| -> i64 {(0..n).map(|i| weights[i][perm[i]]).sum()};
+            let mut brute_scores : Vec<i64> = perms.iter().map(&total).collect();
+            brute_scores.sort_by(|a,b| b.cmp(a));
+
+            let best = vcf_polishing::max_weight_assignment(&weights);
+            assert_eq!(total(&best),brute_scores[0]);
+
+            let k_best = vcf_polishing::k_best_assignments(&weights,8);
+            assert_eq!(k_best.len(),std::cmp::min(8,perms.len()));
+            let distinct : HashSet<&Vec<usize>> = k_best.iter().collect();
+            assert_eq!(distinct.len(),k_best.len());
+            for (perm,score) in k_best.iter().zip(brute_scores.iter()){
+                assert_eq!(total(perm),*score);
+            }
+        }
+    }
+
+    //Ties go to the identity.
+    assert_eq!(vcf_polishing::max_weight_assignment(&vec![vec![0;4];4]),vec![0,1,2,3]);
+
+    //Murty's subproblems forbid entries : using a forbidden entry must cost more than any
+    //assignment of allowed ones, also when the weights span a wide range.
+    let weights : Vec<Vec<i64>> = (0..3).map(|i| (0..3).map(|j| if i == j {1000} else {-1000}).collect()).collect();
+    assert_eq!(vcf_polishing::k_best_assignments(&weights,6).len(),6);
+
+    //All weights negative.
+    for n in 2..6{
+        let perms = all_permutations(n);
+        for _ in 0..20{
+            let weights : Vec<Vec<i64>> = (0..n).map(|_| (0..n).map(|_| rng.gen_range(-1000,-1)).collect()).collect();
+            let total = |perm : &Vec<usize>| -> i64 {(0..n).map(|i| weights[i][perm[i]]).sum()};
+            let mut brute_scores : Vec<i64> = perms.iter().map(&total).collect();
+            brute_scores.sort_by(|a,b| b.cmp(a));
+
+            let k_best = vcf_polishing::k_best_assignments(&weights,perms.len());
+            assert_eq!(k_best.len(),perms.len());
+            let distinct : HashSet<&Vec<usize>> = k_best.iter().collect();
+            assert_eq!(distinct.len(),k_best.len());
+            for (perm,score) in k_best.iter().zip(brute_scores.iter()){
+                assert_eq!(total(perm),*score);
+            }
+        }
+    }
+
+    //Ploidy 12 has 12! permutations per boundary, which would not finish if enumerated.
+    let ploidy = 12;
+    let mut frags = Vec::new();
+    for start in (1..60).step_by(5){
+        for hap in 0..ploidy{
+            let mut frag = types_structs::build_frag(format!("h{}_{}",hap,start),frags.len());
+            for pos in start..start+10{
+                types_structs::update_frag(&mut frag,(hap >> (pos % 4)) & 1,30,pos);
+            }
+            frags.push(frag);
+        }
+    }
+    let blocks = utils_frags::get_fixed_blocks(70,10);
+    let frag_index = types_structs::FragIndex::new(&frags,MaxFragSpan::Snps(usize::MAX));
+    let mut all_parts = Vec::new();
+    for (i,block) in blocks.iter().enumerate(){
+        let mut part = vec![FxHashSet::default();ploidy];
+        for frag in frag_index.reads_in_interval(block.start,block.end){
+            let hap : usize = frag.id[1..frag.id.find('_').unwrap()].parse().unwrap();
+            part[(hap * [1,5,7,11][i % 4] + i) % ploidy].insert(frag);
+        }
+        all_parts.push(part);
+    }
+
+    let start_t = Instant::now();
+    for final_part in [vcf_polishing::link_blocks_greedy(&all_parts,&frags),
+                       vcf_polishing::link_blocks(&all_parts),
+                       vcf_polishing::link_blocks_heur(&all_parts,4)].iter(){
+        for reads in final_part.iter(){
+            let haps : HashSet<&str> = reads.iter().map(|frag| frag.id.split('_').next().unwrap()).collect();
+            assert_eq!(haps.len(),1);
+        }
+    }
+    assert!(start_t.elapsed().as_secs() < 60);
+}
+
+#[test]
+fn refine_partition_test(){
+    //Two haplotypes with reads of 10 SNPs every 2 SNPs.
+    let mut frags = Vec::new();
+    for start in (1..80).step_by(2){
+        for hap in 0..2{
+            let mut frag = types_structs::build_frag(format!("h{}_{}",hap,start),frags.len());
+            for pos in start..start+10{
+                types_structs::update_frag(&mut frag,(hap + pos) % 2,30,pos);
+            }
+            frags.push(frag);
+        }
+    }
+    let frag_index = types_structs::FragIndex::new(&frags,MaxFragSpan::Snps(usize::MAX));
+
+    //Put a few reads around SNP 40 on the wrong haplotype.
+    let mut partition = vec![FxHashSet::default();2];
+    let mut wrong_reads = 0;
+    for frag in frags.iter(){
+        let hap : usize = frag.id[1..2].parse().unwrap();
+        if hap == 0 && [35,39,43].contains(&frag.first_position){
+            partition[1].insert(frag);
+            wrong_reads += 1;
+        }
+        else{
+            partition[hap].insert(frag);
+        }
+    }
+    assert_eq!(wrong_reads,3);
+
+    let genotype_dict = FxHashMap::default();
+    let refined = local_clustering::refine_partition(partition.clone(),&frag_index,89,20,0.03,&genotype_dict,None,false,10,&local_clustering::UpemObjective{div_factor : 1.0});
+    for reads in refined.iter(){
+        let haps : HashSet<&str> = reads.iter().map(|frag| &frag.id[0..2]).collect();
+        assert_eq!(haps.len(),1);
+    }
+    assert_eq!(refined[0].len() + refined[1].len(),frags.len());
+
+    //No iterations leave the partition as it is.
+    let unrefined = local_clustering::refine_partition(partition.clone(),&frag_index,89,20,0.03,&genotype_dict,None,false,0,&local_clustering::UpemObjective{div_factor : 1.0});
+    assert_eq!(unrefined,partition);
+}
+
+#[test]
+fn switch_error_test(){
+    assert_eq!(vcf_polishing::parse_switch_correction("split"),Some(vcf_polishing::SwitchCorrection::Split));
+    assert_eq!(vcf_polishing::parse_switch_correction("none"),None);
+
+    //Two haplotypes with reads of 10 SNPs every 2 SNPs, and a switch at SNP 40 : the reads
+    //starting from there are on the wrong haplotype.
+    let mut frags = Vec::new();
+    for start in (1..80).step_by(2){
+        for hap in 0..2{
+            let mut frag = types_structs::build_frag(format!("h{}_{}",hap,start),frags.len());
+            for pos in start..start+10{
+                types_structs::update_frag(&mut frag,(hap + pos) % 2,30,pos);
+            }
+            frags.push(frag);
+        }
+    }
+    let mut partition = vec![FxHashSet::default();2];
+    for frag in frags.iter(){
+        let hap : usize = frag.id[1..2].parse().unwrap();
+        if frag.first_position >= 40{
+            partition[1 - hap].insert(frag);
+        }
+        else{
+            partition[hap].insert(frag);
+        }
+    }
+
+    let block = utils_frags::hap_block_from_partition(&partition);
+    let suspicious = vcf_polishing::map_reads_against_hap_errors(&partition,&block,0.03);
+    assert!(suspicious.windows(2).all(|x| x[0].0 <= x[1].0));
+
+    let switch_errors = vcf_polishing::find_switch_errors(&partition,&block,0.03,1);
+    assert_eq!(switch_errors.len(),1);
+    assert_eq!(switch_errors[0].haps,(0,1));
+    assert!(switch_errors[0].position >= 40 && switch_errors[0].position <= 50);
+    assert!(switch_errors[0].gain > 0);
+
+    vcf_polishing::fix_switch_errors(&mut partition,&switch_errors);
+    for reads in partition.iter(){
+        let haps : HashSet<&str> = reads.iter().map(|frag| &frag.id[0..2]).collect();
+        assert_eq!(haps.len(),1);
+    }
+    assert_eq!(partition[0].len() + partition[1].len(),frags.len());
+
+    //A correct partition has no switch errors.
+    let block = utils_frags::hap_block_from_partition(&partition);
+    assert!(vcf_polishing::find_switch_errors(&partition,&block,0.03,1).is_empty());
+}
+
+#[test]
+fn epsilon_estimate_test(){
+    //Most haplotypes have 3% errors, a few are badly clustered.
+    let mut stats = vec![(970,30);20];
+    stats.extend(vec![(800,200);5]);
+    let (epsilon,resp) = local_clustering::fit_error_mixture(&stats);
+    assert!((epsilon - 0.03).abs() < 0.001);
+    assert!(resp[0] > 0.99 && resp[24] < 0.01);
+    let (epsilon,resp) = local_clustering::fit_error_mixture(&vec![(970,30);20]);
+    assert!((epsilon - 0.03).abs() < 1e-9);
+    assert!(resp.iter().all(|r| *r == 1.0));
+
+    //A read alone on a haplotype has nothing to be compared to.
+    let mut frags = Vec::new();
+    for i in 0..3{
+        let mut frag = types_structs::build_frag(format!("r{}",i),i);
+        for pos in 1..11{
+            types_structs::update_frag(&mut frag,(i == 2 && pos == 5) as usize,30,pos);
+        }
+        frags.push(frag);
+    }
+    let part = vec![frags[0..1].iter().collect(),frags[1..3].iter().collect()];
+    assert_eq!(local_clustering::get_leave_one_out_stats(&part),vec![(0,0),(18,2)]);
+
+    //Two haplotypes with an error every 25 alleles.
+    let mut frags = Vec::new();
+    let mut num_alleles = 0;
+    for start in (1..400).step_by(3){
+        for hap in 0..2{
+            let mut frag = types_structs::build_frag(format!("h{}_{}",hap,start),frags.len());
+            for pos in start..start+20{
+                num_alleles += 1;
+                let error = (num_alleles % 25 == 0) as usize;
+                types_structs::update_frag(&mut frag,((hap + pos) % 2) ^ error,30,pos);
+            }
+            frags.push(frag);
+        }
+    }
+    let frag_index = types_structs::FragIndex::new(&frags,MaxFragSpan::Snps(usize::MAX));
+    let blocks = utils_frags::get_fixed_blocks(419,20);
+    let estimate = local_clustering::estimate_epsilon(&blocks,10,2,false,&frag_index,0.03,local_clustering::ClusterInit::Clique,1).unwrap();
+    assert!(estimate.read_epsilon > 0.02 && estimate.read_epsilon < 0.07);
+    assert!(estimate.epsilon >= local_clustering::MIN_EPSILON && estimate.epsilon <= estimate.read_epsilon);
+    assert!(estimate.lower <= estimate.epsilon && estimate.epsilon <= estimate.upper);
+    assert_eq!(estimate.block_estimates.len(),10);
+    assert_eq!(local_clustering::estimate_epsilon(&blocks,10,2,false,&frag_index,0.03,local_clustering::ClusterInit::Clique,1).unwrap(),estimate);
+
+    //No blocks, no estimate.
+    assert!(local_clustering::estimate_epsilon(&[],10,2,false,&frag_index,0.03,local_clustering::ClusterInit::Clique,1).is_none());
+}
+
+#[test]
+fn strain_epsilon_test(){
+    //Two strains with abundances 2/3 and 1/3 and an error every 25 alleles, estimated with up to
+    //4 strains.
+    let mut frags = Vec::new();
+    let mut num_alleles = 0;
+    for start in (1..400).step_by(2){
+        for strain in 0..(2 - start % 4 / 3){
+            let mut frag = types_structs::build_frag(format!("s{}_{}",strain,start),frags.len());
+            for pos in start..start+20{
+                num_alleles += 1;
+                let error = (num_alleles % 25 == 0) as usize;
+                types_structs::update_frag(&mut frag,hap_allele(strain,pos) ^ error,30,pos);
+            }
+            frags.push(frag);
+        }
+    }
+    let frag_index = types_structs::FragIndex::new(&frags,MaxFragSpan::Snps(usize::MAX));
+    let blocks = utils_frags::get_fixed_blocks(419,20);
+    let estimate = local_clustering::estimate_epsilon(&blocks,10,4,true,&frag_index,0.03,local_clustering::ClusterInit::Clique,1).unwrap();
+    let over_split = local_clustering::estimate_epsilon(&blocks,10,4,false,&frag_index,0.03,local_clustering::ClusterInit::Clique,1).unwrap();
+    assert!(estimate.epsilon > 0.03 && estimate.epsilon < 0.05);
+    assert!(estimate.read_epsilon > 0.03 && estimate.read_epsilon < 0.05);
+
+    //Clustering into 4 strains splits the reads of a strain, so their errors look lower.
+    assert!(over_split.epsilon < estimate.epsilon);
+}
+
+#[test]
+fn read_error_test(){
+    //Three reads on each of two haplotypes, and a read of the first haplotype with errors at the
+    //first 8 of its 20 SNPs.
+    let frags : Vec<types_structs::Frag> = (0..7).map(|i| {
+        let hap = (3..6).contains(&i) as usize;
+        simulated_read(&format!("r{}",i),i,1..=20,|pos| hap_allele(hap,pos) ^ (i == 6 && pos < 9) as usize)
+    }).collect();
+    let noisy = &frags[6];
+    let part : Vec<FxHashSet<&types_structs::Frag>> = vec![
+        frags.iter().filter(|frag| frag.counter_id < 3 || frag.counter_id == 6).collect(),
+        frags[3..6].iter().collect()];
+
+    //The read is in two blocks.
+    let read_stats = local_clustering::get_read_error_stats(&[part.clone(),part.clone()]);
+    assert_eq!(read_stats.len(),7);
+    assert_eq!(read_stats[noisy],(24,16));
+    assert_eq!(read_stats[&frags[0]],(40,0));
+    let high_error_reads = local_clustering::get_high_error_reads(&read_stats,None,10);
+    assert_eq!(high_error_reads.len(),1);
+    assert!(high_error_reads.contains(noisy));
+    assert!(local_clustering::get_high_error_reads(&read_stats,Some(0.5),10).is_empty());
+    assert!(local_clustering::get_high_error_reads(&read_stats,None,50).is_empty());
+
+    //The read goes back on the haplotype it agrees with best.
+    let mut kept_part = part.clone();
+    kept_part[0].remove(noisy);
+    vcf_polishing::assign_reads_to_haplotypes(&mut kept_part,&[noisy]);
+    assert_eq!(kept_part,part);
+
+    //A haplotype with few alleles where the read is has few mismatches, but doesn't agree with
+    //the read more.
+    let short_frag = simulated_read("short",7,1..=2,|pos| hap_allele(1,pos));
+    let read = simulated_read("read",8,1..=20,|pos| hap_allele(0,pos) ^ (pos < 3 || pos == 5 || pos == 6) as usize);
+    let mut short_part = vec![frags[0..3].iter().collect(),vec![&short_frag].into_iter().collect()];
+    vcf_polishing::assign_reads_to_haplotypes(&mut short_part,&[&read]);
+    assert!(short_part[0].contains(&read));
+}
+
+#[test]
+fn assignment_margin_test(){
+    //Three reads on each of two haplotypes which differ at SNPs 1 to 8 and agree at SNPs 9 and 10,
+    //and a read on the first haplotype covering only SNPs 9 and 10.
+    let mut frags : Vec<types_structs::Frag> = (0..6).map(|i| {
+        simulated_read(&format!("r{}",i),i,1..=10,|pos| if pos < 9 { hap_allele(i / 3,pos) } else { 0 })
+    }).collect();
+    frags.push(simulated_read("hom",6,9..=10,|_| 0));
+    let mut part : Vec<FxHashSet<&types_structs::Frag>> = vec![
+        frags.iter().filter(|frag| frag.counter_id < 3 || frag.counter_id == 6).collect(),
+        frags[3..6].iter().collect()];
+    let block = utils_frags::hap_block_from_partition(&part);
+
+    let epsilon : f64 = 0.05;
+    let margins = vcf_polishing::get_assignment_margins(&part,&block,epsilon);
+    assert_eq!(margins.len(),7);
+    let expected = 8.0 * ((1.0 - epsilon).ln() - epsilon.ln());
+    assert!((margins[&frags[0]] - expected).abs() < 1e-9);
+    assert!((margins[&frags[4]] - expected).abs() < 1e-9);
+    assert_eq!(margins[&frags[6]],0.0);
+
+    assert!(vcf_polishing::remove_ambiguous_reads(&mut part,&margins,0.0).is_empty());
+    let unassigned = vcf_polishing::remove_ambiguous_reads(&mut part,&margins,5.0);
+    assert_eq!(unassigned,vec![&frags[6]]);
+    assert_eq!(part[0].len(),3);
+    assert_eq!(part[1].len(),3);
+}
+
+#[test]
+fn read_probability_test(){
+    //Two reads on each of two haplotypes which differ at every SNP, and a read with an error at
+    //SNPs 1 to 4 of 10.
+    let frags : Vec<types_structs::Frag> = (0..5).map(|i| {
+        let hap = (i == 2 || i == 3) as usize;
+        simulated_read(&format!("r{}",i),i,1..=10,|pos| hap_allele(hap,pos) ^ (i == 4 && pos < 5) as usize)
+    }).collect();
+    let part : Vec<FxHashSet<&types_structs::Frag>> = vec![
+        frags.iter().filter(|frag| frag.counter_id != 2 && frag.counter_id != 3).collect(),
+        frags[2..4].iter().collect()];
+    let block = utils_frags::hap_block_from_partition(&part);
+
+    let epsilon : f64 = 0.1;
+    let probabilities = vcf_polishing::get_assignment_probabilities(&part,&block,epsilon);
+    let margins = vcf_polishing::get_assignment_margins(&part,&block,epsilon);
+    assert_eq!(probabilities.len(),5);
+    for (read, probs) in probabilities.iter(){
+        assert_eq!(probs.len(),2);
+        assert!((probs.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        //With two haplotypes, the margin is the log odds of the best one.
+        let best = probs[0].max(probs[1]);
+        assert!(((best / (1.0 - best)).ln() - margins[read]).abs() < 1e-6);
+    }
+    assert!(probabilities[&frags[0]][0] > 0.999);
+    assert!(probabilities[&frags[2]][1] > 0.999);
+    //The noisy read has 6 alleles for its haplotype and 4 against it.
+    let odds = 2.0 * ((1.0 - epsilon).ln() - epsilon.ln());
+    assert!((probabilities[&frags[4]][0] - 1.0 / (1.0 + (-odds).exp())).abs() < 1e-9);
+}
+
+#[test]
+fn cluster_init_test(){
+    assert_eq!(local_clustering::parse_cluster_init("kmedoids"),Some(local_clustering::ClusterInit::KMedoids));
+    assert_eq!(local_clustering::parse_cluster_init("spectral"),Some(local_clustering::ClusterInit::Spectral));
+    assert_eq!(local_clustering::parse_cluster_init("kmeans"),None);
+
+    //Four reads on each of three haplotypes over 20 SNPs, one of them with errors at 3 SNPs.
+    let frags : Vec<types_structs::Frag> = (0..12).map(|i| {
+        let allele = |pos| match i / 4 { 0 => 0, 1 => 1, _ => hap_allele(0,pos) };
+        simulated_read(&format!("r{}",i),i,1..=20,|pos| allele(pos) ^ (i == 0 && pos < 4) as usize)
+    }).collect();
+    let all_reads : FxHashSet<&types_structs::Frag> = frags.iter().collect();
+    let mut true_haps : Vec<Vec<usize>> = (0..3).map(|hap| (4 * hap..4 * hap + 4).collect()).collect();
+    true_haps.sort();
+
+    for init in [local_clustering::ClusterInit::Clique,
+                 local_clustering::ClusterInit::KMedoids,
+                 local_clustering::ClusterInit::Spectral].iter(){
+        let clusters = local_clustering::cluster_reads(&all_reads,3,0.04,*init);
+        let mut haps : Vec<Vec<usize>> = clusters
+            .iter()
+            .map(|cluster| {
+                let mut ids : Vec<usize> = cluster.iter().map(|frag| frag.counter_id).collect();
+                ids.sort_unstable();
+                ids
+            })
+            .collect();
+        haps.sort();
+        assert_eq!(haps,true_haps,"{:?}",init);
+    }
+}
+
+#[test]
+fn perturb_partition_test(){
+    let frags : Vec<types_structs::Frag> = (0..40).map(|i| types_structs::build_frag(format!("r{}",i),i)).collect();
+    let part : Vec<FxHashSet<&types_structs::Frag>> = (0..4)
+        .map(|hap| frags.iter().filter(|frag| frag.counter_id % 4 == hap).collect())
+        .collect();
+
+    assert_eq!(local_clustering::perturb_partition(&part,0.0,1,0),part);
+
+    //Every read stays in exactly one cluster, and the same seed and stream give the same partition.
+    let perturbed = local_clustering::perturb_partition(&part,0.5,1,0);
+    assert_eq!(perturbed.len(),4);
+    assert_eq!(perturbed.iter().map(|cluster| cluster.len()).sum::<usize>(),40);
+    let all_reads : FxHashSet<&types_structs::Frag> = perturbed.iter().flatten().copied().collect();
+    assert_eq!(all_reads.len(),40);
+    assert_ne!(perturbed,part);
+    assert_eq!(local_clustering::perturb_partition(&part,0.5,1,0),perturbed);
+    assert_ne!(local_clustering::perturb_partition(&part,0.5,1,1),perturbed);
+}
+
+//Minus the errors of each haplotype weighted by the given weights.
+struct WeightedMecObjective{
+    weights : Vec<f64>,
+}
+
+impl PartitionObjective for WeightedMecObjective{
+    fn score(&self, binom_vec : &[(usize,usize)], _freq_vec : &[usize], _epsilons : &[f64]) -> f64{
+        binom_vec.iter().zip(self.weights.iter()).map(|(stat,weight)| -(stat.1 as f64) * weight).sum()
+    }
+}
+
+#[test]
+fn partition_objective_test(){
+    //Three reads on each of two haplotypes, and a read of the first haplotype on the second one.
+    let frags : Vec<types_structs::Frag> = (0..7).map(|i| {
+        simulated_read(&format!("r{}",i),i,1..=10,|pos| hap_allele((3..6).contains(&i) as usize,pos))
+    }).collect();
+    let part : Vec<FxHashSet<&types_structs::Frag>> = vec![
+        frags[0..3].iter().collect(),
+        frags[3..7].iter().collect()];
+    let mut moved_part = part.clone();
+    moved_part[1].remove(&frags[6]);
+    moved_part[0].insert(&frags[6]);
+
+    let hap_block = utils_frags::hap_block_from_partition(&part);
+    let epsilons = vec![0.05,0.05];
+    let (binom_vec,freq_vec) = local_clustering::get_partition_stats(&part,&hap_block);
+    let (moved_binom_vec,moved_freq_vec) = local_clustering::get_partition_stats(&moved_part,&hap_block);
+    let read_move = local_clustering::ReadMove{
+        from : 1,
+        to : 0,
+        from_stats : utils_frags::distance_read_haplo(&frags[6],&hap_block.blocks[1]),
+        to_stats : utils_frags::distance_read_haplo(&frags[6],&hap_block.blocks[0]),
+    };
+
+    //The move deltas agree with rescoring the partition.
+    let objectives : Vec<Box<dyn PartitionObjective>> = vec![
+        Box::new(local_clustering::UpemObjective{div_factor : 1.0}),
+        Box::new(local_clustering::PemObjective{div_factor : 1.0}),
+        Box::new(local_clustering::MecObjective),
+        Box::new(WeightedMecObjective{weights : vec![1.0,2.0]})];
+    for objective in objectives.iter(){
+        let delta = objective.move_delta(&binom_vec,&freq_vec,&epsilons,&read_move);
+        let rescored = objective.score(&moved_binom_vec,&moved_freq_vec,&epsilons)
+            - objective.score(&binom_vec,&freq_vec,&epsilons);
+        assert!((delta - rescored).abs() < 1e-9);
+        assert!(delta > 0.0);
+    }
+    assert_eq!(local_clustering::MecObjective.move_delta(&binom_vec,&freq_vec,&epsilons,&read_move),10.0);
+
+    //A custom objective drives the optimization.
+    let genotype_dict = FxHashMap::default();
+    let objective = WeightedMecObjective{weights : vec![1.0,2.0]};
+    let (score,best_part,_) = local_clustering::optimize_clustering(part,0.05,&genotype_dict,None,false,10,&objective);
+    assert_eq!(best_part,moved_part);
+    assert_eq!(score,0.0);
+}
+
+#[test]
+fn coverage_objective_test(){
+    assert_eq!(local_clustering::parse_hap_coverage("2,1,1"),Some(local_clustering::HapCoverage::Given(vec![2.0,1.0,1.0])));
+    assert_eq!(local_clustering::parse_hap_coverage("estimate"),Some(local_clustering::HapCoverage::Estimate));
+    assert_eq!(local_clustering::parse_hap_coverage("2,0,1"),None);
+    assert_eq!(local_clustering::parse_hap_coverage("2,a"),None);
+
+    let objective = local_clustering::CoverageObjective::new(1.0,&[1.0,2.0,1.0]);
+    assert_eq!(objective.proportions().to_vec(),vec![0.5,0.25,0.25]);
+
+    //Read counts matching the proportions score better than equal read counts, the other way
+    //round from UPEM.
+    let epsilons = vec![0.05,0.05,0.05];
+    let binom_vec = vec![(100,5),(100,5),(100,5)];
+    let upem = local_clustering::UpemObjective{div_factor : 1.0};
+    assert!(objective.score(&binom_vec,&[20,10,10],&epsilons) > objective.score(&binom_vec,&[14,13,13],&epsilons));
+    assert!(upem.score(&binom_vec,&[20,10,10],&epsilons) < upem.score(&binom_vec,&[14,13,13],&epsilons));
+    //Haplotypes are unlabelled.
+    assert_eq!(objective.score(&binom_vec,&[10,20,10],&epsilons),objective.score(&binom_vec,&[20,10,10],&epsilons));
+
+    //A haplotype with a tiny proportion and no reads still gives a finite score.
+    let floored = local_clustering::CoverageObjective::new(1.0,&[0.5,0.49,local_clustering::MIN_HAP_PROPORTION]);
+    assert!(floored.score(&binom_vec,&[20,20,0],&epsilons).is_finite());
+}
+
+#[test]
+fn subgenome_test(){
+    let subgenomes = file_reader::parse_subgenomes("A:2,B:2,D:2").unwrap();
+    assert_eq!(subgenomes.len(),3);
+    assert_eq!(subgenomes[2],types_structs::Subgenome{name : "D".to_string(), ploidy : 2});
+    assert_eq!(file_reader::parse_subgenomes("A:2,A:2"),None);
+    assert_eq!(file_reader::parse_subgenomes("A:0"),None);
+    assert_eq!(file_reader::parse_subgenomes("A"),None);
+
+    let assignment_file = std::env::temp_dir().join("flopp_subgenome_test.txt");
+    std::fs::write(&assignment_file,"#read\tsubgenome\nr0\tA\nr1 D\n").unwrap();
+    let read_subgenomes = file_reader::get_subgenome_assignments(&assignment_file,&subgenomes);
+    assert_eq!(read_subgenomes.len(),2);
+    assert_eq!(read_subgenomes["r1"],2);
+
+    //Fragments are looked up by the names of their reads, not their ids : split alignments and
+    //barcodes follow their reads, fragments with no or conflicting subgenomes are set aside.
+    let read_names = [vec!["r0"],vec!["r1"],vec!["r2"],vec!["r0","r2"],vec!["r0","r1"]];
+    let frags : Vec<types_structs::Frag> = ["r0","r1_split1","r2","AAA","BBB"].iter().zip(read_names.iter()).enumerate()
+        .map(|(i,(id,names))| {
+            let mut frag = types_structs::build_frag(id.to_string(),i);
+            frag.read_names = names.iter().map(|name| name.to_string()).collect();
+            frag
+        })
+        .collect();
+    let (subgenome_frags,unknown_frags) = utils_frags::split_frags_by_subgenome(frags,&read_subgenomes,subgenomes.len());
+    assert_eq!(subgenome_frags[0].iter().map(|frag| frag.id.as_str()).collect::<Vec<_>>(),vec!["r0","AAA"]);
+    assert!(subgenome_frags[1].is_empty());
+    assert_eq!(subgenome_frags[2].iter().map(|frag| frag.id.as_str()).collect::<Vec<_>>(),vec!["r1_split1"]);
+    assert_eq!(unknown_frags.iter().map(|frag| frag.id.as_str()).collect::<Vec<_>>(),vec!["r2","BBB"]);
+
+    //Contigs of a subgenome are checked against the ploidy of their own genotypes.
+    let mut genotype_dict = FxHashMap::default();
+    assert_eq!(utils_frags::get_genotype_ploidy(&genotype_dict),None);
+    genotype_dict.insert(1,[(0,1),(1,1)].iter().cloned().collect::<FxHashMap<usize,usize>>());
+    genotype_dict.insert(2,[(0,2)].iter().cloned().collect::<FxHashMap<usize,usize>>());
+    assert_eq!(utils_frags::get_genotype_ploidy(&genotype_dict),Some(2));
+}
+
+#[test]
+fn strain_bic_test(){
+    //Two strains differing at every SNP, with 12 and 4 reads over the same 20 SNPs.
+    let frags : Vec<types_structs::Frag> = (0..16).map(|i| {
+        let strain = (i >= 12) as usize;
+        simulated_read(&format!("s{}_{}",strain,i),i,1..=20,|pos| hap_allele(strain,pos))
+    }).collect();
+    let one_strain : Vec<FxHashSet<&types_structs::Frag>> = vec![frags.iter().collect()];
+    let two_strains : Vec<FxHashSet<&types_structs::Frag>> = vec![frags[0..12].iter().collect(),frags[12..16].iter().collect()];
+    let three_strains : Vec<FxHashSet<&types_structs::Frag>> = vec![frags[0..6].iter().collect(),frags[6..12].iter().collect(),frags[12..16].iter().collect()];
+    let bic = |part| local_clustering::get_strain_bic(part,0.03);
+    assert!(bic(&two_strains) < bic(&one_strain));
+    assert!(bic(&two_strains) < bic(&three_strains));
+
+    //A single strain gets all the reads.
+    let all_reads : FxHashSet<&types_structs::Frag> = frags.iter().collect();
+    assert_eq!(local_clustering::cluster_reads(&all_reads,1,0.03,local_clustering::ClusterInit::Clique),one_strain);
+
+    //Empty strains are left out and the others sorted by number of reads.
+    let padded = vec![FxHashSet::default(),two_strains[1].clone(),two_strains[0].clone()];
+    assert_eq!(local_clustering::sort_strains(padded),two_strains);
+}
+
+#[test]
+fn parental_switch_test(){
+    //A diploid whose first parent is homozygous for haplotype a and second parent for haplotype b.
+    let frags = two_block_reads();
+    let part = switched_partition(&frags);
+    let block = utils_frags::hap_block_from_partition(&part);
+
+    let parent = |hap| -> FxHashMap<usize,FxHashMap<usize,usize>> {(1..21).map(|pos| (pos,vec![(hap_allele(hap,pos),2)].into_iter().collect())).collect()};
+    let (first_parent,second_parent) = (parent(0),parent(1));
+    assert_eq!(vcf_polishing::get_parent_incompatibilities(&block,&first_parent,1,20),vec![8,12]);
+    assert_eq!(vcf_polishing::get_parent_incompatibilities(&block,&second_parent,13,20),vec![0,8]);
+
+    let costs = vec![vec![8,12],vec![12,8]];
+    assert_eq!(vcf_polishing::assign_haplotypes_to_parents(&costs,&[1,1]),(vec![0,1],16));
+    assert_eq!(vcf_polishing::assign_haplotypes_to_parents(&costs,&[2,0]),(vec![0,0],20));
+
+    let parents = types_structs::ParentGenotypes{genotypes : vec![&first_parent,&second_parent], contributions : vec![1,1]};
+    assert_eq!(vcf_polishing::get_parent_costs(&block,&parents,1,20),costs);
+    let switches = vcf_polishing::find_parental_switches(&part,&block,&two_local_blocks(),&parents,&[0,1],10);
+    assert_eq!(switches.len(),1);
+    assert_eq!(switches[0].position,13);
+    assert_eq!(switches[0].haps,(0,1));
+
+    let mut fixed = part.clone();
+    vcf_polishing::fix_switch_errors(&mut fixed,&switches);
+    assert!(is_unswitched(&fixed));
+}
+
+#[test]
+fn parent_polishing_test(){
+    //The reads call allele 0 more strongly for the second haplotype, but only the first parent has
+    //allele 0.
+    let counts = |zeros,ones| -> FxHashMap<usize,FxHashMap<usize,usize>> {vec![(1,vec![(0,zeros),(1,ones)].into_iter().collect())].into_iter().collect()};
+    let block = types_structs::HapBlock{blocks : vec![counts(3,2),counts(3,1)], sample_blocks : Vec::new()};
+    let genotype = |alleles : Vec<(usize,usize)>| -> FxHashMap<usize,FxHashMap<usize,usize>> {vec![(1,alleles.into_iter().collect())].into_iter().collect()};
+    let genotype_dict = genotype(vec![(0,1),(1,1)]);
+    let called = |polished : &types_structs::HapBlock| -> Vec<usize> {polished.blocks.iter().map(|hap| *hap[&1].keys().next().unwrap()).collect()};
+    assert_eq!(called(&vcf_polishing::polish_using_vcf(&genotype_dict,&block,&vec![1])),vec![1,0]);
+
+    let (first_parent,second_parent) = (genotype(vec![(0,2)]),genotype(vec![(1,2)]));
+    let parents = types_structs::ParentGenotypes{genotypes : vec![&first_parent,&second_parent], contributions : vec![1,1]};
+    assert_eq!(called(&vcf_polishing::polish_using_parents(&genotype_dict,&block,&vec![1],&parents,&[0,1])),vec![0,1]);
+
+    //A parent without a genotype passes on any allele.
+    let no_genotypes = FxHashMap::default();
+    let parents = types_structs::ParentGenotypes{genotypes : vec![&no_genotypes,&second_parent], contributions : vec![1,1]};
+    assert_eq!(called(&vcf_polishing::polish_using_parents(&genotype_dict,&block,&vec![1],&parents,&[0,1])),vec![0,1]);
+
+    //Parents which can't give the genotype are ignored.
+    let parents = types_structs::ParentGenotypes{genotypes : vec![&first_parent,&first_parent], contributions : vec![1,1]};
+    assert_eq!(called(&vcf_polishing::polish_using_parents(&genotype_dict,&block,&vec![1],&parents,&[0,1])),vec![1,0]);
+}
+
+#[test]
+fn panel_switch_test(){
+    //The panel has the haplotypes a and b whole.
+    let frags = two_block_reads();
+    let part = switched_partition(&frags);
+    let block = utils_frags::hap_block_from_partition(&part);
+    let panel : Vec<FxHashMap<usize,usize>> = (0..2).map(|hap| (1..21).map(|pos| (pos,hap_allele(hap,pos))).collect()).collect();
+    let local_blocks = two_local_blocks();
+
+    let switches = vcf_polishing::find_panel_switches(&part,&block,&local_blocks,&panel,1.0);
+    assert_eq!(switches.len(),1);
+    assert_eq!(switches[0].position,13);
+    assert_eq!(switches[0].haps,(0,1));
+    assert_eq!(switches[0].gain,32);
+
+    let mut fixed = part.clone();
+    vcf_polishing::fix_switch_errors(&mut fixed,&switches);
+    assert!(is_unswitched(&fixed));
+
+    assert!(vcf_polishing::find_panel_switches(&part,&block,&local_blocks,&panel,0.0).is_empty());
+    assert!(vcf_polishing::find_panel_switches(&part,&block,&local_blocks,&[],1.0).is_empty());
+}
+
+#[test]
+fn panel_haplotypes_test(){
+    //p2 is unphased at 200 so it is skipped there, and 250 is an indel.
+    let vcf_file = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/test_vcfs/panel.vcf");
+    let snp_to_genome_pos_map : FxHashMap<String,Vec<usize>> = vec![("chr1".to_string(),vec![100,200,250,300])].into_iter().collect();
+    let panel = file_reader::get_panel_haplotypes(vcf_file,&snp_to_genome_pos_map);
+    let haps = |alleles : &[(usize,usize)]| -> FxHashMap<usize,usize> {alleles.iter().copied().collect()};
+    assert_eq!(panel["chr1"],vec![
+        haps(&[(1,0),(2,1),(4,0)]),
+        haps(&[(1,1),(2,0),(4,1)]),
+        haps(&[(1,1)]),
+        haps(&[(1,0),(4,1)])]);
+}
+
+#[test]
+fn multi_sample_test(){
+    //A pool of three haplotypes over two local blocks of 10 SNPs. Sample 0 (the first two sources)
+    //carries pool haplotypes 0 and 1 and then recombines onto 2 instead of 1; sample 1 is
+    //homozygous for haplotype 2.
+    let local_blocks = vec![types_structs::LocalBlock{start : 1, end : 10},types_structs::LocalBlock{start : 11, end : 20}];
+    let source_samples = vec![0,0,1];
+    let carried = [(0,vec![vec![0,1],vec![0,2]]),(1,vec![vec![2,2],vec![2,2]])];
+    let mut frags = Vec::new();
+    for (sample, block_haps) in carried.iter(){
+        for (block, haps) in block_haps.iter().enumerate(){
+            for (copy, hap) in haps.iter().enumerate(){
+                for k in 0..3{
+                    let id = format!("{}_{}_{}_{}_{}",sample,block,copy,hap,k);
+                    let mut frag = simulated_read(&id,frags.len(),local_blocks[block].start..=local_blocks[block].end,|pos| (hap + pos) % 3 / 2);
+                    frag.source = if *sample == 0 {k % 2} else {2};
+                    frags.push((*hap,frag));
+                }
+            }
+        }
+    }
+    let mut part : Vec<FxHashSet<&types_structs::Frag>> = vec![FxHashSet::default(); 3];
+    for (hap, frag) in frags.iter(){
+        part[*hap].insert(frag);
+    }
+
+    let hap_block = utils_frags::hap_block_from_samples(&part,&source_samples,2);
+    assert_eq!(hap_block.sample_blocks.len(),2);
+    assert_eq!(hap_block.sample_blocks[0][1].get(&5).unwrap().values().sum::<usize>(),3);
+    assert!(!hap_block.sample_blocks[0][1].contains_key(&15));
+    assert_eq!(hap_block.sample_blocks[1][2].get(&15).unwrap().values().sum::<usize>(),6);
+
+    let sample_haps = vcf_polishing::assign_samples_to_haplotypes(&part,&source_samples,2,&local_blocks,2,5.0);
+    assert_eq!(sample_haps[0],vec![vec![0,1],vec![0,2]]);
+    assert_eq!(sample_haps[1],vec![vec![2,2],vec![2,2]]);
+    //With a prohibitive recombination cost the samples keep the same copies in both blocks.
+    let no_recombination = vcf_polishing::assign_samples_to_haplotypes(&part,&source_samples,2,&local_blocks,2,1000.0);
+    assert_eq!(no_recombination[0][0],no_recombination[0][1]);
+
+    let sample_part = vcf_polishing::get_sample_partition(&part,&local_blocks,&sample_haps[0],&source_samples,0,2);
+    assert!(sample_part[0].iter().all(|frag| frag.id.starts_with("0_") && frag.id.split('_').nth(3) == Some("0")));
+    assert!(sample_part[1].iter().all(|frag| frag.id.starts_with("0_1_1_2") || frag.id.starts_with("0_0_1_1")));
+    assert_eq!(sample_part[0].len() + sample_part[1].len(),12);
+    let sample_part = vcf_polishing::get_sample_partition(&part,&local_blocks,&sample_haps[1],&source_samples,1,2);
+    assert_eq!(sample_part[0],sample_part[1]);
+    assert_eq!(sample_part[0].len(),12);
+    //Reads of a homozygous sample are on both copies, which is no ambiguity.
+    let margins = vcf_polishing::get_sample_assignment_margins(&sample_part,0.04);
+    assert!(margins.values().all(|margin| *margin == f64::INFINITY));
+
+    //A read of sample 0 on pool haplotype 1 covering only SNPs where pool haplotypes 1 and 2 agree
+    //is ambiguous in the pool, but not for sample 0, which doesn't carry 2 in the first block.
+    let mut part = part;
+    let ambiguous_read = simulated_read("ambiguous",frags.len(),2..=8,|_| 0);
+    let ambiguous_read = types_structs::Frag{positions : vec![2,5,8], seqs : vec![0,0,0], quals : vec![30,30,30], ..ambiguous_read};
+    part[1].insert(&ambiguous_read);
+    let pool_margins = vcf_polishing::get_assignment_margins(&part,&utils_frags::hap_block_from_partition(&part),0.04);
+    assert_eq!(pool_margins[&ambiguous_read],0.0);
+    let sample_part = vcf_polishing::get_sample_partition(&part,&local_blocks,&sample_haps[0],&source_samples,0,2);
+    assert!(sample_part[1].contains(&ambiguous_read));
+    let margins = vcf_polishing::get_sample_assignment_margins(&sample_part,0.04);
+    assert!(margins[&ambiguous_read] > 5.0);
+}
+
+#[test]
+fn sample_read_count_test(){
+    //A sample with one long read on pool haplotype 0 and three short reads on pool haplotype 1 :
+    //every read counts once, so the haploid sample carries haplotype 1.
+    let local_blocks = vec![types_structs::LocalBlock{start : 1, end : 20}];
+    let long_read = simulated_read("long",0,1..=20,|_| 0);
+    let short_reads : Vec<types_structs::Frag> = (0..3).map(|i| simulated_read(&format!("short{}",i),i + 1,(5 * i + 1)..=(5 * i + 2),|_| 1)).collect();
+    let part : Vec<FxHashSet<&types_structs::Frag>> = vec![vec![&long_read].into_iter().collect(),short_reads.iter().collect()];
+    let sample_haps = vcf_polishing::assign_samples_to_haplotypes(&part,&[0],1,&local_blocks,1,20.0);
+    assert_eq!(sample_haps,vec![vec![vec![1]]]);
+}